@@ -0,0 +1,33 @@
+#![allow(clippy::default_trait_access)]
+
+#[derive(Debug, Builder, Eq, PartialEq)]
+#[builder(public)]
+#[builder(default)]
+pub struct CreateGroupArgs<'a> {
+    pub groupname: &'a str,
+    /// The GID of the new group. If none is given the next free GID is used.
+    #[builder(setter(strip_option))]
+    pub gid: Option<u32>,
+    /// Allocate the GID from the range reserved for system groups.
+    pub system: bool,
+    /// The names of the users that are added as members.
+    pub members: Vec<&'a str>,
+}
+
+impl<'a> CreateGroupArgs<'a> {
+    #[must_use]
+    pub fn builder() -> CreateGroupArgsBuilder<'a> {
+        CreateGroupArgsBuilder::default()
+    }
+}
+
+impl Default for CreateGroupArgs<'_> {
+    fn default() -> Self {
+        Self {
+            groupname: "defaultgroup",
+            gid: None,
+            system: false,
+            members: Vec::new(),
+        }
+    }
+}
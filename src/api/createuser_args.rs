@@ -1,11 +1,36 @@
 #![allow(clippy::default_trait_access)]
+use std::ops::Deref;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum CreateHome {
+    /// Create the home before the user is written. It is removed again if the user can not be written.
     Create,
     Skip,
-    HomeFromDir { path: PathBuf },
+    /// Use an existing directory as the new home. All the files of the previous owner are given to the new user.
+    ///
+    /// The directory is checked before and moved or copied after the user has been written. If moving or copying fails
+    /// the user exists without this home and [`UserLibError::HomeFailed`](crate::UserLibError::HomeFailed) is returned.
+    /// The files that belong to other users are returned in [`NewUser::foreign_files`].
+    HomeFromDir {
+        path: PathBuf,
+        transfer: HomeTransfer,
+        foreign_owners: ForeignOwners,
+    },
+}
+/// How an existing directory becomes the home of a new user.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HomeTransfer {
+    Move,
+    Copy,
+}
+/// What to do with files in an adopted home that are owned by neither the previous owner nor the new user.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ForeignOwners {
+    /// Log the files and keep their owners.
+    Report,
+    /// Log the files and change their owner to the new user.
+    Fix,
 }
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum CreatePrimaryGroup {
@@ -13,13 +38,52 @@ pub enum CreatePrimaryGroup {
     Skip,
     CreateIfEmptyOrAdd,
 }
+/// The primary group of a new user either identified by its GID or by its name.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PrimaryGroup<'a> {
+    Gid(u32),
+    Name(&'a str),
+}
 #[derive(Debug, Builder, Eq, PartialEq)]
 #[builder(public)]
 #[builder(default)]
 pub struct CreateUserArgs<'a> {
     pub username: &'a str,
-    pub delete_home: CreateHome,
-    pub delete_primary_group: CreatePrimaryGroup,
+    /// The UID of the new user. If none is given the next free UID is used.
+    #[builder(setter(strip_option))]
+    pub uid: Option<u32>,
+    /// An existing group that is used as the primary group of the new user.
+    #[builder(setter(strip_option))]
+    pub group: Option<PrimaryGroup<'a>>,
+    /// The names of existing groups the new user is added to as member.
+    pub groups: Vec<&'a str>,
+    #[builder(setter(strip_option))]
+    pub gecos: Option<&'a str>,
+    /// The home directory. Defaults to `/home/<username>`.
+    #[builder(setter(strip_option))]
+    pub home_dir: Option<&'a str>,
+    /// The login shell. Defaults to `/bin/sh`.
+    #[builder(setter(strip_option))]
+    pub shell: Option<&'a str>,
+    /// The already encrypted password. If none is given the account is locked.
+    #[builder(setter(strip_option))]
+    pub password: Option<&'a str>,
+    /// The date on which the account will be disabled.
+    #[builder(setter(strip_option))]
+    pub expire_date: Option<chrono::NaiveDate>,
+    /// Create a system account.
+    pub system: bool,
+    /// How the home directory is created. If none is given `CREATE_HOME` of `login.defs` decides between
+    /// [`CreateHome::Create`] and [`CreateHome::Skip`]. The home is created if `CREATE_HOME` is not set either.
+    #[builder(setter(strip_option))]
+    pub create_home: Option<CreateHome>,
+    /// The skeleton directory that is copied into the new home. Defaults to `/etc/skel`.
+    #[builder(setter(strip_option))]
+    pub skel: Option<&'a str>,
+    /// If a group with the name of the user becomes its primary group. If none is given `USERGROUPS_ENAB` of `login.defs` decides between
+    /// [`CreatePrimaryGroup::CreateIfEmptyOrAdd`] and [`CreatePrimaryGroup::Skip`]. The group is created if `USERGROUPS_ENAB` is not set either.
+    #[builder(setter(strip_option))]
+    pub create_primary_group: Option<CreatePrimaryGroup>,
 }
 
 impl<'a> CreateUserArgs<'a> {
@@ -33,8 +97,33 @@ impl Default for CreateUserArgs<'_> {
     fn default() -> Self {
         Self {
             username: "defaultuser",
-            delete_home: CreateHome::Create,
-            delete_primary_group: CreatePrimaryGroup::CreateIfEmptyOrAdd,
+            uid: None,
+            group: None,
+            groups: Vec::new(),
+            gecos: None,
+            home_dir: None,
+            shell: None,
+            password: None,
+            expire_date: None,
+            system: false,
+            create_home: None,
+            skel: None,
+            create_primary_group: None,
         }
     }
 }
+
+/// A user that has been added by [`UserDBWrite::new_user`](crate::api::UserDBWrite::new_user). It dereferences to the [`User`](crate::User).
+#[derive(Debug)]
+pub struct NewUser<'a> {
+    pub user: &'a crate::User,
+    /// The files of a home adopted with [`CreateHome::HomeFromDir`] that belong to neither the previous owner nor the new user.
+    pub foreign_files: Vec<PathBuf>,
+}
+
+impl Deref for NewUser<'_> {
+    type Target = crate::User;
+    fn deref(&self) -> &crate::User {
+        self.user
+    }
+}
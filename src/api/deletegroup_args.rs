@@ -0,0 +1,26 @@
+#![allow(clippy::default_trait_access)]
+
+#[derive(Debug, Builder, Eq, PartialEq)]
+#[builder(public)]
+#[builder(default)]
+pub struct DeleteGroupArgs<'a> {
+    pub groupname: &'a str,
+    /// Delete the group even if it is still the primary group of some user.
+    pub force: bool,
+}
+
+impl<'a> DeleteGroupArgs<'a> {
+    #[must_use]
+    pub fn builder() -> DeleteGroupArgsBuilder<'a> {
+        DeleteGroupArgsBuilder::default()
+    }
+}
+
+impl Default for DeleteGroupArgs<'_> {
+    fn default() -> Self {
+        Self {
+            groupname: "defaultgroup",
+            force: false,
+        }
+    }
+}
@@ -5,12 +5,27 @@ use std::path::PathBuf;
 pub enum DeleteHome {
     Delete,
     Keep,
-    Archive { path: PathBuf },
+    /// Write a tar archive of the home directory to the directory `path` and delete the home afterwards.
+    ///
+    /// The archive is named `<username>-<uid>-<timestamp>.tar` (with `.gz` appended if it is compressed).
+    Archive {
+        path: PathBuf,
+        compression: ArchiveCompression,
+    },
 }
+/// The compression of archived home directories.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ArchiveCompression {
+    None,
+    Gzip,
+}
+/// What happens to the primary group of a deleted user.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum DeletePrimaryGroup {
+    /// Delete the group even if it has other members. It must not be the primary group of another user.
     Delete,
     Keep,
+    /// Delete the group if the deleted user was its only member.
     DeleteIfEmpty,
 }
 #[derive(Debug, Builder, Eq, PartialEq)]
@@ -1,13 +1,20 @@
+pub mod creategroup_args;
 pub mod createuser_args;
+pub mod deletegroup_args;
 pub mod deleteuser_args;
 
-pub use createuser_args::{CreateHome, CreatePrimaryGroup, CreateUserArgs};
-pub use deleteuser_args::{DeleteHome, DeletePrimaryGroup, DeleteUserArgs};
+pub use creategroup_args::CreateGroupArgs;
+pub use createuser_args::{
+    CreateHome, CreatePrimaryGroup, CreateUserArgs, ForeignOwners, HomeTransfer, NewUser,
+    PrimaryGroup,
+};
+pub use deletegroup_args::DeleteGroupArgs;
+pub use deleteuser_args::{ArchiveCompression, DeleteHome, DeletePrimaryGroup, DeleteUserArgs};
 pub trait UserDBRead {
     fn get_all_users(&self) -> Vec<&crate::User>;
     fn get_user_by_name(&self, name: &str) -> Option<&crate::User>;
     fn get_user_by_id(&self, uid: u32) -> Option<&crate::User>;
-    fn get_all_groups(&self) -> Vec<crate::Group>;
+    fn get_all_groups(&self) -> Vec<&crate::Group>;
     fn get_group_by_name(&self, name: &str) -> Option<&crate::Group>;
     fn get_group_by_id(&self, name: u32) -> Option<&crate::Group>;
 }
@@ -21,9 +28,17 @@ pub trait UserDBValidation {
 
 pub trait UserDBWrite {
     fn delete_user(&mut self, params: DeleteUserArgs) -> Result<crate::User, crate::UserLibError>;
-    fn new_user(&mut self, params: CreateUserArgs) -> Result<&crate::User, crate::UserLibError>;
-    fn delete_group(&mut self, group: &crate::Group) -> Result<(), crate::UserLibError>;
-    fn new_group(&mut self) -> Result<&crate::Group, crate::UserLibError>;
+    fn new_user(&mut self, params: CreateUserArgs) -> Result<NewUser<'_>, crate::UserLibError>;
+    fn delete_group(
+        &mut self,
+        params: DeleteGroupArgs,
+    ) -> Result<crate::Group, crate::UserLibError>;
+    fn new_group(&mut self, params: CreateGroupArgs) -> Result<&crate::Group, crate::UserLibError>;
+    fn rename_group(
+        &mut self,
+        groupname: &str,
+        new_name: &str,
+    ) -> Result<&crate::Group, crate::UserLibError>;
 }
 
 pub trait UserRead {
@@ -46,23 +46,162 @@ fn main() -> Result<(), UserLibError> {
                 .default_value("/etc/group")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("gshadow")
+                .long("gshadow")
+                .value_name("FILE")
+                .about("The gshadow file (defaults to the gshadow file next to the group file)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("login-defs")
+                .short('l')
+                .long("login-defs")
+                .value_name("FILE")
+                .about("The login.defs file")
+                .default_value("/etc/login.defs")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("uid")
+                .long("uid")
+                .value_name("UID")
+                .about("The user ID of the new user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("gid")
+                .long("gid")
+                .value_name("GROUP")
+                .about("The name or ID of the primary group of the new user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("groups")
+                .long("groups")
+                .value_name("GROUPS")
+                .about("A comma separated list of supplementary groups of the new user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("comment")
+                .long("comment")
+                .value_name("COMMENT")
+                .about("The GECOS field of the new user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("home-dir")
+                .long("home-dir")
+                .value_name("HOME_DIR")
+                .about("The home directory of the new user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("shell")
+                .long("shell")
+                .value_name("SHELL")
+                .about("The login shell of the new user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("password")
+                .long("password")
+                .value_name("PASSWORD")
+                .about("The encrypted password of the new user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("expiredate")
+                .long("expiredate")
+                .value_name("EXPIRE_DATE")
+                .about("The date on which the account will be disabled (YYYY-MM-DD)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("system")
+                .long("system")
+                .about("Create a system account"),
+        )
+        .arg(
+            Arg::new("no-create-home")
+                .short('M')
+                .long("no-create-home")
+                .about("Do not create the home directory of the new user"),
+        )
+        .arg(
+            Arg::new("skel")
+                .short('k')
+                .long("skel")
+                .value_name("SKEL_DIR")
+                .about("The skeleton directory that is copied into the new home directory")
+                .takes_value(true),
+        )
         .get_matches();
 
+    let group = PathBuf::from(matches.value_of("group").unwrap());
+    let gshadow = matches
+        .value_of("gshadow")
+        .map_or_else(|| group.with_file_name("gshadow"), PathBuf::from);
     let mf = umanux::Files {
         passwd: Some(PathBuf::from(matches.value_of("passwd").unwrap())),
         shadow: Some(PathBuf::from(matches.value_of("shadow").unwrap())),
-        group: Some(PathBuf::from(matches.value_of("group").unwrap())),
+        gshadow: Some(gshadow),
+        subuid: Some(group.with_file_name("subuid")),
+        subgid: Some(group.with_file_name("subgid")),
+        group: Some(group),
+        login_defs: Some(PathBuf::from(matches.value_of("login-defs").unwrap())),
+        root: None,
+        lock_policy: umanux::LockPolicy::default(),
+        backup: true,
     };
 
     let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
 
-    match db.new_user(
-        umanux::api::CreateUserArgs::builder()
-            .username(matches.value_of("username").unwrap())
-            // .delete_home(umanux::api::DeleteHome::Delete)
-            .build()
-            .unwrap(),
-    ) {
+    let mut args = umanux::api::CreateUserArgs::builder();
+    args.username(matches.value_of("username").unwrap())
+        .system(matches.is_present("system"));
+    if let Some(uid) = matches.value_of("uid") {
+        args.uid(
+            uid.parse()
+                .map_err(|e| format!("Invalid UID {}: {}", uid, e))?,
+        );
+    }
+    if let Some(group) = matches.value_of("gid") {
+        args.group(match group.parse() {
+            Ok(gid) => umanux::api::PrimaryGroup::Gid(gid),
+            Err(_) => umanux::api::PrimaryGroup::Name(group),
+        });
+    }
+    if let Some(groups) = matches.value_of("groups") {
+        args.groups(groups.split(',').collect());
+    }
+    if let Some(comment) = matches.value_of("comment") {
+        args.gecos(comment);
+    }
+    if let Some(home_dir) = matches.value_of("home-dir") {
+        args.home_dir(home_dir);
+    }
+    if let Some(shell) = matches.value_of("shell") {
+        args.shell(shell);
+    }
+    if let Some(password) = matches.value_of("password") {
+        args.password(password);
+    }
+    if matches.is_present("no-create-home") {
+        args.create_home(umanux::api::CreateHome::Skip);
+    }
+    if let Some(skel) = matches.value_of("skel") {
+        args.skel(skel);
+    }
+    if let Some(date) = matches.value_of("expiredate") {
+        args.expire_date(
+            chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map_err(|e| format!("Invalid expire date {}: {}", date, e))?,
+        );
+    }
+
+    match db.new_user(args.build()?) {
         Ok(_) => Ok(()),
         Err(e) => Err(e),
     }
@@ -16,6 +16,13 @@ fn main() {
         passwd: Some(PathBuf::from("./passwd")),
         shadow: Some(PathBuf::from("./shadow")),
         group: Some(PathBuf::from("./group")),
+        gshadow: Some(PathBuf::from("./gshadow")),
+        subuid: Some(PathBuf::from("./subuid")),
+        subgid: Some(PathBuf::from("./subgid")),
+        login_defs: None,
+        root: None,
+        lock_policy: umanux::LockPolicy::default(),
+        backup: true,
     };
 
     let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
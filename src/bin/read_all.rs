@@ -10,7 +10,15 @@ fn main() {
     )])
     .unwrap();
 
-    let db = umanux::UserDBLocal::load_files(umanux::Files::default()).unwrap();
+    // reading needs no locks so this works for users other than root as well.
+    let (db, warnings) = umanux::UserDBLocal::load_files_read_only(
+        umanux::Files::default(),
+        umanux::LoadOptions::Lenient,
+    )
+    .unwrap();
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
 
     for u in db.get_all_users() {
         println!("{}", u);
@@ -21,7 +29,10 @@ fn main() {
                 .map(|group| {
                     (
                         format!("{:?}", group.0),
-                        group.1.borrow().get_groupname().unwrap().to_owned(),
+                        db.get_group_by_id(group.1)
+                            .and_then(GroupRead::get_groupname)
+                            .unwrap()
+                            .to_owned(),
                     )
                 })
                 .collect::<Vec<(String, String)>>()
@@ -29,8 +40,7 @@ fn main() {
     }
 
     for group in db.get_all_groups() {
-        let gp = group.borrow();
-        println!("{}", gp);
-        println!("{:?}", gp.get_member_names())
+        println!("{}", group);
+        println!("{:?}", group.get_member_names())
     }
 }
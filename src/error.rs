@@ -1,9 +1,12 @@
 use std::error::Error;
 use std::fmt::{self, Display};
 
+/// The field of a record that could not be parsed.
 #[allow(clippy::module_name_repetitions)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ParseError {
+    /// The record as a whole, e.g. if it does not have the right number of fields.
+    Record,
     Username,
     Password,
     Uid,
@@ -11,15 +14,96 @@ pub enum ParseError {
     Gecos,
     HomeDir,
     ShellDir,
+    Groupname,
+    Members,
+    Administrators,
+    LastChange,
+    MinDays,
+    MaxDays,
+    WarnDays,
+    InactiveDays,
+    ExpireDate,
+    Reserved,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Record => "record",
+            Self::Username => "username",
+            Self::Password => "password",
+            Self::Uid => "UID",
+            Self::Gid => "GID",
+            Self::Gecos => "gecos field",
+            Self::HomeDir => "home directory",
+            Self::ShellDir => "shell",
+            Self::Groupname => "groupname",
+            Self::Members => "members",
+            Self::Administrators => "administrators",
+            Self::LastChange => "date of the last password change",
+            Self::MinDays => "minimum password age",
+            Self::MaxDays => "maximum password age",
+            Self::WarnDays => "password warning period",
+            Self::InactiveDays => "password inactivity period",
+            Self::ExpireDate => "account expiration date",
+            Self::Reserved => "reserved field",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// A field that could not be parsed together with its position.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseFailure {
+    /// The name of the file like `passwd`. Empty if the content did not come from a file.
+    pub file: String,
+    /// The line number starting at 1.
+    pub line: u32,
+    pub field: ParseError,
+    /// The offending value.
+    pub value: String,
+}
+
+impl ParseFailure {
+    /// A failure in the record at `position` (the line number starting at 0).
+    #[must_use]
+    pub fn new(field: ParseError, value: &str, position: u32) -> Self {
+        Self {
+            file: String::new(),
+            line: position.saturating_add(1),
+            field,
+            value: value.to_owned(),
+        }
+    }
+}
+
+impl Display for ParseFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: invalid {}: {}",
+            self.file, self.line, self.field, self.value
+        )
+    }
 }
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug, PartialEq)]
 pub enum UserLibError {
     NotFound,
-    ParseError,
-    FilesChanged,
+    /// The files have been changed by others in a way that conflicts with the changes of the database.
+    FilesChanged(Vec<crate::userlib::merge::MergeConflict>),
     FilesRequired,
+    /// Some records could not be parsed. All the problems that were found are contained.
+    Parse(Vec<ParseFailure>),
+    /// The files stayed locked by another process until the timeout of the [`LockPolicy`](crate::userlib::files::LockPolicy) passed.
+    LockTimeout,
+    /// The files are locked by the process with this pid.
+    LockHeldBy(u32),
+    /// The database has been loaded read-only and cannot be changed.
+    ReadOnly,
+    /// The user has been added or deleted but its home directory or mail spool failed with the contained error.
+    HomeFailed(Box<Self>),
     Message(MyMessage),
 }
 
@@ -48,14 +132,36 @@ impl Display for UserLibError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::NotFound => write!(f, "not found"),
-            Self::ParseError => write!(f, "failed to parse"),
             Self::FilesRequired => write!(
                 f,
                 "File locking is only possible if some files are specified"
             ),
-            Self::FilesChanged => write!(
+            Self::FilesChanged(conflicts) => {
+                write!(
+                    f,
+                    "The files changed. Updating could lead to conflict aborting."
+                )?;
+                for conflict in conflicts {
+                    write!(f, "\n{conflict}")?;
+                }
+                Ok(())
+            }
+            Self::Parse(failures) => {
+                write!(f, "Failed to parse the files:")?;
+                for failure in failures {
+                    write!(f, "\n{failure}")?;
+                }
+                Ok(())
+            }
+            Self::LockTimeout => write!(f, "Timeout while waiting for the files to be unlocked"),
+            Self::LockHeldBy(pid) => write!(f, "The files are locked by the process {pid}"),
+            Self::ReadOnly => write!(
+                f,
+                "The database has been loaded read-only and cannot be changed"
+            ),
+            Self::HomeFailed(e) => write!(
                 f,
-                "The files changed. Updating could lead to conflict aborting."
+                "The user database has been changed but the home directory failed: {e}"
             ),
             Self::Message(message) => write!(f, "{}", message),
         }
@@ -66,15 +172,44 @@ impl Error for UserLibError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match *self {
             Self::NotFound
-            | Self::ParseError
-            | Self::FilesChanged
+            | Self::FilesChanged(_)
             | Self::FilesRequired
+            | Self::Parse(_)
+            | Self::LockTimeout
+            | Self::LockHeldBy(_)
+            | Self::ReadOnly
             | Self::Message(MyMessage::Simple(_)) => None,
+            Self::HomeFailed(ref e) => Some(e.as_ref()),
             Self::Message(MyMessage::IOError(_, ref e)) => Some(e),
         }
     }
 }
 
+impl UserLibError {
+    /// Set the file of all the contained parse failures.
+    #[must_use]
+    pub fn in_file(self, file: &str) -> Self {
+        match self {
+            Self::Parse(failures) => Self::Parse(
+                failures
+                    .into_iter()
+                    .map(|failure| ParseFailure {
+                        file: file.to_owned(),
+                        ..failure
+                    })
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+}
+
+impl From<ParseFailure> for UserLibError {
+    fn from(failure: ParseFailure) -> Self {
+        Self::Parse(vec![failure])
+    }
+}
+
 impl From<&str> for UserLibError {
     fn from(err: &str) -> Self {
         Self::Message(MyMessage::Simple(err.to_owned()))
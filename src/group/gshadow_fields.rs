@@ -0,0 +1,167 @@
+use crate::error::{ParseError, ParseFailure};
+use crate::userlib::{parse_field, NewFromString};
+
+use super::Groupname;
+use crate::UserLibError;
+use std::cmp::Eq;
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Display};
+
+/// A record(line) in the group shadow database `/etc/gshadow` found in most linux systems.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct GShadow {
+    pos: u32,
+    groupname: Groupname,                          /* Groupname.  */
+    pub(crate) password: crate::EncryptedPassword, /* Hashed passphrase */
+    administrators: Vec<crate::Username>,          /* May change the password and the members */
+    members: Vec<crate::Username>,                 /* Members without the password */
+}
+
+impl GShadow {
+    /// Create a new record with a locked password (`!`) that is not yet part of any database.
+    ///
+    /// # Errors
+    /// Fails if the groupname or one of the member names is invalid.
+    pub fn new(groupname: &str, members: &[&str]) -> Result<Self, UserLibError> {
+        Ok(Self {
+            pos: u32::MAX,
+            groupname: Groupname::try_from(groupname.to_owned())?,
+            password: crate::EncryptedPassword::try_from("!".to_owned())?,
+            administrators: Vec::new(),
+            members: members
+                .iter()
+                .map(|m| crate::Username::try_from((*m).to_owned()))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// The line number of the record starting at 0.
+    pub(crate) const fn get_pos(&self) -> u32 {
+        self.pos
+    }
+
+    #[must_use]
+    pub fn get_groupname(&self) -> &str {
+        &self.groupname.groupname
+    }
+
+    #[must_use]
+    pub fn get_password(&self) -> &str {
+        &self.password.password
+    }
+
+    #[must_use]
+    pub fn get_administrator_names(&self) -> Vec<&str> {
+        self.administrators
+            .iter()
+            .map(|u| u.username.as_str())
+            .collect()
+    }
+
+    #[must_use]
+    pub fn get_member_names(&self) -> Vec<&str> {
+        self.members.iter().map(|u| u.username.as_str()).collect()
+    }
+
+    pub(crate) fn set_groupname(&mut self, groupname: Groupname) {
+        self.groupname = groupname;
+    }
+
+    pub(crate) fn add_member(&mut self, username: &str) {
+        if !self.members.iter().any(|u| u.username == username) {
+            self.members.push(crate::Username {
+                username: username.to_owned(),
+            });
+        }
+    }
+
+    /// Remove the user from the members and the administrators.
+    pub(crate) fn remove_member(&mut self, username: &str) {
+        self.members.retain(|u| u.username != username);
+        self.administrators.retain(|u| u.username != username);
+    }
+}
+
+impl Display for GShadow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.groupname,
+            self.password,
+            join_names(&self.administrators),
+            join_names(&self.members),
+        )
+    }
+}
+
+fn join_names(names: &[crate::Username]) -> String {
+    names
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn parse_names(source: &str) -> Result<Vec<crate::Username>, UserLibError> {
+    source
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| crate::Username::try_from(name.to_owned()))
+        .collect()
+}
+
+impl NewFromString for GShadow {
+    /// Parse a line formatted like one in `/etc/gshadow` and construct a matching `GShadow` instance
+    ///
+    /// # Example
+    /// ```
+    /// use umanux::NewFromString;
+    /// let gshad = umanux::GShadow::new_from_string(
+    ///     "wheel:!:dietrich:dietrich,teste".to_string(),
+    ///     0,
+    /// ).unwrap();
+    /// assert_eq!(gshad.get_groupname(), "wheel");
+    /// assert_eq!(gshad.get_member_names(), vec!["dietrich", "teste"]);
+    /// ```
+    ///
+    /// # Errors
+    /// When parsing fails this function returns a [`UserLibError::Parse`] naming the field that could not be parsed.
+    fn new_from_string(line: String, position: u32) -> Result<Self, UserLibError> {
+        let elements: Vec<String> = line.split(':').map(ToString::to_string).collect();
+        if elements.len() == 4 {
+            let names = |field, value: &str| {
+                parse_names(value).map_err(|_| ParseFailure::new(field, value, position))
+            };
+            Ok(Self {
+                pos: position,
+                groupname: parse_field(ParseError::Groupname, &elements[0], position)?,
+                password: parse_field(ParseError::Password, &elements[1], position)?,
+                administrators: names(ParseError::Administrators, &elements[2])?,
+                members: names(ParseError::Members, &elements[3])?,
+            })
+        } else {
+            Err(ParseFailure::new(ParseError::Record, &line, position).into())
+        }
+    }
+}
+
+#[test]
+fn test_parse_and_back_identity() {
+    let line = "wheel:$6$salt$hash:dietrich:dietrich,teste";
+    let line2 = GShadow::new_from_string(line.to_owned(), 0).unwrap();
+    assert_eq!(format!("{line2}"), line);
+    assert_eq!(line2.get_administrator_names(), vec!["dietrich"]);
+    assert_eq!(line2.get_password(), "$6$salt$hash");
+}
+
+#[test]
+fn test_membership_changes() {
+    let mut gshadow = GShadow::new("audio", &["teste"]).unwrap();
+    assert_eq!(gshadow.to_string(), "audio:!::teste");
+    gshadow.add_member("bergfried");
+    gshadow.add_member("teste");
+    assert_eq!(gshadow.get_member_names(), vec!["teste", "bergfried"]);
+    gshadow.remove_member("teste");
+    assert_eq!(gshadow.to_string(), "audio:!::bergfried");
+}
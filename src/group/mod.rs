@@ -1,12 +1,16 @@
 #![allow(clippy::non_ascii_literal)]
 
-use crate::userlib::NewFromString;
+pub mod gshadow_fields;
+
+use crate::error::{ParseError, ParseFailure};
+use crate::userlib::{parse_field, NewFromString};
+pub use gshadow_fields::GShadow;
 use log::warn;
 
 use crate::UserLibError;
+use std::cmp::Eq;
+use std::convert::TryFrom;
 use std::fmt::{self, Debug, Display};
-use std::{cell::RefCell, convert::TryFrom};
-use std::{cmp::Eq, rc::Rc};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum MembershipKind {
@@ -20,7 +24,7 @@ pub struct Membership {
     username: crate::Username,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Groupname {
     groupname: String,
 }
@@ -50,26 +54,40 @@ pub(crate) fn is_groupname_valid(name: &str) -> bool {
     crate::user::passwd_fields::is_username_valid(name)
 }
 
-pub type Group = Rc<RefCell<Inner>>;
-/// A record(line) in the user database `/etc/shadow` found in most linux systems.
-#[derive(Debug, PartialEq, Eq)]
-pub struct Inner {
+/// A record(line) in the group database `/etc/group` found in most linux systems.
+///
+/// Groups are owned by the database. Users refer to their groups by GID (see [`User::get_groups`](crate::User::get_groups)).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Group {
     pos: u32,
-    source: String,
     groupname: Groupname,                 /* Username.  */
     pub(crate) password: crate::Password, /* Usually not used (disabled with x) */
     gid: crate::Gid,                      /* Group ID.  */
     members: Vec<Membership>,             /* Real name.  */
+    gshadow: Option<GShadow>,             /* The matching entry in `/etc/gshadow` */
 }
 
-impl Inner {
-    #[must_use]
-    pub fn remove_in(&self, content: &str) -> String {
-        content
-            .split(&self.source)
-            .map(str::trim)
-            .collect::<Vec<&str>>()
-            .join("\n")
+impl Group {
+    /// Create a new group record that is not yet part of any database.
+    ///
+    /// # Errors
+    /// Fails if the groupname or one of the member names is invalid.
+    pub fn new(groupname: &str, gid: u32, members: &[&str]) -> Result<Self, UserLibError> {
+        let mut group = Self {
+            pos: u32::MAX,
+            groupname: Groupname::try_from(groupname.to_owned())?,
+            password: crate::Password::Disabled,
+            gid: crate::Gid { gid },
+            members: Vec::new(),
+            gshadow: Some(GShadow::new(groupname, members)?),
+        };
+        for member in members {
+            group.members.push(Membership {
+                kind: MembershipKind::Member,
+                username: crate::Username::try_from((*member).to_owned())?,
+            });
+        }
+        Ok(group)
     }
 
     pub(super) fn append_user(&mut self, username: &str) {
@@ -81,14 +99,96 @@ impl Inner {
         })
     }
 
+    pub(super) fn add_member(&mut self, username: &str) {
+        self.members.push(Membership {
+            kind: MembershipKind::Member,
+            username: crate::Username {
+                username: username.to_owned(),
+            },
+        });
+        if let Some(ref mut gshadow) = self.gshadow {
+            gshadow.add_member(username);
+        }
+    }
+
     pub(super) fn remove_member(&mut self, kind: MembershipKind, username: &str) {
         self.members
-            .retain(|u| !(u.username.username == username && u.kind == kind))
+            .retain(|u| !(u.username.username == username && u.kind == kind));
+        if kind == MembershipKind::Member {
+            if let Some(ref mut gshadow) = self.gshadow {
+                gshadow.remove_member(username);
+            }
+        }
+    }
+
+    /// Remove the user from the members and the administrators of the gshadow entry.
+    ///
+    /// The gshadow entry may list users that are not members in `/etc/group`. Returns whether the entry has been changed.
+    pub(super) fn remove_from_gshadow(&mut self, username: &str) -> bool {
+        match self.gshadow {
+            Some(ref mut gshadow)
+                if gshadow.get_member_names().contains(&username)
+                    || gshadow.get_administrator_names().contains(&username) =>
+            {
+                gshadow.remove_member(username);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Rename the group and its gshadow entry.
+    pub(super) fn rename(&mut self, groupname: &str) -> Result<(), UserLibError> {
+        self.groupname = Groupname::try_from(groupname.to_owned())?;
+        if let Some(ref mut gshadow) = self.gshadow {
+            gshadow.set_groupname(self.groupname.clone());
+        }
+        Ok(())
+    }
+
+    /// Create the missing gshadow entry of the group like [`Group::new`] does.
+    ///
+    /// Like `grpconv` the password of the group is moved to the new entry and replaced with `x`.
+    pub(super) fn add_missing_gshadow(&mut self) -> Result<(), UserLibError> {
+        if self.gshadow.is_some() {
+            return Ok(());
+        }
+        let members: Vec<&str> = self
+            .members
+            .iter()
+            .filter(|m| m.kind == MembershipKind::Member)
+            .map(|m| m.username.username.as_str())
+            .collect();
+        let mut gshadow = GShadow::new(&self.groupname.groupname, &members)?;
+        match self.password {
+            crate::Password::Encrypted(ref password) if password.password != "x" => {
+                gshadow.password = password.clone();
+            }
+            _ => (),
+        }
+        self.password = crate::Password::Disabled;
+        self.gshadow = Some(gshadow);
+        Ok(())
+    }
+
+    /// The matching entry of `/etc/gshadow` if there is one.
+    #[must_use]
+    pub const fn get_gshadow(&self) -> Option<&GShadow> {
+        self.gshadow.as_ref()
+    }
+
+    /// The line number of the record starting at 0.
+    pub(crate) const fn get_pos(&self) -> u32 {
+        self.pos
+    }
+
+    pub(crate) fn set_gshadow(&mut self, gshadow: Option<GShadow>) {
+        self.gshadow = gshadow;
     }
 }
 
 use crate::api::GroupRead;
-impl GroupRead for Inner {
+impl GroupRead for Group {
     #[must_use]
     fn get_groupname(&self) -> Option<&str> {
         Some(&self.groupname.groupname)
@@ -107,11 +207,17 @@ impl GroupRead for Inner {
     }
 
     fn get_encrypted_password(&self) -> Option<&str> {
-        todo!()
+        match (&self.gshadow, &self.password) {
+            (Some(gshadow), _) => Some(gshadow.get_password()),
+            (None, crate::Password::Encrypted(crate::EncryptedPassword { password })) => {
+                Some(password)
+            }
+            (None, _) => None,
+        }
     }
 }
 
-impl Display for Inner {
+impl Display for Group {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         write!(
             f,
@@ -143,66 +249,71 @@ impl NewFromString for Group {
     ///     "teste:x:1002:test,teste".to_owned(),
     ///     0,
     /// ).unwrap();
-    /// assert_eq!(grp.borrow().get_groupname().unwrap(), "teste");
+    /// assert_eq!(grp.get_groupname().unwrap(), "teste");
     /// ```
     ///
     /// # Errors
-    /// When parsing fails this function returns a `UserLibError::Message` containing some information as to why the function failed.
+    /// When parsing fails this function returns a [`UserLibError::Parse`] naming the field that could not be parsed.
     fn new_from_string(line: String, position: u32) -> Result<Self, UserLibError> {
         let elements: Vec<String> = line.split(':').map(ToString::to_string).collect();
         if elements.len() == 4 {
-            Ok(Self::new(RefCell::new(Inner {
+            Ok(Self {
+                groupname: parse_field(ParseError::Groupname, &elements[0], position)?,
+                password: crate::Password::Encrypted(parse_field(
+                    ParseError::Password,
+                    &elements[1],
+                    position,
+                )?),
+                gid: parse_field(ParseError::Gid, &elements[2], position)?,
+                members: parse_members_list(&elements[3], position)?,
+                gshadow: None,
                 pos: position,
-                source: line,
-                groupname: Groupname::try_from(elements.get(0).unwrap().to_string())?,
-                password: crate::Password::Disabled,
-                gid: crate::Gid::try_from(elements.get(2).unwrap().to_string())?,
-                members: parse_members_list(elements.get(3).unwrap()),
-            })))
+            })
         } else {
-            Err(format!(
-                "Failed to parse: not enough elements ({}): {:?}",
-                elements.len(),
-                elements
-            )
-            .into())
+            Err(ParseFailure::new(ParseError::Record, &line, position).into())
         }
     }
 }
 
-fn parse_members_list(source: &str) -> Vec<Membership> {
-    let mut res = vec![];
-    for mem in source.split(',').filter_map(|x| {
-        if x.is_empty() {
-            None
-        } else {
-            Some(x.to_string())
-        }
-    }) {
-        res.push(Membership {
-            kind: MembershipKind::Member,
-            username: crate::Username::try_from(mem).expect("failed to parse username"),
-        });
-    }
-    res
+fn parse_members_list(source: &str, position: u32) -> Result<Vec<Membership>, UserLibError> {
+    source
+        .split(',')
+        .filter(|x| !x.is_empty())
+        .map(|mem| {
+            Ok(Membership {
+                kind: MembershipKind::Member,
+                username: crate::Username::try_from(mem.to_owned())
+                    .map_err(|_| ParseFailure::new(ParseError::Members, mem, position))?,
+            })
+        })
+        .collect()
 }
 
 #[test]
 fn test_parse_and_back_identity() {
     let line = "teste:x:1002:test,teste";
     let line2: Group = Group::new_from_string(line.to_owned(), 0).unwrap();
-    assert_eq!(format!("{}", line2.borrow()), line);
+    assert_eq!(line2.to_string(), line);
 }
 
 #[test]
 fn test_groupname() {
     let line = "teste:x:1002:test,teste";
     let line2 = Group::new_from_string(line.to_owned(), 0).unwrap();
-    assert_eq!(line2.borrow().get_groupname().unwrap(), "teste");
+    assert_eq!(line2.get_groupname().unwrap(), "teste");
 }
 #[test]
 fn test_root_group() {
     let line = "root:x:0:";
     let line2 = Group::new_from_string(line.to_owned(), 0).unwrap();
-    assert_eq!(line2.borrow().get_groupname().unwrap(), "root");
+    assert_eq!(line2.get_groupname().unwrap(), "root");
+}
+
+#[test]
+fn test_invalid_member() {
+    let failure = Group::new_from_string("teste:x:1002:test,Te$te".to_owned(), 7);
+    assert_eq!(
+        failure.unwrap_err(),
+        ParseFailure::new(ParseError::Members, "Te$te", 7).into()
+    );
 }
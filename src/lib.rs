@@ -22,11 +22,15 @@ pub mod group;
 pub mod user;
 pub mod userlib;
 pub use error::UserLibError;
-pub use group::Group;
+pub use group::{GShadow, Group};
 pub use user::gecos_fields::Gecos;
 pub use user::passwd_fields::{
     EncryptedPassword, Gid, HomeDir, Password, ShellPath, Uid, Username,
 };
 pub use user::shadow_fields::Shadow;
 pub use user::User;
-pub use userlib::{files::Files, NewFromString, UserDBLocal};
+pub use userlib::{
+    files::{Files, LockPolicy},
+    merge::MergeConflict,
+    LoadOptions, NewFromString, OptionalFiles, UserDBLocal,
+};
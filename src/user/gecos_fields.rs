@@ -142,7 +142,7 @@ impl TryFrom<String> for Gecos {
                 comment: vals.get(0).unwrap().into(),
             })
         } else {
-            panic!(format!("Could not parse this string: {}", source))
+            Err(format!("Could not parse the gecos field: {source}").into())
         }
     }
 }
@@ -6,39 +6,44 @@ pub mod shadow_fields;
 use crate::userlib::NewFromString;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use std::convert::TryFrom;
 use std::fmt::{self, Display};
 
 /// A record(line) in the user database `/etc/passwd` found in most linux systems.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct User {
-    source: String,
     pos: u32,
-    username: crate::Username,            /* Username.  */
-    pub(crate) password: crate::Password, /* Hashed passphrase, if shadow database not in use (see shadow.h).  */
-    uid: crate::Uid,                      /* User ID.  */
-    gid: crate::Gid,                      /* Group ID.  */
-    gecos: crate::Gecos,                  /* Real name.  */
-    home_dir: crate::HomeDir,             /* Home directory.  */
-    shell_path: crate::ShellPath,         /* Shell program.  */
-    groups: Vec<(crate::group::MembershipKind, crate::Group)>,
+    username: crate::Username,                        /* Username.  */
+    pub(crate) password: crate::Password,             /* Hashed passphrase or shadow entry.  */
+    uid: crate::Uid,                                  /* User ID.  */
+    gid: crate::Gid,                                  /* Group ID.  */
+    gecos: crate::Gecos,                              /* Real name.  */
+    home_dir: crate::HomeDir,                         /* Home directory.  */
+    shell_path: crate::ShellPath,                     /* Shell program.  */
+    groups: Vec<(crate::group::MembershipKind, u32)>, /* The GIDs of the groups.  */
 }
 
 impl User {
     #[must_use]
     pub const fn get_shadow(&self) -> Option<&crate::Shadow> {
         match self.password {
-            crate::Password::Encrypted(_) | crate::Password::Disabled => None,
+            crate::Password::Encrypted(_)
+            | crate::Password::Disabled
+            | crate::Password::Unknown => None,
             crate::Password::Shadow(ref s) => Some(s),
         }
     }
+    /// How the password of the user is stored. [`Password::Unknown`](crate::Password::Unknown) if the shadow file could not be read.
     #[must_use]
-    pub fn remove_in(&self, content: &str) -> String {
-        content
-            .split(&self.source)
-            .map(str::trim)
-            .collect::<Vec<&str>>()
-            .join("\n")
+    pub const fn get_password_state(&self) -> &crate::Password {
+        &self.password
+    }
+    pub const fn get_shadow_mut(&mut self) -> Option<&mut crate::Shadow> {
+        match self.password {
+            crate::Password::Encrypted(_)
+            | crate::Password::Disabled
+            | crate::Password::Unknown => None,
+            crate::Password::Shadow(ref mut s) => Some(s),
+        }
     }
     pub fn username(&mut self, name: String) -> &mut Self {
         self.username = crate::Username { username: name };
@@ -67,18 +72,26 @@ impl User {
         self.shell_path = crate::ShellPath { shell: path };
         self
     }
+    pub fn gecos(&mut self, gecos: crate::Gecos) -> &mut Self {
+        self.gecos = gecos;
+        self
+    }
+
+    pub fn add_group(&mut self, group_type: crate::group::MembershipKind, gid: u32) -> &mut Self {
+        self.groups.push((group_type, gid));
+        self
+    }
 
-    pub fn add_group(
-        &mut self,
-        group_type: crate::group::MembershipKind,
-        group: crate::Group,
-    ) -> &mut Self {
-        self.groups.push((group_type, group));
+    pub fn remove_group(&mut self, gid: u32) -> &mut Self {
+        self.groups.retain(|(_, g)| *g != gid);
         self
     }
 
+    /// The kind of membership and the GID of all the groups of the user.
+    ///
+    /// The groups themselves are looked up in the database (see [`UserDBRead::get_group_by_id`](crate::api::UserDBRead::get_group_by_id)).
     #[must_use]
-    pub const fn get_groups(&self) -> &Vec<(crate::group::MembershipKind, crate::Group)> {
+    pub const fn get_groups(&self) -> &Vec<(crate::group::MembershipKind, u32)> {
         &self.groups
     }
 }
@@ -96,29 +109,33 @@ impl NewFromString for User {
     /// ```
     ///
     /// # Errors
-    /// When parsing fails this function returns a [`UserLibError::Message`](crate::userlib_error::UserLibError::Message) containing some information as to why the function failed.
+    /// When parsing fails this function returns a [`UserLibError::Parse`](crate::UserLibError::Parse) naming the field that could not be parsed.
     fn new_from_string(line: String, position: u32) -> Result<Self, crate::UserLibError>
     where
         Self: Sized,
     {
+        use crate::error::ParseError;
+        use crate::userlib::parse_field;
+
         let elements: Vec<String> = line.split(':').map(ToString::to_string).collect();
         if elements.len() == 7 {
             Ok(Self {
-                source: line,
-                pos: position,
-                username: crate::Username::try_from(elements.get(0).unwrap().to_string())?,
-                password: crate::Password::Encrypted(crate::EncryptedPassword::try_from(
-                    elements.get(1).unwrap().to_string(),
+                username: parse_field(ParseError::Username, &elements[0], position)?,
+                password: crate::Password::Encrypted(parse_field(
+                    ParseError::Password,
+                    &elements[1],
+                    position,
                 )?),
-                uid: crate::Uid::try_from(elements.get(2).unwrap().to_string())?,
-                gid: crate::Gid::try_from(elements.get(3).unwrap().to_string())?,
-                gecos: crate::Gecos::try_from(elements.get(4).unwrap().to_string())?,
-                home_dir: crate::HomeDir::try_from(elements.get(5).unwrap().to_string())?,
-                shell_path: crate::ShellPath::try_from(elements.get(6).unwrap().to_string())?,
+                uid: parse_field(ParseError::Uid, &elements[2], position)?,
+                gid: parse_field(ParseError::Gid, &elements[3], position)?,
+                gecos: parse_field(ParseError::Gecos, &elements[4], position)?,
+                home_dir: parse_field(ParseError::HomeDir, &elements[5], position)?,
+                shell_path: parse_field(ParseError::ShellDir, &elements[6], position)?,
+                pos: position,
                 groups: Vec::new(),
             })
         } else {
-            Err("Failed to parse: not enough elements".into())
+            Err(crate::error::ParseFailure::new(ParseError::Record, &line, position).into())
         }
     }
 }
@@ -133,7 +150,7 @@ impl crate::api::UserRead for User {
         match &self.password {
             crate::Password::Encrypted(crate::EncryptedPassword { password }) => Some(password),
             crate::Password::Shadow(crate::Shadow { ref password, .. }) => Some(&password.password),
-            crate::Password::Disabled => None,
+            crate::Password::Disabled | crate::Password::Unknown => None,
         }
     }
     #[must_use]
@@ -205,7 +222,6 @@ impl Default for User {
             shadow_fields::Shadow::new_from_string(line.to_owned(), u32::MAX).unwrap(),
         );
         Self {
-            source: "".to_owned(),
             pos: u32::MAX,
             username,
             password,
@@ -256,7 +272,15 @@ fn test_default_user() {
 fn test_new_from_string() {
     // Test if a single line can be parsed and if the resulting struct is populated correctly.
     let fail = User::new_from_string("".into(), 0).err().unwrap();
-    assert_eq!(fail, "Failed to parse: not enough elements".into());
+    assert_eq!(
+        fail,
+        crate::error::ParseFailure::new(crate::error::ParseError::Record, "", 0).into()
+    );
+    let fail = User::new_from_string("test:x:10o1:1001::/home/test:/bin/test".into(), 4);
+    assert_eq!(
+        fail.err().unwrap(),
+        crate::error::ParseFailure::new(crate::error::ParseError::Uid, "10o1", 4).into()
+    );
     let pwd = User::new_from_string(
         "testuser:testpassword:1001:1001:testcomment:/home/test:/bin/test".into(),
         0,
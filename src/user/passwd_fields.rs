@@ -3,6 +3,7 @@
 use log::warn;
 use regex::Regex;
 
+use crate::userlib::allocation::IdRanges;
 use crate::UserLibError;
 use std::cmp::Eq;
 use std::convert::TryFrom;
@@ -52,20 +53,22 @@ pub enum Password {
     Encrypted(crate::EncryptedPassword),
     Shadow(crate::Shadow),
     Disabled,
+    /// The password is stored in a shadow file that could not be read.
+    Unknown,
 }
 
 impl Display for Password {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Encrypted(EncryptedPassword { password }) => write!(f, "{}", password,),
-            Self::Shadow(_) | Self::Disabled => write!(f, "x"),
+            Self::Shadow(_) | Self::Disabled | Self::Unknown => write!(f, "x"),
         }
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct EncryptedPassword {
-    pub(in crate::user) password: String,
+    pub(crate) password: String,
 }
 
 impl Display for EncryptedPassword {
@@ -100,23 +103,25 @@ impl Display for Uid {
 impl TryFrom<String> for Uid {
     type Error = UserLibError;
     fn try_from(source: String) -> std::result::Result<Self, Self::Error> {
-        Ok(Self {
-            uid: source.parse::<u32>().unwrap(),
-        })
+        match source.parse::<u32>() {
+            Ok(uid) => Ok(Self { uid }),
+            Err(e) => Err(format!("Invalid UID {source}: {e}").into()),
+        }
     }
 }
 
 impl Uid {
+    /// Check if the UID is below the range of regular users (`UID_MIN`).
     #[must_use]
-    pub const fn is_system_uid(&self) -> bool {
+    pub const fn is_system_uid(&self, ranges: &IdRanges) -> bool {
         // since it is a u32  it cannot be smaller than 0
-        self.uid < 1000
+        self.uid < ranges.uid.min
     }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Gid {
-    pub(in crate::user) gid: u32,
+    pub(crate) gid: u32,
 }
 
 impl Display for Gid {
@@ -128,17 +133,19 @@ impl Display for Gid {
 impl TryFrom<String> for Gid {
     type Error = UserLibError;
     fn try_from(source: String) -> std::result::Result<Self, Self::Error> {
-        Ok(Self {
-            gid: source.parse::<u32>().unwrap(),
-        })
+        match source.parse::<u32>() {
+            Ok(gid) => Ok(Self { gid }),
+            Err(e) => Err(format!("Invalid GID {source}: {e}").into()),
+        }
     }
 }
 
 impl Gid {
+    /// Check if the GID is below the range of regular groups (`GID_MIN`).
     #[must_use]
-    pub const fn is_system_gid(&self) -> bool {
+    pub const fn is_system_gid(&self, ranges: &IdRanges) -> bool {
         // since it is a u32  it cannot be smaller than 0
-        self.gid < 1000
+        self.gid < ranges.gid.min
     }
 
     #[must_use]
@@ -222,8 +229,25 @@ fn test_guid_system_user() {
         ("0".to_owned(), true),
         ("1000".to_owned(), false),
     ];
+    let ranges = IdRanges::default();
     for val in values {
-        assert_eq!(Uid::try_from(val.0.clone()).unwrap().is_system_uid(), val.1);
-        assert_eq!(Gid::try_from(val.0.clone()).unwrap().is_system_gid(), val.1);
-    }
+        assert_eq!(
+            Uid::try_from(val.0.clone()).unwrap().is_system_uid(&ranges),
+            val.1
+        );
+        assert_eq!(
+            Gid::try_from(val.0.clone()).unwrap().is_system_gid(&ranges),
+            val.1
+        );
+    }
+    // with a custom boundary
+    let mut ranges = IdRanges::default();
+    ranges.uid.min = 500;
+    ranges.gid.min = 500;
+    assert!(!Uid::try_from("999".to_owned())
+        .unwrap()
+        .is_system_uid(&ranges));
+    assert!(!Gid::try_from("999".to_owned())
+        .unwrap()
+        .is_system_gid(&ranges));
 }
@@ -1,15 +1,14 @@
-use crate::userlib::NewFromString;
+use crate::userlib::{parse_field, NewFromString};
 
+use crate::error::{ParseError, ParseFailure};
 use crate::UserLibError;
 use std::cmp::Eq;
-use std::convert::TryFrom;
 use std::fmt::{self, Debug, Display};
 
 /// A record(line) in the user database `/etc/shadow` found in most linux systems.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Shadow {
     pos: u32,
-    source: String,
     username: crate::Username,                      /* Username.  */
     pub(crate) password: crate::EncryptedPassword,  /* Hashed passphrase */
     last_change: Option<chrono::NaiveDateTime>,     /* User ID.  */
@@ -35,13 +34,60 @@ impl Shadow {
     pub fn get_password(&self) -> &str {
         &self.password.password
     }
-    #[must_use]
-    pub fn remove_in(&self, content: &str) -> String {
-        content
-            .split(&self.source)
-            .map(str::trim)
-            .collect::<Vec<&str>>()
-            .join("\n")
+
+    pub fn set_password(&mut self, password: String) {
+        self.password = crate::EncryptedPassword { password };
+    }
+
+    /// Set the date of the last password change.
+    pub fn set_last_change(&mut self, date: chrono::NaiveDate) {
+        self.last_change = Some(date.and_hms(0, 0, 0));
+    }
+
+    /// Set the date on which the account expires. `None` means the account never expires.
+    pub fn set_expire_date(&mut self, date: Option<chrono::NaiveDate>) {
+        self.deactivated_since =
+            date.map(|d| d.signed_duration_since(chrono::NaiveDate::from_ymd(1970, 1, 1)));
+    }
+
+    /// Set the password aging: the minimum and maximum number of days between password changes and the days of warning before the password expires.
+    ///
+    /// # Errors
+    /// Fails if a number of days is too big to be represented. Nothing is changed in that case.
+    pub fn set_aging(
+        &mut self,
+        min_days: Option<i64>,
+        max_days: Option<i64>,
+        warn_days: Option<i64>,
+    ) -> Result<(), UserLibError> {
+        let out_of_range =
+            |field: ParseError, days: i64| format!("The {field} of {days} days is out of range");
+        let earliest_change = min_days
+            .map(|days| {
+                days_since_epoch_to_date(days)
+                    .ok_or_else(|| out_of_range(ParseError::MinDays, days))
+            })
+            .transpose()?;
+        let latest_change = max_days
+            .map(|days| {
+                days_since_epoch_to_date(days)
+                    .ok_or_else(|| out_of_range(ParseError::MaxDays, days))
+            })
+            .transpose()?;
+        let warn_period = warn_days
+            .map(|days| {
+                days_to_duration(days).ok_or_else(|| out_of_range(ParseError::WarnDays, days))
+            })
+            .transpose()?;
+        self.earliest_change = earliest_change;
+        self.latest_change = latest_change;
+        self.warn_period = warn_period;
+        Ok(())
+    }
+
+    /// The line number of the record starting at 0.
+    pub(crate) const fn get_pos(&self) -> u32 {
+        self.pos
     }
 }
 
@@ -97,57 +143,92 @@ impl NewFromString for Shadow {
     /// ```
     ///
     /// # Errors
-    /// When parsing fails this function returns a `UserLibError::Message` containing some information as to why the function failed.
+    /// When parsing fails this function returns a [`UserLibError::Parse`] naming the field that could not be parsed.
     fn new_from_string(line: String, position: u32) -> Result<Self, UserLibError> {
         let elements: Vec<String> = line.split(':').map(ToString::to_string).collect();
         if elements.len() == 9 {
-            let extra = elements.get(8).unwrap();
+            let extra = &elements[8];
             Ok(Self {
-                pos: position,
-                source: line,
-                username: crate::Username::try_from(elements.get(0).unwrap().to_string())?,
-                password: crate::EncryptedPassword::try_from(elements.get(1).unwrap().to_string())?,
-                last_change: date_since_epoch(elements.get(2).unwrap()),
-                earliest_change: date_since_epoch(elements.get(3).unwrap()),
-                latest_change: date_since_epoch(elements.get(4).unwrap()),
-                warn_period: duration_for_days(elements.get(5).unwrap()),
-                deactivated: duration_for_days(elements.get(6).unwrap()),
-                deactivated_since: duration_for_days(elements.get(7).unwrap()),
+                username: parse_field(ParseError::Username, &elements[0], position)?,
+                password: parse_field(ParseError::Password, &elements[1], position)?,
+                last_change: date_since_epoch(ParseError::LastChange, &elements[2], position)?,
+                earliest_change: date_since_epoch(ParseError::MinDays, &elements[3], position)?,
+                latest_change: date_since_epoch(ParseError::MaxDays, &elements[4], position)?,
+                warn_period: duration_for_days(ParseError::WarnDays, &elements[5], position)?,
+                deactivated: duration_for_days(ParseError::InactiveDays, &elements[6], position)?,
+                deactivated_since: duration_for_days(
+                    ParseError::ExpireDate,
+                    &elements[7],
+                    position,
+                )?,
                 extensions: if extra.is_empty() {
                     None
                 } else {
-                    Some(extra.parse::<u64>().unwrap())
+                    Some(
+                        extra.parse::<u64>().map_err(|_| {
+                            ParseFailure::new(ParseError::Reserved, extra, position)
+                        })?,
+                    )
                 },
+                pos: position,
             })
         } else {
-            Err(format!(
-                "Failed to parse: not enough elements ({}): {:?}",
-                elements.len(),
-                elements
-            )
-            .into())
+            Err(ParseFailure::new(ParseError::Record, &line, position).into())
         }
     }
 }
 
 const SECONDS_PER_DAY: i64 = 86400;
 
-fn date_since_epoch(days_since_epoch: &str) -> Option<chrono::NaiveDateTime> {
-    if days_since_epoch.is_empty() {
-        None
+/// Parse a number of days. An empty field means `None`.
+fn parse_days(field: ParseError, days: &str, position: u32) -> Result<Option<i64>, UserLibError> {
+    if days.is_empty() {
+        Ok(None)
     } else {
-        let days: i64 = days_since_epoch.parse::<i64>().unwrap();
-        let seconds = days * SECONDS_PER_DAY;
-        Some(chrono::NaiveDateTime::from_timestamp(seconds, 0))
+        days.parse::<i64>()
+            .map(Some)
+            .map_err(|_| ParseFailure::new(field, days, position).into())
     }
 }
-fn duration_for_days(days_source: &str) -> Option<chrono::Duration> {
-    if days_source.is_empty() {
-        None
-    } else {
-        let days: i64 = days_source.parse::<i64>().unwrap();
-        Some(chrono::Duration::days(days))
-    }
+
+/// Parse a number of days and convert it with `convert`. Numbers that cannot be converted are parse failures as well.
+fn convert_days<T>(
+    field: ParseError,
+    days: &str,
+    position: u32,
+    convert: fn(i64) -> Option<T>,
+) -> Result<Option<T>, UserLibError> {
+    parse_days(field, days, position)?
+        .map(|number| {
+            convert(number).ok_or_else(|| ParseFailure::new(field, days, position).into())
+        })
+        .transpose()
+}
+
+fn date_since_epoch(
+    field: ParseError,
+    days_since_epoch: &str,
+    position: u32,
+) -> Result<Option<chrono::NaiveDateTime>, UserLibError> {
+    convert_days(field, days_since_epoch, position, days_since_epoch_to_date)
+}
+/// The date `days` after the epoch. `None` if it is out of range.
+fn days_since_epoch_to_date(days: i64) -> Option<chrono::NaiveDateTime> {
+    days.checked_mul(SECONDS_PER_DAY)
+        .and_then(|seconds| chrono::NaiveDateTime::from_timestamp_opt(seconds, 0))
+}
+/// A duration of `days`. `None` if it is out of range.
+fn days_to_duration(days: i64) -> Option<chrono::Duration> {
+    // unlike `Duration::days` the milliseconds do not panic on large values.
+    days.checked_mul(SECONDS_PER_DAY * 1000)
+        .map(chrono::Duration::milliseconds)
+}
+fn duration_for_days(
+    field: ParseError,
+    days_source: &str,
+    position: u32,
+) -> Result<Option<chrono::Duration>, UserLibError> {
+    convert_days(field, days_source, position, days_to_duration)
 }
 
 #[test]
@@ -156,3 +237,31 @@ fn test_parse_and_back_identity() {
     let line2 = Shadow::new_from_string(line.to_owned(), 0).unwrap();
     assert_eq!(format!("{}", line2), line);
 }
+
+#[test]
+fn test_parse_failures() {
+    let failure = Shadow::new_from_string("test:!:18260:0:99999:seven:::".to_owned(), 2);
+    assert_eq!(
+        failure.unwrap_err(),
+        ParseFailure::new(ParseError::WarnDays, "seven", 2).into()
+    );
+    // numbers of days that are too big for a date or a duration.
+    let failure = Shadow::new_from_string("test:!:999999999999999:0:99999:7:::".to_owned(), 1);
+    assert_eq!(
+        failure.unwrap_err(),
+        ParseFailure::new(ParseError::LastChange, "999999999999999", 1).into()
+    );
+    let failure = Shadow::new_from_string("test:!:18260:0:99999:7::999999999999999:".to_owned(), 1);
+    assert_eq!(
+        failure.unwrap_err(),
+        ParseFailure::new(ParseError::ExpireDate, "999999999999999", 1).into()
+    );
+    let mut shadow = Shadow::new_from_string("test:!:18260:0:99999:7:::".to_owned(), 0).unwrap();
+    assert!(shadow.set_aging(Some(0), Some(i64::MAX), Some(7)).is_err());
+    assert_eq!(shadow.to_string(), "test:!:18260:0:99999:7:::");
+    let failure = Shadow::new_from_string("test:!:18260".to_owned(), 0);
+    assert_eq!(
+        failure.unwrap_err(),
+        ParseFailure::new(ParseError::Record, "test:!:18260", 0).into()
+    );
+}
@@ -0,0 +1,199 @@
+//! Allocation of new UIDs and GIDs in the way shadow-utils does it.
+//!
+//! Regular accounts get the next ID above the highest one in use, system accounts get the next ID below the lowest one in use.
+//! Only if that ID is outside of the range the gaps are searched.
+use super::UserDBLocal;
+use crate::api::{GroupRead, UserRead};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::collections::HashSet;
+
+/// An inclusive range of IDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl IdRange {
+    #[must_use]
+    pub const fn new(min: u32, max: u32) -> Self {
+        Self { min, max }
+    }
+
+    #[must_use]
+    pub const fn contains(self, id: u32) -> bool {
+        self.min <= id && id <= self.max
+    }
+
+    /// Find a free ID for a regular account: the highest used ID + 1 or the lowest gap.
+    fn find_up(self, used: &HashSet<u32>) -> Option<u32> {
+        let candidate = used
+            .iter()
+            .filter(|id| self.contains(**id))
+            .max()
+            .map_or(self.min, |highest| highest.saturating_add(1));
+        if self.contains(candidate) && !used.contains(&candidate) {
+            Some(candidate)
+        } else {
+            (self.min..=self.max).find(|id| !used.contains(id))
+        }
+    }
+
+    /// Find a free ID for a system account: the lowest used ID - 1 or the highest gap.
+    fn find_down(self, used: &HashSet<u32>) -> Option<u32> {
+        let candidate = used
+            .iter()
+            .filter(|id| self.contains(**id))
+            .min()
+            .map_or(Some(self.max), |lowest| lowest.checked_sub(1));
+        match candidate {
+            Some(id) if self.contains(id) && !used.contains(&id) => Some(id),
+            _ => (self.min..=self.max).rev().find(|id| !used.contains(id)),
+        }
+    }
+
+    fn find(self, used: &HashSet<u32>, system: bool) -> Option<u32> {
+        if system {
+            self.find_down(used)
+        } else {
+            self.find_up(used)
+        }
+    }
+}
+
+/// The ranges the IDs for new users and groups are taken from (`UID_MIN`, `SYS_UID_MAX`, ... in `login.defs`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRanges {
+    pub uid: IdRange,
+    pub sys_uid: IdRange,
+    pub gid: IdRange,
+    pub sys_gid: IdRange,
+}
+
+impl Default for IdRanges {
+    /// The defaults of shadow-utils
+    fn default() -> Self {
+        Self {
+            uid: IdRange::new(1000, 60000),
+            sys_uid: IdRange::new(101, 999),
+            gid: IdRange::new(1000, 60000),
+            sys_gid: IdRange::new(101, 999),
+        }
+    }
+}
+
+impl IdRanges {
+    #[must_use]
+    pub const fn uid_range(&self, system: bool) -> IdRange {
+        if system {
+            self.sys_uid
+        } else {
+            self.uid
+        }
+    }
+
+    #[must_use]
+    pub const fn gid_range(&self, system: bool) -> IdRange {
+        if system {
+            self.sys_gid
+        } else {
+            self.gid
+        }
+    }
+}
+
+impl UserDBLocal {
+    fn used_uids(&self) -> HashSet<u32> {
+        self.users
+            .values()
+            .map(UserRead::get_uid)
+            .chain(self.invalid_records.uids.iter().copied())
+            .chain(self.foreign_ids.uids.iter().copied())
+            .collect()
+    }
+
+    fn used_gids(&self) -> HashSet<u32> {
+        self.groups
+            .iter()
+            .filter_map(GroupRead::get_gid)
+            .chain(self.invalid_records.gids.iter().copied())
+            .chain(self.foreign_ids.gids.iter().copied())
+            .collect()
+    }
+
+    /// Find the next free UID in the range for regular or system users.
+    #[must_use]
+    pub fn next_free_uid(&self, system: bool) -> Option<u32> {
+        let uid = self
+            .get_id_ranges()
+            .uid_range(system)
+            .find(&self.used_uids(), system);
+        debug!("Next free UID (system: {}): {:?}", system, uid);
+        uid
+    }
+
+    /// Find the next free GID in the range for regular or system groups.
+    #[must_use]
+    pub fn next_free_gid(&self, system: bool) -> Option<u32> {
+        let gid = self
+            .get_id_ranges()
+            .gid_range(system)
+            .find(&self.used_gids(), system);
+        debug!("Next free GID (system: {}): {:?}", system, gid);
+        gid
+    }
+
+    /// Find a UID and a GID for a new user and its primary group.
+    ///
+    /// If possible both IDs are the same. Otherwise the next free UID and GID are returned.
+    #[must_use]
+    pub fn next_free_uid_gid_pair(&self, system: bool) -> Option<(u32, u32)> {
+        let ranges = self.get_id_ranges();
+        let uid = self.next_free_uid(system)?;
+        let gids_in_use = self.used_gids();
+        if !gids_in_use.contains(&uid) && ranges.gid_range(system).contains(uid) {
+            return Some((uid, uid));
+        }
+        // search for an ID that is free in both databases.
+        let used: HashSet<u32> = self.used_uids().union(&gids_in_use).copied().collect();
+        if let Some(id) = ranges
+            .uid_range(system)
+            .find(&used, system)
+            .filter(|id| ranges.gid_range(system).contains(*id))
+        {
+            return Some((id, id));
+        }
+        warn!("No matching UID/GID pair is free, using different IDs");
+        Some((uid, self.next_free_gid(system)?))
+    }
+}
+
+#[test]
+fn test_find_ids() {
+    let range = IdRange::new(1000, 1005);
+    let used: HashSet<u32> = [0, 1000, 1001, 1003, 65534].iter().copied().collect();
+    assert_eq!(range.find(&used, false), Some(1004));
+    assert_eq!(range.find(&used, true), Some(1005));
+    let used: HashSet<u32> = [1002, 1005].iter().copied().collect();
+    // the highest ID is in use, so the gaps are used.
+    assert_eq!(range.find(&used, false), Some(1000));
+    assert_eq!(range.find(&used, true), Some(1001));
+    let used: HashSet<u32> = (1000..=1005).collect();
+    assert_eq!(range.find(&used, false), None);
+    assert_eq!(range.find(&used, true), None);
+}
+
+#[test]
+fn test_uid_gid_pairs() {
+    let data = UserDBLocal::import_from_strings(
+        "a:x:1000:1000::/home/a:/bin/sh\nb:x:1001:1000::/home/b:/bin/sh",
+        "",
+        "a:x:1000:\nc:x:1002:",
+    )
+    .unwrap();
+    // 1002 is used as GID so the pair 1003 is the first that is free in both.
+    assert_eq!(data.next_free_uid(false), Some(1002));
+    assert_eq!(data.next_free_uid_gid_pair(false), Some((1003, 1003)));
+    assert_eq!(data.next_free_uid_gid_pair(true), Some((999, 999)));
+}
@@ -0,0 +1,145 @@
+//! A database file as a list of lines that is edited record by record.
+//!
+//! Every line of the file is kept including comments, empty lines and lines that cannot be parsed.
+//! Only the records that are edited are replaced so writing an unchanged document reproduces the file byte by byte.
+use std::fmt::{self, Display};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    lines: Vec<String>,
+    trailing_newline: bool,
+}
+
+/// Records are all the lines that are neither empty nor comments.
+pub(super) fn is_record(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with('#')
+}
+
+/// The name of a record is its first field.
+pub(super) fn name_of(line: &str) -> &str {
+    line.split(':').next().unwrap_or(line)
+}
+
+impl Document {
+    /// Split the content into lines. Line endings other than `\n` are kept as part of the lines.
+    #[must_use]
+    pub fn parse(content: &str) -> Self {
+        let mut lines: Vec<String> = content.split('\n').map(ToOwned::to_owned).collect();
+        // a trailing newline results in an empty last element.
+        let trailing_newline = content.is_empty() || content.ends_with('\n');
+        if trailing_newline {
+            lines.pop();
+        }
+        Self {
+            lines,
+            trailing_newline,
+        }
+    }
+
+    /// All the lines including comments and empty lines.
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().map(String::as_str)
+    }
+
+    /// Replace all the lines keeping the line ending at the end of the file.
+    pub fn replace_lines(&mut self, lines: Vec<String>) {
+        self.lines = lines;
+    }
+
+    /// All the records together with their line numbers (starting at 0).
+    pub fn records(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| is_record(line))
+            .map(|(n, line)| (n, line.as_str()))
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.lines
+            .iter()
+            .position(|line| is_record(line) && name_of(line) == name)
+    }
+
+    /// The first record with the given name.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.position(name).map(|n| self.lines[n].as_str())
+    }
+
+    /// Replace the first record with the given name or append `record` if there is none.
+    pub fn set(&mut self, name: &str, record: String) {
+        match self.position(name) {
+            Some(n) => self.lines[n] = record,
+            None => self.push(record),
+        }
+    }
+
+    /// Remove the first record with the given name and return it.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        self.position(name).map(|n| self.lines.remove(n))
+    }
+
+    /// Append a record at the end of the file.
+    pub fn push(&mut self, record: String) {
+        self.lines.push(record);
+    }
+
+    /// Keep only the records for which `keep` returns `true`. Comments and empty lines are always kept.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&str) -> bool,
+    {
+        self.lines.retain(|line| !is_record(line) || keep(line));
+    }
+}
+
+impl Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.lines.join("\n"))?;
+        if self.trailing_newline && !self.lines.is_empty() {
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_document_identity() {
+    for content in &[
+        "",
+        "\n",
+        "root:x:0:\n",
+        "root:x:0:",
+        "# groups\n\nroot:x:0:\n  \nbin:x:1:\n\n",
+        "root:x:0:\r\nbin:x:1:\r\n",
+        "a:!::\n",
+    ] {
+        assert_eq!(Document::parse(content).to_string(), *content);
+    }
+}
+
+#[test]
+fn test_document_edits() {
+    let mut document = Document::parse("# users\nroot:x:0:\n\nbin:x:1:\n");
+    assert_eq!(
+        document.records().collect::<Vec<_>>(),
+        vec![(1, "root:x:0:"), (3, "bin:x:1:")]
+    );
+    assert_eq!(document.get("bin"), Some("bin:x:1:"));
+    document.set("root", "root:x:0:fred".to_owned());
+    document.set("audio", "audio:x:63:".to_owned());
+    assert_eq!(document.remove("bin"), Some("bin:x:1:".to_owned()));
+    assert_eq!(document.remove("bin"), None);
+    assert_eq!(
+        document.to_string(),
+        "# users\nroot:x:0:fred\n\naudio:x:63:\n"
+    );
+    document.retain(|line| !line.starts_with("audio"));
+    assert_eq!(document.to_string(), "# users\nroot:x:0:fred\n\n");
+
+    let mut empty = Document::parse("");
+    empty.push("root:x:0:".to_owned());
+    assert_eq!(empty.to_string(), "root:x:0:\n");
+}
@@ -1,17 +1,116 @@
-use std::{io::Seek, io::SeekFrom, path::PathBuf};
+use std::{
+    io::Seek,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use super::document::Document;
+use super::login_defs::LoginDefs;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
 use std::io::Read;
 use std::io::Write;
 use std::ops::Deref;
+use std::time::{Duration, Instant};
+
+/// How to wait for a lock that is held by another process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockPolicy {
+    /// Give up after waiting this long. A timeout of zero never waits.
+    pub timeout: Duration,
+    /// The time to wait before the first retry.
+    pub retry_interval: Duration,
+    /// The time between retries doubles after every attempt up to this limit.
+    pub max_retry_interval: Duration,
+}
+
+impl LockPolicy {
+    /// Fail immediately if a file is locked by another process.
+    #[must_use]
+    pub const fn non_blocking() -> Self {
+        Self {
+            timeout: Duration::from_secs(0),
+            retry_interval: Duration::from_secs(0),
+            max_retry_interval: Duration::from_secs(0),
+        }
+    }
+}
+
+/// Keeps track of the time spent waiting for a lock.
+struct LockWaiter {
+    policy: LockPolicy,
+    started: Instant,
+    retry_interval: Duration,
+}
+
+impl LockWaiter {
+    fn new(policy: LockPolicy) -> Self {
+        Self {
+            policy,
+            started: Instant::now(),
+            retry_interval: policy.retry_interval,
+        }
+    }
+
+    /// Wait before the next attempt to get a lock held by the process `owner`.
+    ///
+    /// Fails if the timeout of the policy would be exceeded.
+    fn wait(&mut self, owner: u32) -> Result<(), crate::UserLibError> {
+        if self.policy.timeout.as_nanos() == 0 {
+            return Err(crate::UserLibError::LockHeldBy(owner));
+        } else if self.started.elapsed() + self.retry_interval > self.policy.timeout {
+            error!(
+                "Timeout while waiting for the lock of the process {}",
+                owner
+            );
+            return Err(crate::UserLibError::LockTimeout);
+        }
+        debug!(
+            "waiting {:?} for the lock to be released",
+            self.retry_interval
+        );
+        std::thread::sleep(self.retry_interval);
+        self.retry_interval = (self.retry_interval * 2).min(self.policy.max_retry_interval);
+        Ok(())
+    }
+}
+
+impl Default for LockPolicy {
+    /// Wait up to 15 seconds like `lckpwdf` does.
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(15),
+            retry_interval: Duration::from_millis(50),
+            max_retry_interval: Duration::from_secs(1),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Files {
     pub passwd: Option<PathBuf>,
     pub shadow: Option<PathBuf>,
     pub group: Option<PathBuf>,
+    /// The group shadow file. If it is missing only the group file is maintained.
+    pub gshadow: Option<PathBuf>,
+    /// The subordinate UIDs. If the file is missing no subordinate UIDs are managed.
+    pub subuid: Option<PathBuf>,
+    /// The subordinate GIDs. If the file is missing no subordinate GIDs are managed.
+    pub subgid: Option<PathBuf>,
+    /// The configuration of the shadow suite. If it is missing the defaults are used.
+    pub login_defs: Option<PathBuf>,
+    /// An alternate root directory like `useradd --root`.
+    ///
+    /// All the paths (the files of the database, `login.defs`, the home, skeleton and mail spool directories) are relative to it.
+    pub root: Option<PathBuf>,
+    /// How to wait for files that are locked by another process.
+    pub lock_policy: LockPolicy,
+    /// Keep a copy of the previous content of every file as `<file>-` (e.g. `/etc/passwd-`).
+    pub backup: bool,
 }
 
 impl Default for Files {
@@ -21,6 +120,13 @@ impl Default for Files {
             passwd: Some(PathBuf::from("/etc/passwd")),
             shadow: Some(PathBuf::from("/etc/shadow")),
             group: Some(PathBuf::from("/etc/group")),
+            gshadow: Some(PathBuf::from("/etc/gshadow")),
+            subuid: Some(PathBuf::from("/etc/subuid")),
+            subgid: Some(PathBuf::from("/etc/subgid")),
+            login_defs: Some(PathBuf::from("/etc/login.defs")),
+            root: None,
+            lock_policy: LockPolicy::default(),
+            backup: true,
         }
     }
 }
@@ -31,35 +137,413 @@ impl Files {
     pub const fn is_virtual(&self) -> bool {
         !(self.group.is_some() & self.passwd.is_some() & self.shadow.is_some())
     }
+    /// Use the given `passwd`, `shadow` and `group` files without any of the optional files, without backups and without a root directory.
+    #[must_use]
+    pub fn new(
+        passwd: impl Into<PathBuf>,
+        shadow: impl Into<PathBuf>,
+        group: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            passwd: Some(passwd.into()),
+            shadow: Some(shadow.into()),
+            group: Some(group.into()),
+            gshadow: None,
+            subuid: None,
+            subgid: None,
+            login_defs: None,
+            root: None,
+            lock_policy: LockPolicy::default(),
+            backup: false,
+        }
+    }
+    /// No files at all for a database that only exists in memory (see [`Files::is_virtual`]).
+    #[must_use]
+    pub fn in_memory() -> Self {
+        Self {
+            passwd: None,
+            shadow: None,
+            group: None,
+            gshadow: None,
+            subuid: None,
+            subgid: None,
+            login_defs: None,
+            root: None,
+            lock_policy: LockPolicy::default(),
+            backup: false,
+        }
+    }
+    /// Use the default paths inside of the root directory `root`.
+    #[must_use]
+    pub fn with_root(root: &Path) -> Self {
+        Self {
+            root: Some(root.to_owned()),
+            ..Self::default()
+        }
+    }
+
+    /// Get the location of an absolute path inside of the root directory.
+    ///
+    /// Symbolic links are resolved like after a `chroot` so the location never escapes the root directory.
+    ///
+    /// # Errors
+    /// Fails if a link cannot be read or there are too many links (`ELOOP`).
+    pub fn in_root(&self, path: &Path) -> Result<PathBuf, crate::UserLibError> {
+        self.root.as_ref().map_or_else(
+            || Ok(path.to_owned()),
+            |root| {
+                resolve_in_root(root, path).map_err(|e| {
+                    (
+                        format!(
+                            "Failed to resolve {} inside of {}",
+                            path.display(),
+                            root.display()
+                        ),
+                        e,
+                    )
+                        .into()
+                })
+            },
+        )
+    }
+
+    /// Load the `login.defs` file. If no file is specified or it does not exist the defaults are returned.
+    pub fn load_login_defs(&self) -> Result<LoginDefs, crate::UserLibError> {
+        match self
+            .login_defs
+            .as_ref()
+            .map(|path| self.in_root(path))
+            .transpose()?
+        {
+            Some(ref path) if path.exists() => LoginDefs::load(path),
+            Some(ref path) => {
+                info!(
+                    "{} does not exist, using the default settings",
+                    path.display()
+                );
+                Ok(LoginDefs::default())
+            }
+            None => Ok(LoginDefs::default()),
+        }
+    }
     pub fn lock_and_get_passwd(&self) -> Result<LockedFileGuard, crate::UserLibError> {
         let path = self.passwd.as_ref();
         match path {
-            Some(p) => LockedFileGuard::new(p),
+            Some(p) => self.lock(p),
             None => Err(crate::UserLibError::FilesRequired),
         }
     }
     pub fn lock_and_get_shadow(&self) -> Result<LockedFileGuard, crate::UserLibError> {
         let path = self.shadow.as_ref();
         match path {
-            Some(p) => LockedFileGuard::new(p),
+            Some(p) => self.lock(p),
             None => Err(crate::UserLibError::FilesRequired),
         }
     }
     pub fn lock_and_get_group(&self) -> Result<LockedFileGuard, crate::UserLibError> {
         let path = self.group.as_ref();
         match path {
-            Some(p) => LockedFileGuard::new(p),
+            Some(p) => self.lock(p),
             None => Err(crate::UserLibError::FilesRequired),
         }
     }
 
-    pub fn lock_all_get(
+    /// Lock the gshadow file. As it is optional `None` is returned if it is not specified or does not exist.
+    pub fn lock_and_get_gshadow(&self) -> Result<Option<LockedFileGuard>, crate::UserLibError> {
+        self.lock_optional(self.gshadow.as_ref())
+    }
+    /// Lock the subuid file. As it is optional `None` is returned if it is not specified or does not exist.
+    pub fn lock_and_get_subuid(&self) -> Result<Option<LockedFileGuard>, crate::UserLibError> {
+        self.lock_optional(self.subuid.as_ref())
+    }
+    /// Lock the subgid file. As it is optional `None` is returned if it is not specified or does not exist.
+    pub fn lock_and_get_subgid(&self) -> Result<Option<LockedFileGuard>, crate::UserLibError> {
+        self.lock_optional(self.subgid.as_ref())
+    }
+
+    /// Lock a file with the settings of this instance.
+    fn lock(&self, path: &Path) -> Result<LockedFileGuard, crate::UserLibError> {
+        LockedFileGuard::with_policy(&self.in_root(path)?, self.lock_policy)
+            .map(|guard| guard.with_backup(self.backup))
+    }
+
+    fn lock_optional(
         &self,
-    ) -> Result<(LockedFileGuard, LockedFileGuard, LockedFileGuard), crate::UserLibError> {
-        let pwd = self.lock_and_get_passwd()?;
-        let shd = self.lock_and_get_shadow()?;
-        let grp = self.lock_and_get_group()?;
-        Ok((pwd, shd, grp))
+        path: Option<&PathBuf>,
+    ) -> Result<Option<LockedFileGuard>, crate::UserLibError> {
+        match path {
+            Some(p) if self.in_root(p)?.exists() => Ok(Some(self.lock(p)?)),
+            Some(_) | None => Ok(None),
+        }
+    }
+
+    /// Take the lock of the `lckpwdf` function (`/etc/.pwd.lock`) that is used by `passwd`, `chage` and the PAM modules.
+    ///
+    /// The lock file is expected in the directory of the passwd file.
+    pub fn lock_pwd(&self) -> Result<PwdLock, crate::UserLibError> {
+        self.passwd.as_ref().and_then(|p| p.parent()).map_or_else(
+            || Err(crate::UserLibError::FilesRequired),
+            |dir| PwdLock::new(&self.in_root(&dir.join(PWD_LOCK_FILE))?, self.lock_policy),
+        )
+    }
+
+    /// Lock all the files. The `lckpwdf` lock is taken first to always lock in the same order as the shadow utilities.
+    pub fn lock_all_get(&self) -> Result<LockedFiles, crate::UserLibError> {
+        let pwd_lock = self.lock_pwd()?;
+        let passwd = self.lock_and_get_passwd()?;
+        let shadow = self.lock_and_get_shadow()?;
+        let group = self.lock_and_get_group()?;
+        let gshadow = self.lock_and_get_gshadow()?;
+        Ok(LockedFiles {
+            passwd,
+            shadow,
+            group,
+            gshadow,
+            subuid: self.lock_and_get_subuid()?,
+            subgid: self.lock_and_get_subgid()?,
+            pwd_lock,
+        })
+    }
+}
+
+/// Read a file without taking any lock. Files that are not specified or do not exist are read as `None`.
+pub fn read_unlocked(path: Option<&Path>) -> std::io::Result<Option<String>> {
+    match path.map(std::fs::read_to_string) {
+        Some(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        other => other.transpose(),
+    }
+}
+
+/// The maximum number of symbolic links that are followed like `MAXSYMLINKS` of Linux.
+const MAX_SYMLINKS: usize = 40;
+
+/// Resolve the absolute `path` inside of `root` like it would be resolved after `chroot(root)`.
+///
+/// Absolute targets of symbolic links start at `root` and `..` never leaves it.
+/// Components that do not exist (yet) are taken as they are.
+///
+/// # Errors
+/// Fails with `ELOOP` if more than 40 links have to be followed like the kernel does, as taking the last link literally would escape the root.
+pub fn resolve_in_root(root: &Path, path: &Path) -> std::io::Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::path::Component;
+
+    // the components that are still to be resolved in reverse order.
+    let push = |pending: &mut Vec<OsString>, path: &Path| {
+        for component in path.components().rev() {
+            match component {
+                Component::Normal(name) => pending.push(name.to_owned()),
+                Component::ParentDir => pending.push(OsString::from("..")),
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+            }
+        }
+    };
+    let mut pending = Vec::new();
+    push(&mut pending, path);
+    let mut resolved = PathBuf::new();
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&name);
+        match std::fs::read_link(root.join(&candidate)) {
+            Ok(_) if links == MAX_SYMLINKS => {
+                return Err(std::io::Error::from_raw_os_error(nix::libc::ELOOP));
+            }
+            Ok(target) => {
+                links += 1;
+                trace!(
+                    "Following the link {} to {}",
+                    candidate.display(),
+                    target.display()
+                );
+                if target.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                push(&mut pending, &target);
+            }
+            _ => resolved = candidate,
+        }
+    }
+    Ok(root.join(resolved))
+}
+
+/// All the files of the user database locked at once.
+#[derive(Debug)]
+pub struct LockedFiles {
+    pub passwd: LockedFileGuard,
+    pub shadow: LockedFileGuard,
+    pub group: LockedFileGuard,
+    pub gshadow: Option<LockedFileGuard>,
+    pub subuid: Option<LockedFileGuard>,
+    pub subgid: Option<LockedFileGuard>,
+    /// The `lckpwdf` lock is released last as the fields are dropped in order.
+    pub pwd_lock: PwdLock,
+}
+
+impl LockedFiles {
+    /// All the locked files together with their names. Optional files that do not exist are skipped.
+    pub fn guards_mut(&mut self) -> Vec<(&'static str, &mut LockedFileGuard)> {
+        let mut guards = vec![
+            ("passwd", &mut self.passwd),
+            ("shadow", &mut self.shadow),
+            ("group", &mut self.group),
+        ];
+        for (name, guard) in [
+            ("gshadow", self.gshadow.as_mut()),
+            ("subuid", self.subuid.as_mut()),
+            ("subgid", self.subgid.as_mut()),
+        ] {
+            guards.extend(guard.map(|g| (name, g)));
+        }
+        guards
+    }
+}
+
+/// The files of the database while they are changed record by record.
+///
+/// Implemented by the locked files and by the contents of a database that only exists in memory.
+pub trait EditFiles {
+    /// Check if the file with the given name (like `passwd` or `subuid`) is part of the database.
+    fn contains(&self, name: &str) -> bool;
+
+    /// Edit the file with the given name. Files that are not part of the database are skipped.
+    fn edit_file(
+        &mut self,
+        name: &str,
+        edit: &mut dyn FnMut(&mut Document),
+    ) -> Result<(), crate::UserLibError>;
+
+    /// Append a record at the end of the file with the given name.
+    fn append_to(&mut self, name: &str, record: String) -> Result<(), crate::UserLibError> {
+        let mut record = Some(record);
+        self.edit_file(name, &mut |document| {
+            if let Some(record) = record.take() {
+                document.push(record);
+            }
+        })
+    }
+}
+
+impl EditFiles for LockedFiles {
+    fn contains(&self, name: &str) -> bool {
+        match name {
+            "passwd" | "shadow" | "group" => true,
+            "gshadow" => self.gshadow.is_some(),
+            "subuid" => self.subuid.is_some(),
+            "subgid" => self.subgid.is_some(),
+            _ => false,
+        }
+    }
+
+    fn edit_file(
+        &mut self,
+        name: &str,
+        edit: &mut dyn FnMut(&mut Document),
+    ) -> Result<(), crate::UserLibError> {
+        match self.guards_mut().into_iter().find(|(n, _)| *n == name) {
+            Some((_, guard)) => guard.edit(edit),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The contents of the files by name.
+impl<S: std::hash::BuildHasher> EditFiles for HashMap<&'static str, String, S> {
+    fn contains(&self, name: &str) -> bool {
+        self.contains_key(name)
+    }
+
+    fn edit_file(
+        &mut self,
+        name: &str,
+        edit: &mut dyn FnMut(&mut Document),
+    ) -> Result<(), crate::UserLibError> {
+        if let Some(content) = self.get_mut(name) {
+            let mut document = Document::parse(content);
+            edit(&mut document);
+            *content = document.to_string();
+        }
+        Ok(())
+    }
+}
+
+/// The name of the lock file used by `lckpwdf`.
+const PWD_LOCK_FILE: &str = ".pwd.lock";
+
+/// A `fcntl` write lock on the whole lock file like the one taken by `lckpwdf`.
+///
+/// The lock is released when the file is closed on drop.
+#[derive(Debug)]
+pub struct PwdLock {
+    path: PathBuf,
+    /// Closing the file releases the lock.
+    _file: File,
+}
+
+impl PwdLock {
+    /// Lock the file at `path` waiting for other processes according to `policy`.
+    pub fn new(path: &Path, policy: LockPolicy) -> Result<Self, crate::UserLibError> {
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::AsRawFd;
+
+        info!("taking the lckpwdf lock {}", path.to_string_lossy());
+        let file = match OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(e) => return Err(("Failed to open the lckpwdf lock file".to_owned(), e).into()),
+        };
+        let mut waiter = LockWaiter::new(policy);
+        loop {
+            let mut lock = whole_file_lock();
+            match nix::fcntl::fcntl(file.as_raw_fd(), nix::fcntl::FcntlArg::F_SETLK(&lock)) {
+                Ok(_) => {
+                    debug!("successfully took the lckpwdf lock");
+                    return Ok(Self {
+                        path: path.to_owned(),
+                        _file: file,
+                    });
+                }
+                Err(nix::errno::Errno::EACCES | nix::errno::Errno::EAGAIN) => {
+                    // find out who holds the lock for the error message
+                    nix::fcntl::fcntl(file.as_raw_fd(), nix::fcntl::FcntlArg::F_GETLK(&mut lock))
+                        .map_err(|e| format!("Failed to query the lckpwdf lock: {e}"))?;
+                    if i32::from(lock.l_type) == nix::libc::F_UNLCK {
+                        debug!("the lckpwdf lock has been released in the meantime");
+                        continue;
+                    }
+                    let owner = u32::try_from(lock.l_pid).unwrap_or_default();
+                    warn!("The lckpwdf lock is held by the process {}", owner);
+                    waiter.wait(owner)?;
+                }
+                Err(e) => return Err(format!("Failed to take the lckpwdf lock: {e}").into()),
+            }
+        }
+    }
+}
+
+impl Drop for PwdLock {
+    fn drop(&mut self) {
+        info!("releasing the lckpwdf lock {}", self.path.to_string_lossy());
+    }
+}
+
+/// An exclusive lock of the whole file.
+const fn whole_file_lock() -> nix::libc::flock {
+    #[allow(clippy::cast_possible_truncation)]
+    nix::libc::flock {
+        l_type: nix::libc::F_WRLCK as i16,
+        l_whence: nix::libc::SEEK_SET as i16,
+        l_start: 0,
+        l_len: 0,
+        l_pid: 0,
     }
 }
 
@@ -68,6 +552,11 @@ pub struct LockedFileGuard {
     lockfile: PathBuf,
     path: PathBuf,
     pub(crate) file: File,
+    backup: bool,
+    /// The backup is written before the first write only so that it contains the content from before the lock was taken.
+    backed_up: bool,
+    /// While staging all the writes only change this content instead of the file.
+    staged: Option<String>,
 }
 
 #[derive(Debug)]
@@ -77,8 +566,14 @@ struct TempLockFile {
 
 impl Drop for TempLockFile {
     fn drop(&mut self) {
-        info!("removing temporary lockfile {}", self.tlf.to_str().unwrap());
-        std::fs::remove_file(&self.tlf).unwrap();
+        info!("removing temporary lockfile {}", self.tlf.to_string_lossy());
+        if let Err(e) = std::fs::remove_file(&self.tlf) {
+            error!(
+                "Failed to remove the temporary lockfile {}: {}",
+                self.tlf.to_string_lossy(),
+                e
+            );
+        }
     }
 }
 impl Deref for TempLockFile {
@@ -88,50 +583,130 @@ impl Deref for TempLockFile {
     }
 }
 impl LockedFileGuard {
+    /// Lock the file waiting for other processes according to the default [`LockPolicy`].
     pub fn new(path: &PathBuf) -> Result<Self, crate::UserLibError> {
-        let locked = Self::try_to_lock_file(path);
+        Self::with_policy(path, LockPolicy::default())
+    }
+
+    /// Lock the file waiting for other processes according to `policy`.
+    pub fn with_policy(path: &PathBuf, policy: LockPolicy) -> Result<Self, crate::UserLibError> {
+        let locked = Self::try_to_lock_file(path, policy);
         match locked {
             Ok((lockfile, file)) => Ok(Self {
                 lockfile,
                 path: path.to_owned(),
                 file,
+                backup: false,
+                backed_up: false,
+                staged: None,
             }),
             Err(e) => Err(e),
         }
     }
 
+    /// Save the previous content to `<file>-` before the first write like the shadow utilities do.
+    #[must_use]
+    pub const fn with_backup(mut self, backup: bool) -> Self {
+        self.backup = backup;
+        self
+    }
+
+    /// Keep all the following writes in memory starting from `base` instead of writing them to the file.
+    ///
+    /// Reading returns the staged content as well until it is taken with [`LockedFileGuard::take_staged`].
+    pub(crate) fn stage(&mut self, base: String) {
+        self.staged = Some(base);
+    }
+
+    /// Stop staging and return the staged content.
+    pub(crate) const fn take_staged(&mut self) -> Option<String> {
+        self.staged.take()
+    }
+
+    /// Replace the content of the file with `content` as it is.
+    pub(crate) fn write_all(&mut self, content: &str) -> Result<(), crate::UserLibError> {
+        self.write_atomically(content.as_bytes())
+    }
+
+    /// Replace the content of the file. A newline is added at the end.
     pub fn replace_contents(&mut self, new_content: String) -> Result<(), crate::UserLibError> {
-        self.file = match File::create(&self.path) {
-            Ok(file) => file,
-            Err(e) => return Err(("Failed to truncate file.".to_owned(), e).into()),
-        };
-        match self.file.write_all(&new_content.into_bytes()) {
-            Ok(_) => (),
-            Err(e) => return Err(("Could not write (all) users. ".to_owned(), e).into()),
-        };
-        let _ = self.file.write(b"\n");
-        Ok(())
+        let mut content = new_content.into_bytes();
+        content.push(b'\n');
+        self.write_atomically(&content)
     }
 
+    /// Write `content` to the file without ever leaving a partially written file behind.
+    ///
+    /// The content is written to a sibling temporary file (`<file>+`) that gets the mode, the owner and the extended attributes of the original.
+    /// The temporary file is synced to disk and renamed over the original. Finally the directory is synced as well.
+    ///
+    /// If backups are enabled the content as it was when the file was locked is saved to `<file>-` before the first write.
+    fn write_atomically(&mut self, content: &[u8]) -> Result<(), crate::UserLibError> {
+        if let Some(ref mut staged) = self.staged {
+            *staged = String::from_utf8_lossy(content).into_owned();
+            return Ok(());
+        }
+        if self.backup && !self.backed_up {
+            let previous = self.read_contents()?;
+            let backup = with_suffix(&self.path, "-");
+            debug!("saving a backup to {}", backup.to_string_lossy());
+            if let Err(e) = write_replacement(&self.path, &backup, previous.as_bytes()) {
+                error!(
+                    "Failed to write the backup {}: {}",
+                    backup.to_string_lossy(),
+                    e
+                );
+                return Err(("Failed to write the backup.".to_owned(), e).into());
+            }
+            self.backed_up = true;
+        }
+        debug!("writing {} atomically", self.path.to_string_lossy());
+        match write_replacement(&self.path, &self.path, content) {
+            Ok(file) => {
+                self.file = file;
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to write {}: {}", self.path.to_string_lossy(), e);
+                Err(("Failed to write the file.".to_owned(), e).into())
+            }
+        }
+    }
+
+    /// Read the whole content of the locked file starting from the beginning. While staging the staged content is returned.
+    pub fn read_contents(&mut self) -> Result<String, crate::UserLibError> {
+        if let Some(ref staged) = self.staged {
+            return Ok(staged.clone());
+        }
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut content = String::new();
+        match self.file.read_to_string(&mut content) {
+            Ok(_) => Ok(content),
+            Err(e) => Err(("Failed to read the file.".to_owned(), e).into()),
+        }
+    }
+
+    /// Append a line to the file. The file is replaced as a whole just like in [`LockedFileGuard::replace_contents`].
     pub fn append(&mut self, appendee: String) -> Result<(), crate::UserLibError> {
-        // Seek to the last character.
-        self.file.seek(SeekFrom::End(-1)).map_or_else(
-            |e| Err(format!("Failed to append to file {}", e)),
-            |_| Ok(()),
-        )?;
-        // Read the last character
-        let mut b = [0_u8; 1];
-        self.file.read_exact(&mut b)?;
-        // Verify it is '\n' else append '\n' so in any case the file ends with with a newline now
-        if &b != b"\n" {
-            //self.file.write_all(&b)?;
-            self.file.write_all(b"\n")?;
-        }
-        // write the new line.
-        self.file.write_all(&appendee.into_bytes()).map_or_else(
-            |e| Err(("Failed to append to file".to_owned(), e).into()),
-            Ok,
-        )
+        self.edit(|document| document.push(appendee))
+    }
+
+    /// Edit the records of the file. All the other lines are written back exactly as they were read.
+    ///
+    /// Nothing is written if the content did not change.
+    pub fn edit<F>(&mut self, edit: F) -> Result<(), crate::UserLibError>
+    where
+        F: FnOnce(&mut Document),
+    {
+        let content = self.read_contents()?;
+        let mut document = Document::parse(&content);
+        edit(&mut document);
+        let new_content = document.to_string();
+        if new_content == content {
+            Ok(())
+        } else {
+            self.write_atomically(new_content.as_bytes())
+        }
     }
 
     /// This function tries to lock a file in the way other passwd locking mechanisms work.
@@ -148,128 +723,304 @@ impl LockedFileGuard {
     ///
     /// * Open the lockfile
     /// * read the contents of the lockfile
-    /// * check if the lockfile contains a pid if not error out
-    /// * check if the containing pid is in a valid format. If not create a matching error
+    /// * check if the lockfile contains a pid in a valid format. If not the lock is stale
+    /// * test if this process is still running. If so disclose the pid in the error.
+    /// * delete the lockfile as it is apparently not used by the process anymore. (cleanup)
+    /// * try to lock again now that the old lockfile has been safely removed.
+    ///
+    /// If the lock is valid the locking is retried until the timeout of the `policy` has passed.
     ///
     /// not implemented yet:
     ///
-    /// * test if this process could be killed. If so disclose the pid in the error.
-    /// * try to delete the lockfile as it is apparently not used by the process anmore. (cleanup)
-    /// * try to lock again now that the old logfile has been safely removed.
     /// * remove the original file and only keep the lock hardlink
-    fn try_to_lock_file(path: &PathBuf) -> Result<(PathBuf, File), crate::UserLibError> {
+    fn try_to_lock_file(
+        path: &PathBuf,
+        policy: LockPolicy,
+    ) -> Result<(PathBuf, File), crate::UserLibError> {
         info!("locking file {}", path.to_string_lossy());
         let mut tempfilepath_const = path.clone();
         // get the pid
         let pid = std::process::id();
         debug!("using pid {}", std::process::id());
         // get the filename
-        let filename = tempfilepath_const.file_name().unwrap().to_owned();
+        let filename = match tempfilepath_const.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return Err(format!("Cannot lock {}", path.to_string_lossy()).into()),
+        };
         // and the base path which is the base for tempfile and lockfile.
         tempfilepath_const.pop();
         let mut lockfilepath = tempfilepath_const.clone();
         // push the filenames to the paths
-        tempfilepath_const.push(format!("{}.{}", filename.to_str().unwrap(), pid));
-        let tempfilepath = TempLockFile {
-            tlf: tempfilepath_const,
-        };
-        lockfilepath.push(format!("{}.lock", filename.to_str().unwrap()));
+        tempfilepath_const.push(format!("{filename}.{pid}"));
+        lockfilepath.push(format!("{filename}.lock"));
         debug!(
             "Lockfile paths: {:?} (temporary) {:?} (final)",
-            *tempfilepath, lockfilepath
+            tempfilepath_const, lockfilepath
         );
         // write the pid into the tempfile
-        {
-            let mut tempfile = File::create(&*tempfilepath).unwrap_or_else(|e| {
-                panic!("Failed to open {} error: {}", filename.to_str().unwrap(), e)
-            });
-            write!(tempfile, "{}", pid).or_else(|e| {
-                let error_msg = format!(
-                    "could not write to {} error {}",
-                    filename.to_string_lossy(),
-                    e
-                );
-                error!("{}", error_msg);
-                let err: crate::UserLibError = error_msg.into();
-                Err(err)
-            })?;
-        }
+        let mut tempfile = match File::create(&tempfilepath_const) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Failed to create {}: {}", tempfilepath_const.display(), e);
+                return Err(("Failed to create the temporary lockfile".to_owned(), e).into());
+            }
+        };
+        let tempfilepath = TempLockFile {
+            tlf: tempfilepath_const,
+        };
+        write!(tempfile, "{}", pid).or_else(|e| {
+            let error_msg = format!("could not write to {filename} error {e}");
+            error!("{}", error_msg);
+            let err: crate::UserLibError = error_msg.into();
+            Err(err)
+        })?;
+        drop(tempfile);
 
+        let mut waiter = LockWaiter::new(policy);
         // try to make a hardlink from the lockfile to the tempfile
-        let linkresult = std::fs::hard_link(&*tempfilepath, &lockfilepath);
-        match linkresult {
-            Ok(()) => {
-                debug!("successfully locked");
-
-                // open the file
-                let resfile = OpenOptions::new().read(true).write(true).open(&path);
-                return match resfile {
-                    Ok(file) => Ok((lockfilepath, file)),
-                    Err(e) => {
-                        // failed to open the file undo the locks
-                        let _ = std::fs::remove_file(&lockfilepath);
-                        let ret: crate::UserLibError = format!(
-                            "Failed to open the file: {}, error: {}",
-                            path.to_str().unwrap(),
-                            e
-                        )
-                        .into();
-                        Err(ret)
-                    }
-                };
-            }
-            Err(e) => match e.kind() {
-                // analyze the error further
-                std::io::ErrorKind::AlreadyExists => {
-                    warn!("The file is already locked by another process! – testing the validity of the lock");
-                    {
-                        let mut lf = match File::open(&lockfilepath) {
-                            Ok(file) => file,
-                            Err(e) => {
-                                panic!("failed to open the lockfile: {}", e);
-                            }
-                        };
-                        let mut content = String::new();
-                        lf.read_to_string(&mut content)
-                            .unwrap_or_else(|e| panic!("failed to read the lockfile{}", e));
-
-                        let content = content.trim().trim_matches(char::from(0));
-                        let lock_pid = content.parse::<u32>();
-                        match lock_pid {
-                            Ok(pid) => {
-                                warn!(
-                                    "found a pid: {}, checking if this process is still running",
-                                    pid
-                                );
-                                error!("The file could not be locked");
-                                todo!("Validate the lock and delete the file if the process does not exist anymore");
-                                /*let sent = nix::sys::signal::kill(
-                                    nix::unistd::Pid::from_raw(pid as i32),
-                                    nix::sys::signal::Signal::from(0),
-                                );*/
-                            }
-                            Err(e) => error!(
-                                "existing lock file {} with an invalid PID '{}' Error: {}",
-                                lockfilepath.to_str().unwrap(),
-                                content,
+        loop {
+            match std::fs::hard_link(&*tempfilepath, &lockfilepath) {
+                Ok(()) => {
+                    debug!("successfully locked");
+
+                    // open the file
+                    let resfile = OpenOptions::new().read(true).write(true).open(&path);
+                    return match resfile {
+                        Ok(file) => Ok((lockfilepath, file)),
+                        Err(e) => {
+                            // failed to open the file undo the locks
+                            let _ = std::fs::remove_file(&lockfilepath);
+                            let ret: crate::UserLibError = format!(
+                                "Failed to open the file: {}, error: {}",
+                                path.to_string_lossy(),
                                 e
-                            ),
+                            )
+                            .into();
+                            Err(ret)
                         }
+                    };
+                }
+                // analyze the error further
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    warn!("The file is already locked by another process! – testing the validity of the lock");
+                    if let Some(owner) = Self::remove_stale_lock(&lockfilepath)? {
+                        waiter.wait(owner)?;
                     }
+                    info!("retrying to lock {}", path.to_string_lossy());
+                }
+                Err(e) => {
+                    error!("failed to lock the file: {}", e);
+                    return Err(("Failed to create the lockfile".to_owned(), e).into());
                 }
+            }
+        }
+    }
 
-                _ => {
-                    panic!("failed to lock the file: {}", e);
+    /// Check the lockfile of another process and remove it if that process does not exist anymore.
+    ///
+    /// A lockfile that does not contain a valid pid is considered stale as well.
+    /// If the lock is still valid the pid of the owner is returned.
+    ///
+    /// Another process might remove the stale lock and take a new one in the meantime,
+    /// so the lockfile is only removed if it is still the file that has been checked.
+    fn remove_stale_lock(lockfilepath: &Path) -> Result<Option<u32>, crate::UserLibError> {
+        let (content, stale) = match read_with_metadata(lockfilepath) {
+            Ok(lock) => lock,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("the lockfile has been removed in the meantime");
+                return Ok(None);
+            }
+            Err(e) => return Err(("Failed to read the lockfile".to_owned(), e).into()),
+        };
+        let pid = content.trim().trim_matches(char::from(0));
+        match pid.parse::<u32>() {
+            Ok(pid) if pid > 0 => {
+                warn!(
+                    "found a pid: {}, checking if this process is still running",
+                    pid
+                );
+                if process_exists(pid) {
+                    warn!(
+                        "The file {} is locked by the process {}",
+                        lockfilepath.to_string_lossy(),
+                        pid
+                    );
+                    return Ok(Some(pid));
                 }
-            },
+                warn!(
+                    "the process {} does not exist anymore, the lock is stale",
+                    pid
+                );
+            }
+            Ok(_) | Err(_) => warn!(
+                "existing lock file {} with an invalid PID '{}', the lock is stale",
+                lockfilepath.to_string_lossy(),
+                pid
+            ),
+        }
+        info!(
+            "removing the stale lockfile {}",
+            lockfilepath.to_string_lossy()
+        );
+        remove_if_unchanged(lockfilepath, &content, &stale)
+            .map(|_| None)
+            .map_err(|e| ("Failed to remove the stale lockfile".to_owned(), e).into())
+    }
+}
+
+/// Read the file at `path` together with its metadata.
+fn read_with_metadata(path: &Path) -> std::io::Result<(String, std::fs::Metadata)> {
+    let mut file = File::open(path)?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    Ok((content, file.metadata()?))
+}
+
+/// Remove the file at `path` if it still is the file with the `checked` contents and metadata.
+///
+/// The inode of a removed file can be reused right away, so the contents are compared as well. Returns whether the file has been removed.
+fn remove_if_unchanged(
+    path: &Path,
+    content: &str,
+    checked: &std::fs::Metadata,
+) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let file_id = |metadata: &std::fs::Metadata| (metadata.dev(), metadata.ino());
+
+    match read_with_metadata(path) {
+        Ok((current_content, current))
+            if current_content == content && file_id(&current) == file_id(checked) =>
+        {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+                result => result.map(|()| true),
+            }
+        }
+        Ok(_) => {
+            info!("{} has been replaced in the meantime", path.display());
+            Ok(false)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Append `suffix` to the filename of `path`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.to_owned().into_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Write `content` to the temporary file `<target>+` and rename it to `target`. The written file is returned.
+///
+/// The mode, the owner and the extended attributes are copied from `original`.
+/// If anything fails the temporary file is removed and `target` is not touched.
+fn write_replacement(original: &Path, target: &Path, content: &[u8]) -> std::io::Result<File> {
+    let temppath = with_suffix(target, "+");
+    let result = write_temporary(original, &temppath, content);
+    match result {
+        Ok(file) => {
+            std::fs::rename(&temppath, target)?;
+            let directory = match target.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                Some(_) | None => Path::new("."),
+            };
+            File::open(directory)?.sync_all()?;
+            Ok(file)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&temppath);
+            Err(e)
+        }
+    }
+}
+
+/// Write `content` to `temppath` with the metadata of `original` and sync it to disk.
+fn write_temporary(original: &Path, temppath: &Path, content: &[u8]) -> std::io::Result<File> {
+    use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+    let metadata = std::fs::metadata(original)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(temppath)?;
+    // change the owner first as that might reset the mode.
+    std::os::unix::fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid()))?;
+    file.set_permissions(metadata.permissions())?;
+    copy_xattrs(original, &file)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+/// Copy the extended attributes (like the `security.selinux` label) of `original` to `file`.
+fn copy_xattrs(original: &Path, file: &File) -> std::io::Result<()> {
+    use xattr::FileExt;
+
+    let attributes = match xattr::list(original) {
+        Ok(attributes) => attributes,
+        Err(e) if e.raw_os_error() == Some(nix::libc::ENOTSUP) => {
+            debug!("the filesystem does not support extended attributes");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    for attribute in attributes {
+        if let Some(value) = xattr::get(original, &attribute)? {
+            file.set_xattr(&attribute, &value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Test if a process with the given pid is running by sending it the signal 0.
+///
+/// If the signal is not permitted the process exists but belongs to someone else.
+fn process_exists(pid: u32) -> bool {
+    let pid = match i32::try_from(pid) {
+        Ok(pid) => nix::unistd::Pid::from_raw(pid),
+        Err(_) => return false,
+    };
+    match nix::sys::signal::kill(pid, None) {
+        Ok(()) | Err(nix::errno::Errno::EPERM) => true,
+        Err(e) => {
+            debug!("signaling the process {} failed: {}", pid, e);
+            false
         }
-        Err("was not able to lock!".into())
     }
 }
 
 impl Drop for LockedFileGuard {
     fn drop(&mut self) {
         info!("removing lock");
-        std::fs::remove_file(&self.lockfile).unwrap();
+        if let Err(e) = std::fs::remove_file(&self.lockfile) {
+            error!(
+                "Failed to remove the lockfile {}: {}",
+                self.lockfile.to_string_lossy(),
+                e
+            );
+        }
     }
 }
+
+#[test]
+fn test_replaced_lock_is_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let lockfile = dir.path().join("passwd.lock");
+    std::fs::write(&lockfile, "1").unwrap();
+    let stale = std::fs::metadata(&lockfile).unwrap();
+    // another process removed the stale lock and took a new one.
+    std::fs::remove_file(&lockfile).unwrap();
+    std::fs::write(dir.path().join("passwd.2"), "2").unwrap();
+    std::fs::hard_link(dir.path().join("passwd.2"), &lockfile).unwrap();
+    assert!(!remove_if_unchanged(&lockfile, "1", &stale).unwrap());
+    assert_eq!(std::fs::read_to_string(&lockfile).unwrap(), "2");
+
+    let current = std::fs::metadata(&lockfile).unwrap();
+    assert!(remove_if_unchanged(&lockfile, "2", &current).unwrap());
+    assert!(!lockfile.exists());
+}
@@ -1,40 +1,135 @@
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::MetadataExt;
+
+use super::files::{LockedFileGuard, LockedFiles};
+
+/// The metadata that changes whenever a file is replaced or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    inode: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+/// A fingerprint of a database file consisting of the SHA-256 digest of its content and (if it was read from a file) its inode, size and modification time.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SourceHash {
-    hashvalue: String,
+    digest: [u8; 32],
+    stamp: Option<FileStamp>,
 }
 
 impl SourceHash {
+    /// The fingerprint of content that has not been read from a file.
     #[must_use]
     pub fn new(src: &str) -> Self {
         Self {
-            hashvalue: src.to_owned(),
+            digest: Sha256::digest(src.as_bytes()).into(),
+            stamp: None,
         }
     }
+
+    /// The fingerprint of the current content and metadata of a locked file.
+    pub fn from_file(locked: &mut LockedFileGuard) -> Result<Self, crate::UserLibError> {
+        let content = locked.read_contents()?;
+        let metadata = locked.file.metadata()?;
+        Ok(Self {
+            stamp: Some(FileStamp {
+                inode: metadata.ino(),
+                size: metadata.size(),
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+            }),
+            ..Self::new(&content)
+        })
+    }
+
     #[must_use]
-    pub fn has_changed(&self, new: &str) -> bool {
-        trace!(
-            "Old and new lengths: {}, {}",
-            self.hashvalue.len(),
-            new.len()
-        );
-        !self.hashvalue.eq(new)
+    pub fn has_changed(&self, new: &Self) -> bool {
+        trace!("Old and new stamps: {:?}, {:?}", self.stamp, new.stamp);
+        self != new
     }
 }
 
+/// The fingerprints of all the files of the database.
 pub struct Hashes {
     pub passwd: SourceHash,
     pub shadow: SourceHash,
     pub group: SourceHash,
+    pub gshadow: SourceHash,
+    pub subuid: SourceHash,
+    pub subgid: SourceHash,
 }
 
 impl Hashes {
     #[must_use]
-    pub fn new(passwd: &str, shadow: &str, group: &str) -> Self {
+    pub fn new(passwd: &str, shadow: &str, group: &str, gshadow: &str) -> Self {
         Self {
             passwd: SourceHash::new(passwd),
             shadow: SourceHash::new(shadow),
             group: SourceHash::new(group),
+            gshadow: SourceHash::new(gshadow),
+            subuid: SourceHash::new(""),
+            subgid: SourceHash::new(""),
+        }
+    }
+
+    /// The fingerprints of the locked files. Missing optional files are treated as empty.
+    pub fn from_locked(locked: &mut LockedFiles) -> Result<Self, crate::UserLibError> {
+        Ok(Self {
+            passwd: SourceHash::from_file(&mut locked.passwd)?,
+            shadow: SourceHash::from_file(&mut locked.shadow)?,
+            group: SourceHash::from_file(&mut locked.group)?,
+            gshadow: optional_from_file(locked.gshadow.as_mut())?,
+            subuid: optional_from_file(locked.subuid.as_mut())?,
+            subgid: optional_from_file(locked.subgid.as_mut())?,
+        })
+    }
+
+    /// The fingerprint of the file with the given name.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut SourceHash> {
+        match name {
+            "passwd" => Some(&mut self.passwd),
+            "shadow" => Some(&mut self.shadow),
+            "group" => Some(&mut self.group),
+            "gshadow" => Some(&mut self.gshadow),
+            "subuid" => Some(&mut self.subuid),
+            "subgid" => Some(&mut self.subgid),
+            _ => None,
         }
     }
+
+    /// The names of the files that have been modified since the hashes have been taken.
+    pub fn changed_files(
+        &self,
+        locked: &mut LockedFiles,
+    ) -> Result<Vec<&'static str>, crate::UserLibError> {
+        let current = Self::from_locked(locked)?;
+        Ok(vec![
+            ("passwd", self.passwd.has_changed(&current.passwd)),
+            ("shadow", self.shadow.has_changed(&current.shadow)),
+            ("group", self.group.has_changed(&current.group)),
+            ("gshadow", self.gshadow.has_changed(&current.gshadow)),
+            ("subuid", self.subuid.has_changed(&current.subuid)),
+            ("subgid", self.subgid.has_changed(&current.subgid)),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| if changed { Some(name) } else { None })
+        .collect())
+    }
+}
+
+fn optional_from_file(
+    locked: Option<&mut LockedFileGuard>,
+) -> Result<SourceHash, crate::UserLibError> {
+    locked.map_or_else(|| Ok(SourceHash::new("")), SourceHash::from_file)
+}
+
+#[test]
+fn test_source_hash() {
+    let hash = SourceHash::new("root:x:0:0::/root:/bin/bash");
+    assert!(!hash.has_changed(&SourceHash::new("root:x:0:0::/root:/bin/bash")));
+    assert!(hash.has_changed(&SourceHash::new("root:x:0:0::/root:/bin/sh")));
 }
@@ -0,0 +1,561 @@
+//! Creation of home directories.
+use crate::api::{
+    ArchiveCompression, CreateHome, CreateUserArgs, DeleteHome, ForeignOwners, HomeTransfer,
+};
+use crate::UserLibError;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{lchown, symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// The default skeleton directory that is copied to new home directories.
+pub const DEFAULT_SKEL: &str = "/etc/skel";
+
+impl super::UserDBLocal {
+    /// Create the home directory `home_dir` of a new user if `args` asks for [`CreateHome::Create`].
+    ///
+    /// This is done before the records of the user are written. A directory that did not exist before is returned so that it can be
+    /// removed again if writing the records fails. If the creation fails the partially created directory is removed right away.
+    pub(super) fn create_new_home(
+        &self,
+        args: &CreateUserArgs,
+        home_dir: &Path,
+        uid: u32,
+        gid: u32,
+    ) -> Result<Option<PathBuf>, UserLibError> {
+        if self.create_home_for(args) != CreateHome::Create {
+            return Ok(None);
+        }
+        let home = self.source_files.in_root(home_dir)?;
+        let existed = home.exists();
+        let skel = Path::new(args.skel.unwrap_or(DEFAULT_SKEL));
+        match create_home(
+            &home,
+            Some(&self.source_files.in_root(skel)?),
+            uid,
+            gid,
+            self.login_defs.home_mode(),
+        ) {
+            Ok(()) if existed => Ok(None),
+            Ok(()) => Ok(Some(home)),
+            Err(e) => {
+                if !existed {
+                    remove_new_home(&home);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Move or copy the directory of [`CreateHome::HomeFromDir`] to the home directory `home_dir` of a new user.
+    ///
+    /// This can not be undone so it is done after the records of the user have been written. The directory is checked by
+    /// [`check_adoptable`] before that. The files that belong to other users are returned.
+    pub(super) fn adopt_new_home(
+        &self,
+        args: &CreateUserArgs,
+        home_dir: &Path,
+        uid: u32,
+        gid: u32,
+    ) -> Result<Vec<PathBuf>, UserLibError> {
+        match self.create_home_for(args) {
+            CreateHome::HomeFromDir {
+                path,
+                transfer,
+                foreign_owners,
+            } => adopt_home(
+                &self.source_files.in_root(&path)?,
+                &self.source_files.in_root(home_dir)?,
+                transfer,
+                foreign_owners,
+                uid,
+                gid,
+            ),
+            CreateHome::Create | CreateHome::Skip => Ok(Vec::new()),
+        }
+    }
+
+    /// The mail spool of the user `username` in the `MAIL_DIR` of `login.defs`.
+    ///
+    /// # Errors
+    /// Fails if the path cannot be resolved inside of the root directory.
+    pub fn get_mail_spool(&self, username: &str) -> Result<Option<PathBuf>, UserLibError> {
+        self.login_defs
+            .mail_dir()
+            .map(|dir| self.source_files.in_root(&Path::new(dir).join(username)))
+            .transpose()
+    }
+
+    /// Remove the mail spool of a deleted user like `userdel --remove` does.
+    pub(super) fn remove_mail_spool(&self, username: &str) -> Result<(), UserLibError> {
+        match self.get_mail_spool(username)? {
+            Some(spool) if fs::symlink_metadata(&spool).is_ok() => {
+                info!("Removing the mail spool {}", spool.display());
+                fs::remove_file(&spool).map_err(|e| {
+                    (
+                        format!("Failed to remove the mail spool {}", spool.display()),
+                        e,
+                    )
+                        .into()
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Archive the home directory of `user` if `delete_home` is [`DeleteHome::Archive`].
+    ///
+    /// The path of the written archive is returned.
+    pub(super) fn archive_home(
+        &self,
+        user: &crate::User,
+        delete_home: &DeleteHome,
+    ) -> Result<Option<PathBuf>, UserLibError> {
+        use crate::api::UserRead;
+
+        let (path, compression) = match *delete_home {
+            DeleteHome::Archive {
+                ref path,
+                compression,
+            } => (path, compression),
+            DeleteHome::Delete | DeleteHome::Keep => return Ok(None),
+        };
+        let username = user.get_username().expect("a user allways has a name");
+        let home = self.source_files.in_root(Path::new(
+            user.get_home_dir()
+                .ok_or("The user does not have a home directory")?,
+        ))?;
+        let archive = self.source_files.in_root(path)?.join(archive_name(
+            username,
+            user.get_uid(),
+            chrono::Utc::now(),
+            compression,
+        ));
+        archive_home(&home, &archive, username, compression)?;
+        Ok(Some(archive))
+    }
+}
+
+/// Create a home directory with the given permissions and copy the contents of the skeleton directory into it.
+///
+/// Everything that is created is owned by `uid` and `gid`. Like useradd an already existing directory is left untouched.
+pub fn create_home(
+    home: &Path,
+    skel: Option<&Path>,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+) -> Result<(), UserLibError> {
+    if home.exists() {
+        warn!(
+            "The home directory {} already exists. Not copying any file from the skeleton directory into it.",
+            home.display()
+        );
+        return Ok(());
+    }
+    info!("Creating the home directory {}", home.display());
+    let created = home
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::create_dir(home))
+        .and_then(|()| lchown(home, Some(uid), Some(gid)))
+        .and_then(|()| fs::set_permissions(home, fs::Permissions::from_mode(mode)));
+    if let Err(e) = created {
+        return Err((
+            format!("Failed to create the home directory {}", home.display()),
+            e,
+        )
+            .into());
+    }
+    match skel {
+        Some(skel) if skel.is_dir() => {
+            debug!("Copying the skeleton directory {}", skel.display());
+            copy_tree(skel, home, Some((uid, gid))).map_err(|e| {
+                (
+                    format!(
+                        "Failed to copy the skeleton directory {} to {}",
+                        skel.display(),
+                        home.display()
+                    ),
+                    e,
+                )
+                    .into()
+            })
+        }
+        Some(skel) => {
+            warn!("The skeleton directory {} does not exist", skel.display());
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Remove the home directory of a deleted user. Like `userdel --remove` a missing home is only a warning.
+pub(super) fn remove_home(home: &Path) -> Result<(), UserLibError> {
+    info!("Removing the home directory {}", home.display());
+    match fs::remove_dir_all(home) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!("The home directory {} does not exist", home.display());
+            Ok(())
+        }
+        result => result.map_err(|e| {
+            (
+                format!("Failed to remove the home directory {}", home.display()),
+                e,
+            )
+                .into()
+        }),
+    }
+}
+
+/// Remove the home directory of a user that could not be added. Failures are only logged.
+pub(super) fn remove_new_home(home: &Path) {
+    info!("Removing the new home directory {}", home.display());
+    if let Err(e) = fs::remove_dir_all(home) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            error!(
+                "Failed to remove the home directory {}: {e}",
+                home.display()
+            );
+        }
+    }
+}
+
+/// Copy the contents of `source` into the existing directory `target` keeping the permissions and symlinks.
+///
+/// Every copied entry is owned by `owner` or if it is `None` by the owner of the source entry.
+/// Files given to `owner` lose their setuid and setgid bits like they do with `useradd`.
+pub fn copy_tree(source: &Path, target: &Path, owner: Option<(u32, u32)>) -> std::io::Result<()> {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let file_type = metadata.file_type();
+        let destination = target.join(entry.file_name());
+        let (uid, gid) = owner.unwrap_or_else(|| (metadata.uid(), metadata.gid()));
+        trace!(
+            "Copying {} to {}",
+            entry.path().display(),
+            destination.display()
+        );
+        if file_type.is_symlink() {
+            symlink(fs::read_link(entry.path())?, &destination)?;
+            lchown(&destination, Some(uid), Some(gid))?;
+            continue;
+        } else if file_type.is_dir() {
+            fs::create_dir(&destination)?;
+            copy_tree(&entry.path(), &destination, owner)?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), &destination)?;
+        } else {
+            warn!("Skipping the special file {}", entry.path().display());
+            continue;
+        }
+        // changing the owner clears the setuid bits so the permissions are set afterwards. They are only kept for the same owner.
+        lchown(&destination, Some(uid), Some(gid))?;
+        if owner.is_some() {
+            fs::set_permissions(&destination, without_set_id(&metadata))?;
+        } else {
+            fs::set_permissions(&destination, metadata.permissions())?;
+        }
+    }
+    Ok(())
+}
+
+/// Check that `source` can be adopted as the home directory `home`.
+///
+/// # Errors
+/// Fails if the source is not a directory or if the home directory already exists.
+pub fn check_adoptable(source: &Path, home: &Path) -> Result<(), UserLibError> {
+    if !source.is_dir() {
+        return Err(format!("The directory {} does not exist", source.display()).into());
+    }
+    if source != home && fs::symlink_metadata(home).is_ok() {
+        return Err(format!("The home directory {} already exists", home.display()).into());
+    }
+    Ok(())
+}
+
+/// Move or copy the directory `source` to `home` and give it to the new owner.
+///
+/// Every entry that belongs to the owner of `source` is given to `uid` and `gid`.
+/// Entries owned by other users are logged and, depending on `foreign_owners`, given to the new owner as well.
+/// The entries owned by other users are returned.
+///
+/// # Errors
+/// Fails if the directory cannot be transferred or the owners cannot be changed.
+pub fn adopt_home(
+    source: &Path,
+    home: &Path,
+    transfer: HomeTransfer,
+    foreign_owners: ForeignOwners,
+    uid: u32,
+    gid: u32,
+) -> Result<Vec<PathBuf>, UserLibError> {
+    check_adoptable(source, home)?;
+    let previous_uid = fs::metadata(source)
+        .map_err(|e| (format!("Failed to read {}", source.display()), e))?
+        .uid();
+    if source != home {
+        info!(
+            "Transferring ({:?}) {} to {}",
+            transfer,
+            source.display(),
+            home.display()
+        );
+        transfer_tree(source, home, transfer).map_err(|e| {
+            (
+                format!(
+                    "Failed to transfer {} to {}",
+                    source.display(),
+                    home.display()
+                ),
+                e,
+            )
+        })?;
+    }
+    let mut foreign = Vec::new();
+    lchown(home, Some(uid), Some(gid))
+        .and_then(|()| {
+            reown_tree(
+                home,
+                previous_uid,
+                (uid, gid),
+                foreign_owners == ForeignOwners::Fix,
+                &mut foreign,
+            )
+        })
+        .map_err(|e| {
+            (
+                format!("Failed to change the owner of {}", home.display()),
+                e,
+            )
+        })?;
+    for path in &foreign {
+        warn!(
+            "{} belongs to another user{}",
+            path.display(),
+            if foreign_owners == ForeignOwners::Fix {
+                " the owner has been changed"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(foreign)
+}
+
+fn transfer_tree(source: &Path, home: &Path, transfer: HomeTransfer) -> std::io::Result<()> {
+    if let Some(parent) = home.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if transfer == HomeTransfer::Move {
+        match fs::rename(source, home) {
+            Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                debug!("Cannot rename across filesystems, copying instead");
+            }
+            result => return result,
+        }
+    }
+    let metadata = fs::metadata(source)?;
+    fs::create_dir(home)?;
+    fs::set_permissions(home, metadata.permissions())?;
+    copy_tree(source, home, None)?;
+    if transfer == HomeTransfer::Move {
+        fs::remove_dir_all(source)?;
+    }
+    Ok(())
+}
+
+/// Give every entry in `dir` that belongs to `previous_uid` to the new owner.
+///
+/// The entries belonging to other users are collected in `foreign` and only changed if `fix_foreign` is set.
+fn reown_tree(
+    dir: &Path,
+    previous_uid: u32,
+    owner: (u32, u32),
+    fix_foreign: bool,
+    foreign: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    let (uid, gid) = owner;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        let is_foreign = metadata.uid() != previous_uid && metadata.uid() != uid;
+        if is_foreign {
+            foreign.push(path.clone());
+        }
+        if !is_foreign || fix_foreign {
+            trace!("Changing the owner of {}", path.display());
+            lchown(&path, Some(uid), Some(gid))?;
+            if !metadata.file_type().is_symlink() {
+                fs::set_permissions(&path, without_set_id(&metadata))?;
+            }
+        }
+        if metadata.is_dir() {
+            reown_tree(&path, previous_uid, owner, fix_foreign, foreign)?;
+        }
+    }
+    Ok(())
+}
+
+/// The permissions of `metadata` without the setuid and setgid bits of files.
+///
+/// Changing the owner clears these bits and they must not be given to the new owner. The setgid bit of directories only
+/// makes new entries inherit the group and is kept.
+fn without_set_id(metadata: &fs::Metadata) -> fs::Permissions {
+    let mode = metadata.permissions().mode();
+    if metadata.is_dir() {
+        fs::Permissions::from_mode(mode)
+    } else {
+        fs::Permissions::from_mode(mode & !0o6000)
+    }
+}
+
+/// The name of the archive of a deleted home directory containing the username, the UID and the time of the deletion.
+#[must_use]
+pub fn archive_name(
+    username: &str,
+    uid: u32,
+    time: chrono::DateTime<chrono::Utc>,
+    compression: ArchiveCompression,
+) -> String {
+    format!(
+        "{}-{}-{}.tar{}",
+        username,
+        uid,
+        time.format("%Y%m%dT%H%M%SZ"),
+        match compression {
+            ArchiveCompression::None => "",
+            ArchiveCompression::Gzip => ".gz",
+        }
+    )
+}
+
+/// Write a tar archive of the directory `home` to `archive`.
+///
+/// The owners, permissions, symlinks and extended attributes of all entries are preserved.
+/// The entries are stored in a directory called `name`. The archive is only readable by its owner (mode `0600`).
+/// A partially written archive is removed on failure.
+///
+/// # Errors
+/// Fails if the archive cannot be written.
+pub fn archive_home(
+    home: &Path,
+    archive: &Path,
+    name: &str,
+    compression: ArchiveCompression,
+) -> Result<(), UserLibError> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    info!("Archiving {} to {}", home.display(), archive.display());
+    // the archive contains private files so only the owner may read it.
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(archive)
+        .and_then(|file| match compression {
+            ArchiveCompression::None => write_archive(file, home, name),
+            ArchiveCompression::Gzip => write_archive(
+                flate2::write::GzEncoder::new(file, flate2::Compression::default()),
+                home,
+                name,
+            )?
+            .finish(),
+        })
+        .and_then(|file| file.sync_all());
+    if let Err(e) = result {
+        // Only remove the archive if it was created by this function.
+        if e.kind() != std::io::ErrorKind::AlreadyExists {
+            let _ = fs::remove_file(archive);
+        }
+        return Err((format!("Failed to archive {}", home.display()), e).into());
+    }
+    Ok(())
+}
+
+fn write_archive<W: Write>(writer: W, home: &Path, name: &str) -> std::io::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    builder.mode(tar::HeaderMode::Complete);
+    builder.follow_symlinks(false);
+    append_entry(&mut builder, home, Path::new(name))?;
+    let mut writer = builder.into_inner()?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// Append `path` (and if it is a directory its contents) to the archive as `name`.
+fn append_entry<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+) -> std::io::Result<()> {
+    trace!("Archiving {}", path.display());
+    append_xattrs(builder, path, name)?;
+    builder.append_path_with_name(path, name)?;
+    if fs::symlink_metadata(path)?.is_dir() {
+        let mut entries = fs::read_dir(path)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(fs::DirEntry::file_name);
+        for entry in entries {
+            append_entry(builder, &entry.path(), &name.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Store the extended attributes of `path` in a pax header (`SCHILY.xattr.*`) preceding the entry.
+fn append_xattrs<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let attributes = match xattr::list(path) {
+        Ok(attributes) => attributes,
+        Err(e) if e.raw_os_error() == Some(nix::libc::ENOTSUP) => {
+            debug!("the filesystem does not support extended attributes");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let mut records = Vec::new();
+    for attribute in attributes {
+        if let Some(value) = xattr::get(path, &attribute)? {
+            let mut record = b"SCHILY.xattr.".to_vec();
+            record.extend_from_slice(attribute.as_bytes());
+            record.push(b'=');
+            record.extend_from_slice(&value);
+            record.push(b'\n');
+            records.extend(pax_record(&record));
+        }
+    }
+    if records.is_empty() {
+        return Ok(());
+    }
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_size(records.len() as u64);
+    header.set_mode(0o644);
+    let pax_name = name
+        .file_name()
+        .map_or_else(|| name.to_owned(), |n| Path::new("PaxHeaders").join(n));
+    builder.append_data(&mut header, pax_name, records.as_slice())
+}
+
+/// Prefix a pax record with its length, the length including the digits of the length itself.
+fn pax_record(record: &[u8]) -> Vec<u8> {
+    let mut length = record.len();
+    loop {
+        let total = length.to_string().len() + 1 + record.len();
+        if total == length {
+            break;
+        }
+        length = total;
+    }
+    let mut result = format!("{length} ").into_bytes();
+    result.extend_from_slice(record);
+    result
+}
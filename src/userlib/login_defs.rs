@@ -0,0 +1,301 @@
+//! The configuration of the shadow password suite `/etc/login.defs`.
+//!
+//! The file consists of lines with a key and a value separated by whitespace. Lines starting with `#` are comments.
+//! All keys are kept (also the unknown ones) the most relevant ones can be accessed through typed getters returning the defaults of shadow-utils if they are not set.
+use crate::userlib::allocation::{IdRange, IdRanges};
+use crate::userlib::subid::SubIdSettings;
+use crate::UserLibError;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::fmt::{self, Display};
+use std::path::Path;
+
+/// The hashing algorithm used to encrypt passwords (`ENCRYPT_METHOD`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncryptMethod {
+    Des,
+    Md5,
+    Sha256,
+    Sha512,
+    Bcrypt,
+    Yescrypt,
+    /// A method that is not known by this library.
+    Other(String),
+}
+
+impl From<&str> for EncryptMethod {
+    fn from(source: &str) -> Self {
+        match source.to_uppercase().as_str() {
+            "DES" => Self::Des,
+            "MD5" => Self::Md5,
+            "SHA256" => Self::Sha256,
+            "SHA512" => Self::Sha512,
+            "BCRYPT" => Self::Bcrypt,
+            "YESCRYPT" => Self::Yescrypt,
+            _ => Self::Other(source.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LoginDefs {
+    entries: Vec<(String, String)>,
+}
+
+impl LoginDefs {
+    /// Read and parse a `login.defs` file.
+    pub fn load(path: &Path) -> Result<Self, UserLibError> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(e) => Err((format!("Failed to read {}", path.display()), e).into()),
+        }
+    }
+
+    /// Parse the content of a `login.defs` file.
+    #[must_use]
+    pub fn parse(content: &str) -> Self {
+        let mut defs = Self::default();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let key = parts.next().unwrap_or_default();
+            let value = parts.next().unwrap_or_default().trim().trim_matches('"');
+            trace!("login.defs: {} = {}", key, value);
+            defs.set(key, value);
+        }
+        defs
+    }
+
+    /// Get the raw value of a key. If a key is defined multiple times the last definition is used.
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> &mut Self {
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => value.clone_into(&mut entry.1),
+            None => self.entries.push((key.to_owned(), value.to_owned())),
+        }
+        self
+    }
+
+    /// All the keys that are defined in the order of their first appearance.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(k, _)| k.as_str())
+    }
+
+    /// Get a numeric value. Like shadow-utils octal (`022`) and hexadecimal (`0x1F`) values are supported.
+    #[must_use]
+    pub fn get_number(&self, key: &str) -> Option<i64> {
+        let value = self.get(key)?;
+        let (negative, digits) = value
+            .strip_prefix('-')
+            .map_or((false, value), |rest| (true, rest));
+        let parsed = match digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None if digits.len() > 1 && digits.starts_with('0') => {
+                i64::from_str_radix(&digits[1..], 8)
+            }
+            None => digits.parse::<i64>(),
+        };
+        match parsed {
+            Ok(number) => Some(if negative { -number } else { number }),
+            Err(e) => {
+                warn!("login.defs: invalid number {} for {}: {}", value, key, e);
+                None
+            }
+        }
+    }
+
+    fn get_id(&self, key: &str) -> Option<u32> {
+        use std::convert::TryFrom;
+        self.get_number(key).and_then(|n| u32::try_from(n).ok())
+    }
+
+    /// Get a boolean value (`yes` or `no`).
+    #[must_use]
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.get(key).map(|v| v.eq_ignore_ascii_case("yes"))
+    }
+
+    /// The ranges for new UIDs and GIDs (`UID_MIN`, `UID_MAX`, `SYS_UID_MIN`, `SYS_UID_MAX` and the `GID` equivalents).
+    #[must_use]
+    pub fn id_ranges(&self) -> IdRanges {
+        let default = IdRanges::default();
+        let uid_min = self.get_id("UID_MIN").unwrap_or(default.uid.min);
+        let gid_min = self.get_id("GID_MIN").unwrap_or(default.gid.min);
+        IdRanges {
+            uid: IdRange::new(uid_min, self.get_id("UID_MAX").unwrap_or(default.uid.max)),
+            sys_uid: IdRange::new(
+                self.get_id("SYS_UID_MIN").unwrap_or(default.sys_uid.min),
+                self.get_id("SYS_UID_MAX")
+                    .unwrap_or_else(|| uid_min.saturating_sub(1)),
+            ),
+            gid: IdRange::new(gid_min, self.get_id("GID_MAX").unwrap_or(default.gid.max)),
+            sys_gid: IdRange::new(
+                self.get_id("SYS_GID_MIN").unwrap_or(default.sys_gid.min),
+                self.get_id("SYS_GID_MAX")
+                    .unwrap_or_else(|| gid_min.saturating_sub(1)),
+            ),
+        }
+    }
+
+    /// The settings for subordinate UIDs (`SUB_UID_MIN`, `SUB_UID_MAX` and `SUB_UID_COUNT`).
+    #[must_use]
+    pub fn sub_uid_settings(&self) -> SubIdSettings {
+        self.sub_id_settings("UID")
+    }
+
+    /// The settings for subordinate GIDs (`SUB_GID_MIN`, `SUB_GID_MAX` and `SUB_GID_COUNT`).
+    #[must_use]
+    pub fn sub_gid_settings(&self) -> SubIdSettings {
+        self.sub_id_settings("GID")
+    }
+
+    fn sub_id_settings(&self, kind: &str) -> SubIdSettings {
+        let default = SubIdSettings::default();
+        SubIdSettings {
+            range: IdRange::new(
+                self.get_id(&format!("SUB_{kind}_MIN"))
+                    .unwrap_or(default.range.min),
+                self.get_id(&format!("SUB_{kind}_MAX"))
+                    .unwrap_or(default.range.max),
+            ),
+            count: self
+                .get_id(&format!("SUB_{kind}_COUNT"))
+                .unwrap_or(default.count),
+        }
+    }
+
+    /// Create a group with the same name as the user (`USERGROUPS_ENAB`). `None` if it is not set.
+    #[must_use]
+    pub fn usergroups_enab(&self) -> Option<bool> {
+        self.get_bool("USERGROUPS_ENAB")
+    }
+
+    /// Create home directories by default (`CREATE_HOME`). `None` if it is not set.
+    #[must_use]
+    pub fn create_home(&self) -> Option<bool> {
+        self.get_bool("CREATE_HOME")
+    }
+
+    /// The umask used for new home directories (`UMASK`).
+    #[must_use]
+    pub fn umask(&self) -> u32 {
+        self.get_id("UMASK").unwrap_or(0o022)
+    }
+
+    /// The permissions of new home directories (`HOME_MODE`). If it is not set it is derived from the `UMASK`.
+    #[must_use]
+    pub fn home_mode(&self) -> u32 {
+        self.get_id("HOME_MODE")
+            .unwrap_or_else(|| 0o777 & !self.umask())
+    }
+
+    /// The hashing algorithm used to encrypt passwords (`ENCRYPT_METHOD`). `None` if it is not set.
+    #[must_use]
+    pub fn encrypt_method(&self) -> Option<EncryptMethod> {
+        self.get("ENCRYPT_METHOD").map(EncryptMethod::from)
+    }
+
+    /// The maximum number of days a password may be used (`PASS_MAX_DAYS`). `None` disables the aging.
+    #[must_use]
+    pub fn pass_max_days(&self) -> Option<i64> {
+        Some(self.get_number("PASS_MAX_DAYS").unwrap_or(99999)).filter(|d| *d >= 0)
+    }
+
+    /// The minimum number of days between password changes (`PASS_MIN_DAYS`).
+    #[must_use]
+    pub fn pass_min_days(&self) -> Option<i64> {
+        Some(self.get_number("PASS_MIN_DAYS").unwrap_or(0)).filter(|d| *d >= 0)
+    }
+
+    /// The number of days of warning before a password expires (`PASS_WARN_AGE`).
+    #[must_use]
+    pub fn pass_warn_age(&self) -> Option<i64> {
+        Some(self.get_number("PASS_WARN_AGE").unwrap_or(7)).filter(|d| *d >= 0)
+    }
+
+    /// The directory of the mail spools (`MAIL_DIR`).
+    #[must_use]
+    pub fn mail_dir(&self) -> Option<&str> {
+        self.get("MAIL_DIR")
+    }
+
+    /// A command that is run before a user is deleted (`USERDEL_CMD`).
+    #[must_use]
+    pub fn userdel_cmd(&self) -> Option<&str> {
+        self.get("USERDEL_CMD")
+    }
+}
+
+impl Display for LoginDefs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in &self.entries {
+            writeln!(f, "{key}\t{value}")?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_login_defs() {
+    let defs = LoginDefs::parse(
+        "# comment\n\
+        MAIL_DIR        /var/spool/mail\n\
+        \n\
+        PASS_MAX_DAYS\t90\n\
+        UMASK           077\n\
+        UID_MIN                  2000\n\
+        GID_MAX   0xFFFF\n\
+        USERGROUPS_ENAB yes\n\
+        ENCRYPT_METHOD SHA512\n\
+        UNKNOWN_KEY \"some value\"\n\
+        USERDEL_CMD /usr/sbin/userdel_local\n\
+        PASS_MAX_DAYS 120\n",
+    );
+    assert_eq!(defs.mail_dir(), Some("/var/spool/mail"));
+    assert_eq!(defs.pass_max_days(), Some(120));
+    assert_eq!(defs.pass_min_days(), Some(0));
+    assert_eq!(defs.umask(), 0o077);
+    assert_eq!(defs.home_mode(), 0o700);
+    assert_eq!(defs.usergroups_enab(), Some(true));
+    assert_eq!(defs.create_home(), None);
+    assert_eq!(defs.encrypt_method(), Some(EncryptMethod::Sha512));
+    assert_eq!(defs.userdel_cmd(), Some("/usr/sbin/userdel_local"));
+    assert_eq!(defs.get("UNKNOWN_KEY"), Some("some value"));
+    assert_eq!(defs.keys().count(), 9);
+    let ranges = defs.id_ranges();
+    assert_eq!(ranges.uid, IdRange::new(2000, 60000));
+    assert_eq!(ranges.sys_uid, IdRange::new(101, 1999));
+    assert_eq!(ranges.gid, IdRange::new(1000, 0xFFFF));
+    assert_eq!(ranges.sys_gid, IdRange::new(101, 999));
+}
+
+#[test]
+fn test_encrypt_method() {
+    assert_eq!(LoginDefs::default().encrypt_method(), None);
+    assert_eq!(LoginDefs::default().userdel_cmd(), None);
+    for (value, method) in &[
+        ("DES", EncryptMethod::Des),
+        ("md5", EncryptMethod::Md5),
+        ("SHA256", EncryptMethod::Sha256),
+        ("YESCRYPT", EncryptMethod::Yescrypt),
+        ("BCRYPT", EncryptMethod::Bcrypt),
+        ("GOST", EncryptMethod::Other("GOST".to_owned())),
+    ] {
+        let mut defs = LoginDefs::default();
+        defs.set("ENCRYPT_METHOD", value);
+        assert_eq!(defs.encrypt_method().as_ref(), Some(method));
+    }
+}
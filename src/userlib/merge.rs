@@ -0,0 +1,258 @@
+//! Three-way merge of the database files.
+//!
+//! When another program modified a file after it has been read, the writes of this database are staged in memory.
+//! Before anything is written the staged content (ours) and the current content of the file (theirs) are merged
+//! record by record using the content as it was read (the original) as common ancestor.
+//! Records that are added on both sides must not use the same UID, GID or subordinate IDs.
+use super::document::{self, Document};
+use super::subid::SubIdRange;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+
+/// A record that has been changed differently in this database and in the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeConflict {
+    /// The name of the file like `passwd` or `group`.
+    pub file: String,
+    /// The record as it was read. `None` if it did not exist.
+    pub original: Option<String>,
+    /// The record as changed by this database. `None` if it has been removed.
+    pub ours: Option<String>,
+    /// The record as it is found in the file now. `None` if it has been removed.
+    pub theirs: Option<String>,
+}
+
+impl Display for MergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |record: &Option<String>| record.as_deref().unwrap_or("<none>").to_owned();
+        write!(
+            f,
+            "{}: {} was changed to {} but the file contains {}",
+            self.file,
+            show(&self.original),
+            show(&self.ours),
+            show(&self.theirs)
+        )
+    }
+}
+
+/// Records are identified by the name in their first field and the number of records with the same name before them.
+type Key<'a> = (&'a str, usize);
+
+/// Split the document into records.
+///
+/// The lines of the subordinate ID files as well as comments and empty lines are identified by the whole line.
+fn records<'a>(file: &str, content: &'a Document) -> Vec<(Key<'a>, &'a str)> {
+    let whole_lines = matches!(file, "subuid" | "subgid");
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    content
+        .lines()
+        .map(|line| {
+            let name = if whole_lines || !document::is_record(line) {
+                line
+            } else {
+                document::name_of(line)
+            };
+            let count = seen.entry(name).or_insert(0);
+            *count += 1;
+            ((name, *count), line)
+        })
+        .collect()
+}
+
+/// Pick the version of a record: if only one side changed the record that change wins.
+///
+/// Fails if both sides changed the record differently.
+fn pick<'a>(
+    original: Option<&'a str>,
+    ours: Option<&'a str>,
+    theirs: Option<&'a str>,
+) -> Result<Option<&'a str>, ()> {
+    if ours == theirs || theirs == original {
+        Ok(ours)
+    } else if ours == original {
+        Ok(theirs)
+    } else {
+        Err(())
+    }
+}
+
+/// Check if two records of `file` use the same ID (the UID or the GID) or overlapping subordinate IDs.
+fn clash(file: &str, first: &str, second: &str) -> bool {
+    match file {
+        "passwd" | "group" => {
+            fn id(line: &str) -> Option<&str> {
+                line.split(':').nth(2).map(str::trim)
+            }
+            id(first).is_some() && id(first) == id(second)
+        }
+        "subuid" | "subgid" => {
+            match (SubIdRange::from_line(first), SubIdRange::from_line(second)) {
+                (Some(first), Some(second)) => first.overlaps(&second),
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Merge the changes from `original` to `ours` with the changes from `original` to `theirs`.
+///
+/// The order of `theirs` is kept. Records that only exist in `ours` are appended.
+/// Like [`Document`] the lines are kept as they are and the file ends with a newline if `ours` does.
+///
+/// # Errors
+/// All the records that were changed on both sides in different ways
+/// and all the pairs of records from both sides that use the same ID or overlapping subordinate IDs.
+pub fn merge(
+    file: &str,
+    original: &str,
+    ours: &str,
+    theirs: &str,
+) -> Result<String, Vec<MergeConflict>> {
+    let (original, ours, theirs) = (
+        Document::parse(original),
+        Document::parse(ours),
+        Document::parse(theirs),
+    );
+    let original_records: HashMap<Key, &str> = records(file, &original).into_iter().collect();
+    let our_records = records(file, &ours);
+    let their_records = records(file, &theirs);
+    let our_map: HashMap<Key, &str> = our_records.iter().copied().collect();
+    let their_map: HashMap<Key, &str> = their_records.iter().copied().collect();
+
+    let candidates = their_records.iter().chain(
+        our_records
+            .iter()
+            .filter(|(key, _)| !their_map.contains_key(key)),
+    );
+    let mut merged = Vec::new();
+    let mut conflicts = Vec::new();
+    for (key, _) in candidates {
+        let (original, ours, theirs) = (
+            original_records.get(key).copied(),
+            our_map.get(key).copied(),
+            their_map.get(key).copied(),
+        );
+        match pick(original, ours, theirs) {
+            Ok(Some(line)) => merged.push(line),
+            Ok(None) => (),
+            Err(()) => conflicts.push(MergeConflict {
+                file: file.to_owned(),
+                original: original.map(ToOwned::to_owned),
+                ours: ours.map(ToOwned::to_owned),
+                theirs: theirs.map(ToOwned::to_owned),
+            }),
+        }
+    }
+    // IDs that have been taken on both sides are only detected in the merged records.
+    let (our_lines, their_lines): (HashSet<&str>, HashSet<&str>) = (
+        our_records.iter().map(|(_, line)| *line).collect(),
+        their_records.iter().map(|(_, line)| *line).collect(),
+    );
+    for ours in merged.iter().filter(|line| !their_lines.contains(*line)) {
+        for theirs in merged.iter().filter(|line| !our_lines.contains(*line)) {
+            if clash(file, ours, theirs) {
+                conflicts.push(MergeConflict {
+                    file: file.to_owned(),
+                    original: None,
+                    ours: Some((*ours).to_owned()),
+                    theirs: Some((*theirs).to_owned()),
+                });
+            }
+        }
+    }
+    if !conflicts.is_empty() {
+        return Err(conflicts);
+    }
+    let merged = merged.into_iter().map(ToOwned::to_owned).collect();
+    let mut content = ours.clone();
+    content.replace_lines(merged);
+    Ok(content.to_string())
+}
+
+#[test]
+fn test_merge() {
+    let original = "# users\nroot:x:0:0\nteste:x:1002:1002\nfred:x:1003:1003\n";
+    // we delete teste and add anna, they modify root and add bert.
+    let ours = "# users\nroot:x:0:0\nfred:x:1003:1003\nanna:x:1004:1004\n";
+    let theirs = "# users\nroot:x:0:1\nteste:x:1002:1002\nfred:x:1003:1003\nbert:x:1005:1005\n";
+    assert_eq!(
+        merge("passwd", original, ours, theirs).unwrap(),
+        "# users\nroot:x:0:1\nfred:x:1003:1003\nbert:x:1005:1005\nanna:x:1004:1004\n"
+    );
+    // both sides making the same change is no conflict.
+    assert_eq!(merge("passwd", original, ours, ours).unwrap(), ours);
+
+    // they modify the record we delete.
+    let theirs = "# users\nroot:x:0:0\nteste:x:1002:100\nfred:x:1003:1003\n";
+    assert_eq!(
+        merge("passwd", original, ours, theirs).unwrap_err(),
+        vec![MergeConflict {
+            file: "passwd".to_owned(),
+            original: Some("teste:x:1002:1002".to_owned()),
+            ours: None,
+            theirs: Some("teste:x:1002:100".to_owned()),
+        }]
+    );
+    // both add a different record with the same name.
+    let theirs = "# users\nroot:x:0:0\nteste:x:1002:1002\nfred:x:1003:1003\nanna:x:2000:2000\n";
+    assert_eq!(
+        merge("passwd", original, ours, theirs)
+            .unwrap_err()
+            .pop()
+            .unwrap()
+            .ours,
+        Some("anna:x:1004:1004".to_owned())
+    );
+
+    // both add a record with the same UID.
+    let ours = "# users\nroot:x:0:0\nteste:x:1002:1002\nfred:x:1003:1003\nanna:x:1004:1004\n";
+    let theirs = "# users\nroot:x:0:0\nteste:x:1002:1002\nfred:x:1003:1003\nbert:x:1004:100\n";
+    assert_eq!(
+        merge("passwd", original, ours, theirs).unwrap_err(),
+        vec![MergeConflict {
+            file: "passwd".to_owned(),
+            original: None,
+            ours: Some("anna:x:1004:1004".to_owned()),
+            theirs: Some("bert:x:1004:100".to_owned()),
+        }]
+    );
+    // IDs that have been shared before are no conflict.
+    let original = "root:x:0:0\ntoor:x:0:0\n";
+    let ours = "root:x:0:0\ntoor:x:0:0\nanna:x:1004:1004\n";
+    let theirs = "root:x:0:0\ntoor:x:0:0\nbert:x:1005:100\n";
+    assert!(merge("passwd", original, ours, theirs).is_ok());
+
+    // subordinate ID ranges are compared as whole lines.
+    let original = "dietrich:100000:65536\n";
+    let ours = "dietrich:100000:65536\nteste:165536:65536\n";
+    let theirs = "dietrich:100000:65536\ndietrich:231072:65536\n";
+    assert_eq!(
+        merge("subuid", original, ours, theirs).unwrap(),
+        "dietrich:100000:65536\ndietrich:231072:65536\nteste:165536:65536\n"
+    );
+    // but they must not overlap.
+    let theirs = "dietrich:100000:65536\nbert:165536:65536\n";
+    assert_eq!(
+        merge("subuid", original, ours, theirs).unwrap_err().len(),
+        1
+    );
+}
+
+#[test]
+fn test_merge_keeps_the_format() {
+    // the line endings and indented comments are kept like they are by `Document`.
+    let original = "  # users\r\nroot:x:0:0\r\n";
+    let ours = "  # users\r\nroot:x:0:0\r\nanna:x:1004:1004\n";
+    let theirs = "  # users\r\nroot:x:0:1\r\n";
+    assert_eq!(
+        merge("passwd", original, ours, theirs).unwrap(),
+        "  # users\r\nroot:x:0:1\r\nanna:x:1004:1004\n"
+    );
+    // without a trailing newline none is added.
+    assert_eq!(
+        merge("passwd", "root:x:0:0", "root:x:0:0", "root:x:0:1").unwrap(),
+        "root:x:0:1"
+    );
+}
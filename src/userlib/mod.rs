@@ -1,112 +1,630 @@
 #![allow(clippy::non_ascii_literal)]
 
+pub mod allocation;
+pub mod document;
 pub mod files;
 pub mod hashes;
+pub mod home;
+pub mod login_defs;
+pub mod merge;
+pub mod subid;
 
 use crate::{
     api::{
-        CreateUserArgs, DeleteHome, DeleteUserArgs, GroupRead, UserDBRead, UserDBWrite, UserRead,
+        CreateGroupArgs, CreateHome, CreatePrimaryGroup, CreateUserArgs, DeleteGroupArgs,
+        DeleteHome, DeletePrimaryGroup, DeleteUserArgs, GroupRead, NewUser, UserDBRead,
+        UserDBWrite, UserRead,
     },
+    error::{ParseError, ParseFailure},
     group::MembershipKind,
     UserLibError,
 };
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufRead, BufReader, Write};
 
 pub type UserList = HashMap<String, crate::User>;
 
+/// The primary group of new users if no group is specified (`users`).
+const DEFAULT_GID: u32 = 100;
+
+/// The login shell of new users if no shell is specified.
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// How records that cannot be parsed are handled when the database is loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoadOptions {
+    /// Loading fails if any record cannot be parsed.
+    #[default]
+    Strict,
+    /// Records that cannot be parsed are reported as warnings and kept in the files as they are.
+    ///
+    /// Their names and IDs cannot be used by new users or groups so those records are never changed.
+    Lenient,
+}
+
+impl LoadOptions {
+    /// Check the records that could not be parsed.
+    ///
+    /// In lenient mode the failures are added in front of the `warnings` and the names and IDs of the records are reserved.
+    ///
+    /// # Errors
+    /// In strict mode a [`UserLibError::Parse`] containing all the `failures`.
+    fn check(
+        self,
+        contents: &[(&str, &str)],
+        mut failures: Vec<ParseFailure>,
+        mut warnings: Vec<ParseFailure>,
+    ) -> Result<(InvalidRecords, Vec<ParseFailure>), UserLibError> {
+        let mut invalid = InvalidRecords::default();
+        if failures.is_empty() {
+            return Ok((invalid, warnings));
+        }
+        if self == Self::Strict {
+            let error = UserLibError::Parse(failures);
+            error!("{}", error);
+            return Err(error);
+        }
+        for failure in &failures {
+            warn!("Keeping a record that cannot be parsed: {}", failure);
+            if let Some((_, content)) = contents.iter().find(|(file, _)| *file == failure.file) {
+                if let Some((_, line)) = document::Document::parse(content)
+                    .records()
+                    .find(|(n, _)| *n as u64 + 1 == u64::from(failure.line))
+                {
+                    invalid.add(&failure.file, line);
+                }
+            }
+        }
+        failures.append(&mut warnings);
+        Ok((invalid, failures))
+    }
+}
+
+/// The names and IDs of the records that have been kept in the files without being parsed in lenient mode.
+#[derive(Debug, Clone, Default)]
+struct InvalidRecords {
+    usernames: HashSet<String>,
+    groupnames: HashSet<String>,
+    uids: HashSet<u32>,
+    gids: HashSet<u32>,
+}
+
+impl InvalidRecords {
+    /// Reserve the name and the ID (if it can be read) of the record `line` in `file`.
+    fn add(&mut self, file: &str, line: &str) {
+        let mut fields = line.split(':');
+        let name = fields.next().unwrap_or_default().to_owned();
+        let id = fields.nth(1).and_then(|id| id.trim().parse::<u32>().ok());
+        match file {
+            "passwd" | "shadow" => {
+                self.usernames.insert(name);
+                if let (Some(uid), "passwd") = (id, file) {
+                    self.uids.insert(uid);
+                }
+            }
+            "group" | "gshadow" => {
+                self.groupnames.insert(name);
+                if let (Some(gid), "group") = (id, file) {
+                    self.gids.insert(gid);
+                }
+            }
+            // the lines of the subordinate ID files are identified as a whole and never changed.
+            _ => (),
+        }
+    }
+}
+
+/// The IDs used by the records of the files as they are right before new IDs are allocated.
+///
+/// Others might have added records since the files have been read. Their IDs are never allocated.
+#[derive(Debug, Clone, Default)]
+struct ForeignIds {
+    uids: HashSet<u32>,
+    gids: HashSet<u32>,
+    subuids: subid::SubIds,
+    subgids: subid::SubIds,
+}
+
+impl ForeignIds {
+    /// The IDs in the third field of the records in `content`.
+    fn parse_ids(content: &str) -> HashSet<u32> {
+        document::Document::parse(content)
+            .records()
+            .filter_map(|(_, line)| line.split(':').nth(2)?.trim().parse().ok())
+            .collect()
+    }
+}
+
+/// The optional gshadow, subuid and subgid files of a database that is loaded from readers or saved to writers.
+///
+/// See [`UserDBLocal::load_from_readers`] and [`UserDBLocal::save_to_writers`].
+#[derive(Debug)]
+pub struct OptionalFiles<T> {
+    pub gshadow: Option<T>,
+    pub subuid: Option<T>,
+    pub subgid: Option<T>,
+}
+
+impl<T> Default for OptionalFiles<T> {
+    fn default() -> Self {
+        Self {
+            gshadow: None,
+            subuid: None,
+            subgid: None,
+        }
+    }
+}
+
+impl<T> OptionalFiles<T> {
+    fn into_named(self) -> [(&'static str, Option<T>); 3] {
+        [
+            ("gshadow", self.gshadow),
+            ("subuid", self.subuid),
+            ("subgid", self.subgid),
+        ]
+    }
+}
+
 pub struct UserDBLocal {
     source_files: files::Files,
     source_hashes: hashes::Hashes, // to detect changes
+    source_snapshots: HashMap<&'static str, String>, // the common ancestors when merging changes
+    login_defs: login_defs::LoginDefs,
     pub users: UserList,
     pub groups: Vec<crate::Group>,
+    subuids: subid::SubIds,
+    subgids: subid::SubIds,
+    invalid_records: InvalidRecords,
+    foreign_ids: ForeignIds,
+    read_only: bool,
 }
 
 impl UserDBLocal {
     /// Import the database from strings
-    #[must_use]
+    ///
+    /// The database only exists in memory. All the changes are applied to the imported contents (see [`UserDBLocal::export_to_strings`]).
+    ///
+    /// # Errors
+    /// A [`UserLibError::Parse`] containing all the records that could not be parsed.
     pub fn import_from_strings(
         passwd_content: &str,
         shadow_content: &str,
         group_content: &str,
-    ) -> Self {
-        let shadow_entries: Vec<crate::Shadow> = string_to(shadow_content);
-        let mut users = user_vec_to_hashmap(string_to(passwd_content));
-        let mut groups = string_to(group_content);
-        shadow_to_users(&mut users, shadow_entries);
-        groups_to_users(&mut users, &mut groups);
-        Self {
-            source_files: files::Files {
-                passwd: None,
-                group: None,
-                shadow: None,
-            },
+    ) -> Result<Self, UserLibError> {
+        Self::import_from_strings_with_options(
+            passwd_content,
+            shadow_content,
+            group_content,
+            LoadOptions::Strict,
+        )
+        .map(|(db, _)| db)
+    }
+
+    /// Import the database from strings and return it together with the warnings.
+    ///
+    /// # Errors
+    /// In strict mode a [`UserLibError::Parse`] containing all the records that could not be parsed.
+    pub fn import_from_strings_with_options(
+        passwd_content: &str,
+        shadow_content: &str,
+        group_content: &str,
+        options: LoadOptions,
+    ) -> Result<(Self, Vec<ParseFailure>), UserLibError> {
+        let mut failures = Vec::new();
+        let mut warnings = Vec::new();
+        let (users, groups) = parse_contents(
+            [passwd_content, shadow_content, group_content, ""],
+            &mut failures,
+            &mut warnings,
+        );
+        let (invalid_records, warnings) = options.check(
+            &[
+                ("passwd", passwd_content),
+                ("shadow", shadow_content),
+                ("group", group_content),
+            ],
+            failures,
+            warnings,
+        )?;
+        let db = Self {
+            source_files: files::Files::in_memory(),
             users,
             groups,
-            source_hashes: hashes::Hashes::new(passwd_content, shadow_content, group_content),
-        }
+            source_hashes: hashes::Hashes::new(passwd_content, shadow_content, group_content, ""),
+            // without files all the changes are applied to these contents.
+            source_snapshots: vec![
+                ("passwd", passwd_content.to_owned()),
+                ("shadow", shadow_content.to_owned()),
+                ("group", group_content.to_owned()),
+            ]
+            .into_iter()
+            .collect(),
+            login_defs: login_defs::LoginDefs::default(),
+            subuids: subid::SubIds::default(),
+            subgids: subid::SubIds::default(),
+            invalid_records,
+            foreign_ids: ForeignIds::default(),
+            read_only: false,
+        };
+        Ok((db, warnings))
     }
 
     /// Import the database from a [`Files`] struct
+    ///
+    /// # Errors
+    /// Fails if the files cannot be locked or read.
+    /// If records cannot be parsed a [`UserLibError::Parse`] contains all the problems of all the files.
     pub fn load_files(files: files::Files) -> Result<Self, crate::UserLibError> {
+        Self::load_files_with_options(files, LoadOptions::Strict).map(|(db, _)| db)
+    }
+
+    /// Import the database from a [`Files`] struct and return it together with the warnings.
+    ///
+    /// Warnings are records that refer to missing users or groups and (in lenient mode) all the records that cannot be parsed.
+    ///
+    /// # Errors
+    /// Fails if the files cannot be locked or read.
+    /// In strict mode a [`UserLibError::Parse`] contains all the records of all the files that cannot be parsed.
+    pub fn load_files_with_options(
+        files: files::Files,
+        options: LoadOptions,
+    ) -> Result<(Self, Vec<ParseFailure>), crate::UserLibError> {
         // Get the Strings for the files use an inner block to drop references after read.
-        let (my_passwd_lines, my_shadow_lines, my_group_lines) = {
-            let opened = files.lock_all_get();
-            let (locked_p, locked_s, locked_g) = opened.expect("failed to lock files!");
+        let (
+            my_passwd_lines,
+            my_shadow_lines,
+            my_group_lines,
+            my_gshadow_content,
+            subid_contents,
+            source_hashes,
+        ) = {
+            let mut locked = files.lock_all_get()?;
             // read the files to strings
-            let p = file_to_string(&locked_p.file)?;
-            let s = file_to_string(&locked_s.file)?;
-            let g = file_to_string(&locked_g.file)?;
+            let p = file_to_string(&locked.passwd.file)?;
+            let s = file_to_string(&locked.shadow.file)?;
+            let g = file_to_string(&locked.group.file)?;
+            let gshadow = optional_file_to_string(locked.gshadow.as_mut())?;
+            let subids = (
+                optional_file_to_string(locked.subuid.as_mut())?,
+                optional_file_to_string(locked.subgid.as_mut())?,
+            );
+            let hashes = hashes::Hashes::from_locked(&mut locked)?;
             // return the strings to the outer scope and release the lock...
-            (p, s, g)
+            (p, s, g, gshadow, subids, hashes)
         };
 
-        let mut users = user_vec_to_hashmap(string_to(&my_passwd_lines));
-        let passwds: Vec<crate::Shadow> = string_to(&my_shadow_lines);
-        let mut groups: Vec<crate::Group> = string_to(&my_group_lines);
-        shadow_to_users(&mut users, passwds);
-        groups_to_users(&mut users, &mut groups);
-        Ok(Self {
+        let source_snapshots = vec![
+            ("passwd", my_passwd_lines),
+            ("shadow", my_shadow_lines),
+            ("group", my_group_lines),
+            ("gshadow", my_gshadow_content),
+            ("subuid", subid_contents.0),
+            ("subgid", subid_contents.1),
+        ]
+        .into_iter()
+        .collect();
+        Self::from_snapshots(files, source_snapshots, source_hashes, options)
+    }
+
+    /// Load the database from the files without locking them.
+    ///
+    /// Unlike [`UserDBLocal::load_files`] no write access is needed so it can be used by users other than root.
+    /// If the permission to read the shadow or the gshadow file is denied, the file is skipped and the passwords are [`Password::Unknown`](crate::Password::Unknown).
+    /// All the methods of [`UserDBWrite`] fail with [`UserLibError::ReadOnly`] on the returned database.
+    ///
+    /// # Errors
+    /// Fails if the passwd or group file cannot be read.
+    /// In strict mode a [`UserLibError::Parse`] contains all the records of all the files that cannot be parsed.
+    pub fn load_files_read_only(
+        files: files::Files,
+        options: LoadOptions,
+    ) -> Result<(Self, Vec<ParseFailure>), crate::UserLibError> {
+        Self::load_read_only_with(files, options, files::read_unlocked)
+    }
+
+    /// Like [`UserDBLocal::load_files_read_only`] with the optional files (shadow, gshadow, subuid and subgid) read by `read_optional`.
+    fn load_read_only_with<R>(
+        files: files::Files,
+        options: LoadOptions,
+        read_optional: R,
+    ) -> Result<(Self, Vec<ParseFailure>), crate::UserLibError>
+    where
+        R: Fn(Option<&std::path::Path>) -> std::io::Result<Option<String>>,
+    {
+        let required = |path: Option<&std::path::PathBuf>| -> Result<String, UserLibError> {
+            let path = files.in_root(path.ok_or(UserLibError::FilesRequired)?)?;
+            std::fs::read_to_string(&path)
+                .map_err(|e| (format!("Failed to read {}", path.display()), e).into())
+        };
+        let mut denied = Vec::new();
+        let mut optional =
+            |name, path: Option<&std::path::PathBuf>| -> Result<String, UserLibError> {
+                let path = path.map(|p| files.in_root(p)).transpose()?;
+                match read_optional(path.as_deref()) {
+                    Ok(content) => Ok(content.unwrap_or_default()),
+                    Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                        warn!("Skipping the {} file: {}", name, e);
+                        denied.push(name);
+                        Ok(String::new())
+                    }
+                    Err(e) => Err((format!("Failed to read the {name} file"), e).into()),
+                }
+            };
+        let source_snapshots: HashMap<&'static str, String> = vec![
+            ("passwd", required(files.passwd.as_ref())?),
+            ("shadow", optional("shadow", files.shadow.as_ref())?),
+            ("group", required(files.group.as_ref())?),
+            ("gshadow", optional("gshadow", files.gshadow.as_ref())?),
+            ("subuid", optional("subuid", files.subuid.as_ref())?),
+            ("subgid", optional("subgid", files.subgid.as_ref())?),
+        ]
+        .into_iter()
+        .collect();
+        let content = |name| source_snapshots.get(name).map_or("", String::as_str);
+        let source_hashes = hashes::Hashes::new(
+            content("passwd"),
+            content("shadow"),
+            content("group"),
+            content("gshadow"),
+        );
+        let (mut db, warnings) =
+            Self::from_snapshots(files, source_snapshots, source_hashes, options)?;
+        db.read_only = true;
+        // the password is in the shadow file if the passwd file contains an `x`.
+        if denied.contains(&"shadow") {
+            for user in db.users.values_mut() {
+                if user.get_password() == Some("x") {
+                    user.password = crate::Password::Unknown;
+                }
+            }
+        }
+        if denied.contains(&"gshadow") {
+            for group in &mut db.groups {
+                if group.get_encrypted_password() == Some("x") {
+                    group.password = crate::Password::Unknown;
+                }
+            }
+        }
+        Ok((db, warnings))
+    }
+
+    /// Parse the contents of the files and link the records.
+    fn from_snapshots(
+        files: files::Files,
+        source_snapshots: HashMap<&'static str, String>,
+        source_hashes: hashes::Hashes,
+        options: LoadOptions,
+    ) -> Result<(Self, Vec<ParseFailure>), crate::UserLibError> {
+        let content = |name| source_snapshots.get(name).map_or("", String::as_str);
+        let login_defs = files.load_login_defs()?;
+        let mut failures = Vec::new();
+        let mut warnings = Vec::new();
+        let (users, groups) = parse_contents(
+            [
+                content("passwd"),
+                content("shadow"),
+                content("group"),
+                content("gshadow"),
+            ],
+            &mut failures,
+            &mut warnings,
+        );
+        let mut subids = (
+            subid::SubIds::parse_valid(content("subuid")),
+            subid::SubIds::parse_valid(content("subgid")),
+        );
+        for (file, found) in &mut [("subuid", &mut subids.0 .1), ("subgid", &mut subids.1 .1)] {
+            failures.extend(found.drain(..).map(|failure| ParseFailure {
+                file: (*file).to_owned(),
+                ..failure
+            }));
+        }
+        let contents: Vec<(&str, &str)> = source_snapshots
+            .iter()
+            .map(|(name, content)| (*name, content.as_str()))
+            .collect();
+        let (invalid_records, warnings) = options.check(&contents, failures, warnings)?;
+        let db = Self {
             source_files: files,
             users,
             groups,
-            source_hashes: hashes::Hashes::new(&my_passwd_lines, &my_shadow_lines, &my_group_lines),
-        })
+            source_hashes,
+            source_snapshots,
+            login_defs,
+            subuids: subids.0 .0,
+            subgids: subids.1 .0,
+            invalid_records,
+            foreign_ids: ForeignIds::default(),
+            read_only: false,
+        };
+        Ok((db, warnings))
+    }
+    /// The current contents of the passwd, shadow and group files.
+    ///
+    /// For a database that has been imported from strings these are the imported contents with all the changes applied.
+    #[must_use]
+    pub fn export_to_strings(&self) -> (String, String, String) {
+        let content = |name| self.source_snapshots.get(name).cloned().unwrap_or_default();
+        (content("passwd"), content("shadow"), content("group"))
+    }
+
+    /// Load the database from readers like archives, pipes or network buffers.
+    ///
+    /// Like [`UserDBLocal::import_from_strings_with_options`] the database only exists in memory.
+    /// The `optional` files that are not given are not part of the database.
+    ///
+    /// # Errors
+    /// Fails if a reader fails or does not provide UTF-8.
+    /// In strict mode a [`UserLibError::Parse`] contains all the records that could not be parsed.
+    pub fn load_from_readers<P, S, G>(
+        passwd: P,
+        shadow: S,
+        group: G,
+        optional: OptionalFiles<&mut dyn BufRead>,
+        options: LoadOptions,
+    ) -> Result<(Self, Vec<ParseFailure>), UserLibError>
+    where
+        P: BufRead,
+        S: BufRead,
+        G: BufRead,
+    {
+        let mut source_snapshots: HashMap<&'static str, String> = vec![
+            ("passwd", reader_to_string(passwd)?),
+            ("shadow", reader_to_string(shadow)?),
+            ("group", reader_to_string(group)?),
+        ]
+        .into_iter()
+        .collect();
+        for (name, reader) in optional.into_named() {
+            if let Some(reader) = reader {
+                source_snapshots.insert(name, reader_to_string(reader)?);
+            }
+        }
+        let content = |name| source_snapshots.get(name).map_or("", String::as_str);
+        let source_hashes = hashes::Hashes::new(
+            content("passwd"),
+            content("shadow"),
+            content("group"),
+            content("gshadow"),
+        );
+        Self::from_snapshots(
+            files::Files::in_memory(),
+            source_snapshots,
+            source_hashes,
+            options,
+        )
+    }
+
+    /// Write the current contents of the files (see [`UserDBLocal::export_to_strings`]) to writers.
+    ///
+    /// An `optional` file that is part of the database has to be written as well.
+    ///
+    /// # Errors
+    /// Fails if a writer fails or if there is no writer for an optional file with contents.
+    pub fn save_to_writers<P, S, G>(
+        &self,
+        mut passwd: P,
+        mut shadow: S,
+        mut group: G,
+        optional: OptionalFiles<&mut dyn Write>,
+    ) -> Result<(), UserLibError>
+    where
+        P: Write,
+        S: Write,
+        G: Write,
+    {
+        let contents = self.export_to_strings();
+        let mut writers = vec![
+            ("passwd", &mut passwd as &mut dyn Write, contents.0),
+            ("shadow", &mut shadow, contents.1),
+            ("group", &mut group, contents.2),
+        ];
+        for (name, writer) in optional.into_named() {
+            let content = self.source_snapshots.get(name).cloned().unwrap_or_default();
+            match writer {
+                Some(writer) => writers.push((name, writer, content)),
+                None if content.is_empty() => (),
+                None => {
+                    return Err(format!("There is no writer for the {name} database").into());
+                }
+            }
+        }
+        for (name, writer, content) in writers {
+            writer
+                .write_all(content.as_bytes())
+                .and_then(|()| writer.flush())
+                .map_err(|e| (format!("Failed to write the {name} database"), e))?;
+        }
+        Ok(())
+    }
+
+    /// Check if the database has been loaded with [`UserDBLocal::load_files_read_only`].
+    #[must_use]
+    pub const fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fail if the database has been loaded read-only.
+    const fn check_writable(&self) -> Result<(), UserLibError> {
+        if self.read_only {
+            Err(UserLibError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Read the IDs that are used in the files right now so that IDs of records added by others are not allocated.
+    ///
+    /// If those records are added after this, [`merge::merge`] reports the duplicate IDs as conflicts.
+    fn reload_foreign_ids(&mut self) -> Result<(), UserLibError> {
+        if self.source_files.is_virtual() {
+            return Ok(());
+        }
+        let files = &self.source_files;
+        let read = |path: Option<&std::path::PathBuf>| -> Result<String, UserLibError> {
+            let path = path.map(|p| files.in_root(p)).transpose()?;
+            files::read_unlocked(path.as_deref())
+                .map(Option::unwrap_or_default)
+                .map_err(|e| ("Failed to read the current IDs".to_owned(), e).into())
+        };
+        self.foreign_ids = ForeignIds {
+            uids: ForeignIds::parse_ids(&read(files.passwd.as_ref())?),
+            gids: ForeignIds::parse_ids(&read(files.group.as_ref())?),
+            subuids: subid::SubIds::parse_valid(&read(files.subuid.as_ref())?).0,
+            subgids: subid::SubIds::parse_valid(&read(files.subgid.as_ref())?).0,
+        };
+        Ok(())
+    }
+
+    /// The ranges new UIDs and GIDs are allocated from.
+    #[must_use]
+    pub fn get_id_ranges(&self) -> allocation::IdRanges {
+        self.login_defs.id_ranges()
+    }
+
+    /// The `login.defs` settings that are used when creating users and groups.
+    #[must_use]
+    pub const fn get_login_defs(&self) -> &login_defs::LoginDefs {
+        &self.login_defs
+    }
+
+    pub fn set_login_defs(&mut self, login_defs: login_defs::LoginDefs) -> &mut Self {
+        self.login_defs = login_defs;
+        self
     }
+
     fn delete_from_passwd(
-        user: &crate::User,
-        passwd_file_content: &str,
-        locked_p: &mut files::LockedFileGuard,
+        username: &str,
+        files: &mut dyn files::EditFiles,
     ) -> Result<(), UserLibError> {
-        let modified_p = user.remove_in(passwd_file_content);
-
-        // write the new content to the file.
-        let ncont = locked_p.replace_contents(modified_p);
+        // only the line of the user is removed from the file.
+        let ncont = files.edit_file("passwd", &mut |document| {
+            document.remove(username);
+        });
         match ncont {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("Failed to write the passwd database: {}", e).into()),
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Failed to write the passwd database: {e}").into()),
         }
     }
 
     fn delete_from_shadow(
         user: &crate::User,
-        shadow_file_content: &str,
-        locked_s: &mut files::LockedFileGuard,
+        files: &mut dyn files::EditFiles,
     ) -> Result<(), UserLibError> {
         let shad = user.get_shadow();
         match shad {
             Some(shadow) => {
-                let modified_s = shadow.remove_in(shadow_file_content);
-                let ncont = locked_s.replace_contents(modified_s);
+                let ncont = files.edit_file("shadow", &mut |document| {
+                    document.remove(shadow.get_username());
+                });
                 match ncont {
-                    Ok(_) => Ok(()),
+                    Ok(()) => Ok(()),
                     Err(e) => Err(format!(
                         "Error during write to the database. \
-                    Please doublecheck as the shadowdatabase could be corrupted: {}",
-                        e,
+                    Please doublecheck as the shadowdatabase could be corrupted: {e}",
                     )
                     .into()),
                 }
@@ -115,207 +633,739 @@ impl UserDBLocal {
         }
     }
 
+    /// Remove the records of the group from the group file and the gshadow file (if there is one).
     fn delete_from_group(
-        group: &crate::Group,
-        group_file_content: &str,
-        locked_g: &mut files::LockedFileGuard,
+        groupname: &str,
+        files: &mut dyn files::EditFiles,
     ) -> Result<(), UserLibError> {
-        let modified_g = group.borrow().remove_in(group_file_content);
-        let replace_result = locked_g.replace_contents(modified_g);
+        let mut remove = |document: &mut document::Document| {
+            document.remove(groupname);
+        };
+        let replace_result = files
+            .edit_file("group", &mut remove)
+            .and_then(|()| files.edit_file("gshadow", &mut remove));
         match replace_result {
-            Ok(_) => Ok(()),
+            Ok(()) => Ok(()),
             Err(e) => Err(format!(
                 "Error during write to the database. \
-            Please doublecheck as the groupdatabase could be corrupted: {}",
-                e,
+            Please doublecheck as the groupdatabase could be corrupted: {e}",
             )
             .into()),
         }
     }
 
-    fn write_groups(&self, locked_g: &mut files::LockedFileGuard) -> Result<(), UserLibError> {
-        let content = self
-            .groups
-            .iter()
-            .map(|g| (g.borrow().to_string()))
-            .collect::<Vec<String>>()
-            .join("\n");
-        let replace_result = locked_g.replace_contents(content);
+    /// Like groupdel refuse to delete the primary group of a user with [`DeletePrimaryGroup::Delete`]
+    /// if it is the primary group of other users as well.
+    fn check_primary_group_deletable(&self, args: &DeleteUserArgs) -> Result<(), UserLibError> {
+        if args.delete_primary_group != DeletePrimaryGroup::Delete {
+            return Ok(());
+        }
+        let gid = self.get_user_by_name(args.username).map(UserRead::get_gid);
+        let primary_of: Vec<&str> = self
+            .users
+            .values()
+            .filter(|u| Some(u.get_gid()) == gid && u.get_username() != Some(args.username))
+            .filter_map(UserRead::get_username)
+            .collect();
+        if primary_of.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "The primary group of {} is the primary group of: {} as well. Aborting!",
+                args.username,
+                primary_of.join(", ")
+            )
+            .into())
+        }
+    }
+
+    /// Write the current state of the group to its records in the group file and the gshadow file (if there is one).
+    ///
+    /// The records are looked up by `previous_name` after a rename. Missing records are appended.
+    /// If the group has no gshadow entry but there is a gshadow file the entry is created, so that both files stay in sync.
+    /// All the other lines of the files stay untouched.
+    fn write_group(
+        group: &mut crate::Group,
+        previous_name: Option<&str>,
+        files: &mut dyn files::EditFiles,
+    ) -> Result<(), UserLibError> {
+        if files.contains("gshadow") {
+            group.add_missing_gshadow()?;
+        }
+        let group = &*group;
+        let name = previous_name
+            .or_else(|| group.get_groupname())
+            .ok_or("a group has to have a name")?;
+        let mut replace_result = files.edit_file("group", &mut |document| {
+            document.set(name, group.to_string());
+        });
+        if let (Ok(()), Some(gshadow)) = (&replace_result, group.get_gshadow()) {
+            replace_result = files.edit_file("gshadow", &mut |document| {
+                document.set(name, gshadow.to_string());
+            });
+        }
         match replace_result {
-            Ok(_) => Ok(()),
+            Ok(()) => Ok(()),
             Err(e) => Err(format!(
                 "Error during write to the database. \
-            Please doublecheck as the groupdatabase could be corrupted: {}",
-                e,
+            Please doublecheck as the groupdatabase could be corrupted: {e}",
             )
             .into()),
         }
     }
 
-    fn delete_home(user: &crate::User) -> std::io::Result<()> {
-        if let Some(dir) = user.get_home_dir() {
-            std::fs::remove_dir_all(dir)
+    /// Apply `write` to the files of the database.
+    ///
+    /// A database without files (see [`files::Files::is_virtual`]) only changes its contents in memory.
+    /// Otherwise the files are locked and [`UserDBLocal::finish_write`] merges the changes with the modifications of others.
+    ///
+    /// `write` does not change the database. It returns the new state which the caller applies once all the files have been written,
+    /// so that the database still matches the files if writing fails.
+    fn write_files<T, F>(&mut self, action: &str, write: F) -> Result<T, UserLibError>
+    where
+        F: FnOnce(&Self, &mut dyn files::EditFiles) -> Result<T, UserLibError>,
+    {
+        if self.source_files.is_virtual() {
+            debug!("{} in memory", action);
+            let mut contents = self.source_snapshots.clone();
+            let result = write(self, &mut contents)?;
+            self.source_snapshots = contents;
+            Ok(result)
         } else {
+            let mut locked = self.lock_for_write(action)?;
+            let result = write(self, &mut locked)?;
+            self.finish_write(&mut locked)?;
+            Ok(result)
+        }
+    }
+
+    /// Lock all the files for writing.
+    ///
+    /// If a file has been modified by others since it has been read all the writes are staged in memory.
+    /// [`UserDBLocal::finish_write`] merges them with those modifications afterwards.
+    fn lock_for_write(&self, action: &str) -> Result<files::LockedFiles, UserLibError> {
+        let mut locked = self.source_files.lock_all_get()?;
+        let changed = self.source_hashes.changed_files(&mut locked)?;
+        if !changed.is_empty() {
+            warn!(
+                "The source files ({}) have changed. {} merges its changes with them.",
+                changed.join(", "),
+                action
+            );
+            for (name, guard) in locked.guards_mut() {
+                guard.stage(self.source_snapshots.get(name).cloned().unwrap_or_default());
+            }
+        }
+        Ok(locked)
+    }
+
+    /// Write the staged changes merged with the modifications of others and remember the state of all the files.
+    ///
+    /// After a merge the files contain records that are not part of this database.
+    /// The fingerprint of those files is therefore taken from the content of the database so that every following write is merged as well.
+    ///
+    /// # Errors
+    /// If a record has been changed here and in the file nothing is written and [`UserLibError::FilesChanged`] contains the conflicting records.
+    /// The database has to be reloaded in that case.
+    fn finish_write(&mut self, locked: &mut files::LockedFiles) -> Result<(), UserLibError> {
+        let mut merged = HashMap::new();
+        let mut conflicts = Vec::new();
+        for (name, guard) in locked.guards_mut() {
+            if let Some(ours) = guard.take_staged() {
+                let theirs = guard.read_contents()?;
+                let original = self.source_snapshots.get(name).map_or("", String::as_str);
+                match merge::merge(name, original, &ours, &theirs) {
+                    Ok(content) => {
+                        merged.insert(name, (ours, content));
+                    }
+                    Err(mut c) => conflicts.append(&mut c),
+                }
+            }
+        }
+        if !conflicts.is_empty() {
+            error!(
+                "The changes conflict with the modifications of others: {}",
+                conflicts
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<String>>()
+                    .join("; ")
+            );
+            return Err(UserLibError::FilesChanged(conflicts));
+        }
+        for (name, guard) in locked.guards_mut() {
+            if let Some((_, content)) = merged.get(name) {
+                info!("Writing the merged {} file", name);
+                guard.write_all(content)?;
+            }
+            self.source_snapshots.insert(name, guard.read_contents()?);
+        }
+        self.source_hashes = hashes::Hashes::from_locked(locked)?;
+        for (name, (ours, content)) in merged {
+            if ours != content {
+                self.source_snapshots.insert(name, ours.clone());
+                if let Some(hash) = self.source_hashes.get_mut(name) {
+                    *hash = hashes::SourceHash::new(&ours);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The path of the home directory of `user` that is removed when the user is deleted.
+    ///
+    /// Like `userdel --remove` this refuses to remove a home directory that is the root directory.
+    fn home_to_delete(&self, user: &crate::User) -> Result<std::path::PathBuf, UserLibError> {
+        use std::path::Path;
+
+        let dir = user.get_home_dir().ok_or_else(|| {
             let error_msg = "Failed to remove the home directory! As the user did not have one.";
             error!("{}", error_msg);
-            Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                error_msg,
-            ))
+            UserLibError::from(error_msg)
+        })?;
+        let canonical =
+            |path: &Path| std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+        let home = self.source_files.in_root(Path::new(dir))?;
+        let resolved = canonical(&home);
+        if resolved == Path::new("/")
+            || resolved == canonical(&self.source_files.in_root(Path::new("/"))?)
+        {
+            let error_msg = format!(
+                "The home directory {dir} is the root directory and is not removed. Aborting!"
+            );
+            error!("{}", error_msg);
+            return Err(error_msg.into());
         }
+        Ok(home)
     }
 
-    fn delete_group_by_id(&mut self, gid: u32) {
-        self.groups
-            .retain(|g| g.borrow().get_gid().expect("groups have to have a gid") != gid);
+    /// How the home of a new user is created: as requested in `args` or as configured by `CREATE_HOME` in `login.defs`.
+    fn create_home_for(&self, args: &CreateUserArgs) -> CreateHome {
+        args.create_home.clone().unwrap_or_else(|| {
+            if self.login_defs.create_home().unwrap_or(true) {
+                CreateHome::Create
+            } else {
+                CreateHome::Skip
+            }
+        })
     }
-}
 
-impl UserDBWrite for UserDBLocal {
-    fn delete_user(&mut self, args: DeleteUserArgs) -> Result<crate::User, UserLibError> {
-        // try to get the user from the database
-        let user_opt = self.get_user_by_name(args.username);
-        let user = match user_opt {
-            Some(user) => user,
-            None => {
-                return Err(UserLibError::NotFound);
+    /// If a new user gets a group with its name: as requested in `args` or as configured by `USERGROUPS_ENAB` in `login.defs`.
+    fn create_primary_group_for(&self, args: &CreateUserArgs) -> CreatePrimaryGroup {
+        args.create_primary_group.clone().unwrap_or_else(|| {
+            if self.login_defs.usergroups_enab().unwrap_or(true) {
+                CreatePrimaryGroup::CreateIfEmptyOrAdd
+            } else {
+                CreatePrimaryGroup::Skip
+            }
+        })
+    }
+
+    /// Validate the arguments and construct the matching user record.
+    fn user_from_args(
+        &self,
+        args: &CreateUserArgs,
+    ) -> Result<(crate::User, Option<crate::Group>), UserLibError> {
+        use std::convert::TryFrom;
+
+        if !self.is_username_valid_and_free(args.username) {
+            return Err(format!(
+                "The username {} is invalid or already exists! Aborting!",
+                args.username
+            )
+            .into());
+        }
+        let (uid, gid, primary_group) = self.ids_for_new_user(args)?;
+        for group in &args.groups {
+            if self.get_group_by_name(group).is_none() {
+                return Err(format!("The group {group} does not exist! Aborting!").into());
+            }
+        }
+        let home_dir = args
+            .home_dir
+            .map_or_else(|| format!("/home/{}", args.username), ToOwned::to_owned);
+        if !home_dir.starts_with('/') {
+            return Err(format!("The home directory {home_dir} is not an absolute path").into());
+        }
+        if let Some(shell) = args.shell {
+            if !shell.starts_with('/') {
+                return Err(format!("The shell {shell} is not an absolute path").into());
+            }
+        }
+        if let CreateHome::HomeFromDir { ref path, .. } = self.create_home_for(args) {
+            if !self.source_files.is_virtual() {
+                home::check_adoptable(
+                    &self.source_files.in_root(path)?,
+                    &self.source_files.in_root(std::path::Path::new(&home_dir))?,
+                )?;
             }
+        }
+        for (name, value) in &[
+            ("gecos", args.gecos),
+            ("home directory", Some(home_dir.as_str())),
+            ("shell", args.shell),
+            ("password", args.password),
+        ] {
+            if let Some(value) = value {
+                if value.contains(':') || value.contains('\n') {
+                    return Err(format!("The {name} {value} contains invalid characters").into());
+                }
+            }
+        }
+
+        let mut new_user = crate::User::default();
+        new_user
+            .username(args.username.to_owned())
+            .uid(uid)
+            .gid(gid)
+            .home_dir(home_dir)
+            .shell_path(args.shell.unwrap_or(DEFAULT_SHELL).to_owned())
+            .gecos(crate::Gecos::try_from(args.gecos.unwrap_or("").to_owned())?);
+        if let Some(shadow) = new_user.get_shadow_mut() {
+            if let Some(password) = args.password {
+                shadow.set_password(password.to_owned());
+            }
+            shadow.set_last_change(chrono::Utc::today().naive_utc());
+            shadow
+                .set_aging(
+                    self.login_defs.pass_min_days(),
+                    self.login_defs.pass_max_days(),
+                    self.login_defs.pass_warn_age(),
+                )
+                .map_err(|e| format!("Invalid password aging in login.defs: {e}"))?;
+            shadow.set_expire_date(args.expire_date);
+        }
+        Ok((new_user, primary_group))
+    }
+
+    /// Determine the UID and the GID of a new user.
+    ///
+    /// If a new primary group has to be created according to [`CreatePrimaryGroup`] it is returned as well.
+    /// In that case the GID matches the UID if possible.
+    fn ids_for_new_user(
+        &self,
+        args: &CreateUserArgs,
+    ) -> Result<(u32, u32, Option<crate::Group>), UserLibError> {
+        use crate::api::PrimaryGroup;
+
+        let create_primary_group = self.create_primary_group_for(args);
+        let existing_group = self.get_group_by_name(args.username);
+        let create_group = match (args.group, &create_primary_group) {
+            (Some(_), _) | (None, CreatePrimaryGroup::Skip) => false,
+            (None, CreatePrimaryGroup::Create) if existing_group.is_some() => {
+                return Err(
+                    format!("A group named {} already exists! Aborting!", args.username).into(),
+                );
+            }
+            (None, CreatePrimaryGroup::Create) => true,
+            (None, CreatePrimaryGroup::CreateIfEmptyOrAdd) => existing_group.is_none(),
         };
 
-        if self.source_files.is_virtual() {
-            warn!("There are no associated files working in dummy mode!");
-            let res = self.users.remove(args.username);
-            match res {
-                Some(u) => Ok(u),
-                None => Err(UserLibError::NotFound), // should not happen anymore as existence is checked.
+        let (uid, paired_gid) = match args.uid {
+            Some(uid) if self.is_uid_valid_and_free(uid) => (uid, None),
+            Some(uid) => {
+                return Err(format!("The UID {uid} is already in use! Aborting!").into());
             }
-        } else {
-            let opened = self.source_files.lock_all_get();
-            let (mut locked_p, mut locked_s, mut locked_g) = opened.expect("failed to lock files!");
+            None if create_group => {
+                let (uid, gid) = self
+                    .next_free_uid_gid_pair(args.system)
+                    .ok_or("There is no free UID/GID left. Aborting!")?;
+                (uid, Some(gid))
+            }
+            None => (
+                self.next_free_uid(args.system)
+                    .ok_or("There is no free UID left. Aborting!")?,
+                None,
+            ),
+        };
 
-            // read the files to strings
-            let passwd_file_content = file_to_string(&locked_p.file)?;
-            let shadow_file_content = file_to_string(&locked_s.file)?;
-            let group_file_content = file_to_string(&locked_g.file)?;
+        if create_group {
+            let gid = match paired_gid {
+                Some(gid) => gid,
+                None if self.is_gid_valid_and_free(uid) => uid,
+                None => self
+                    .next_free_gid(args.system)
+                    .ok_or("There is no free GID left. Aborting!")?,
+            };
+            info!("Creating the primary group {} ({})", args.username, gid);
+            let group = crate::Group::new(args.username, gid, &[])?;
+            return Ok((uid, gid, Some(group)));
+        }
 
-            let src = &self.source_hashes;
-            if src.passwd.has_changed(&passwd_file_content)
-                | src.shadow.has_changed(&shadow_file_content)
-            {
-                error!("The source files have changed. Deleting the user could corrupt the userdatabase. Aborting!");
-                Err(format!("The userdatabase has been changed {}", args.username).into())
-            } else {
-                Self::delete_from_passwd(user, &passwd_file_content, &mut locked_p)?;
-                Self::delete_from_shadow(user, &shadow_file_content, &mut locked_s)?;
-                if args.delete_home == DeleteHome::Delete {
-                    Self::delete_home(user)?;
+        let gid = match args.group {
+            Some(PrimaryGroup::Gid(gid)) if self.get_group_by_id(gid).is_some() => gid,
+            Some(PrimaryGroup::Gid(gid)) => {
+                return Err(format!("The group with GID {gid} does not exist! Aborting!").into());
+            }
+            Some(PrimaryGroup::Name(name)) => match self.get_group_by_name(name) {
+                Some(group) => group.get_gid().expect("groups have to have a gid"),
+                None => {
+                    return Err(format!("The group {name} does not exist! Aborting!").into());
                 }
-                trace!("The users groups: {:#?}", user.get_groups());
-                // Iterate over the GIDs to avoid borrowing issues
-                let users_groups: Vec<(MembershipKind, u32)> = user
-                    .get_groups()
-                    .iter()
-                    .map(|(k, g)| (*k, g.borrow().get_gid().unwrap()))
-                    .collect();
-                for (kind, group) in users_groups {
-                    trace!("Woring on group: {:?} - {}", kind, group);
-                    match kind {
-                        crate::group::MembershipKind::Primary => {
-                            if self
-                                .get_group_by_id(group)
-                                .expect("The group does not exist")
-                                .borrow()
-                                .get_member_names()
-                                .expect("this group allways has a member")
-                                .len()
-                                == 1
-                            {
-                                trace!(
-                                    "Deleting group as the user to be deleted is the only member {}", self
-                                    .get_group_by_id(group)
-                                    .expect("The group does not exist")
-                                    .borrow()
-                                    .get_groupname().expect("a group has to have a name")
-                                );
-                                Self::delete_from_group(
-                                    self.get_group_by_id(group)
-                                        .expect("The group does not exist"),
-                                    &group_file_content,
-                                    &mut locked_g,
-                                )?;
-                                self.delete_group_by_id(group);
+            },
+            None => match (existing_group, &create_primary_group) {
+                (Some(group), CreatePrimaryGroup::CreateIfEmptyOrAdd) => {
+                    info!("Adding the user to the existing group {}", args.username);
+                    group.get_gid().expect("groups have to have a gid")
+                }
+                _ => DEFAULT_GID,
+            },
+        };
+        Ok((uid, gid, None))
+    }
+
+    fn get_group_by_id_mut(&mut self, id: u32) -> Option<&mut crate::Group> {
+        self.groups.iter_mut().find(|g| g.get_gid() == Some(id))
+    }
+}
+
+impl UserDBWrite for UserDBLocal {
+    fn delete_user(&mut self, args: DeleteUserArgs) -> Result<crate::User, UserLibError> {
+        self.check_writable()?;
+        let home = match self.get_user_by_name(args.username) {
+            Some(user)
+                if args.delete_home != DeleteHome::Keep && !self.source_files.is_virtual() =>
+            {
+                Some(self.home_to_delete(user)?)
+            }
+            Some(_) => None,
+            None => return Err(UserLibError::NotFound),
+        };
+        self.check_primary_group_deletable(&args)?;
+
+        let (groups, subids, deleted_gid) = self.write_files("Deleting the user", |db, files| {
+            let user = db
+                .get_user_by_name(args.username)
+                .ok_or(UserLibError::NotFound)?;
+            // archive the home first so that nothing is changed if that fails.
+            if !db.source_files.is_virtual() {
+                db.archive_home(user, &args.delete_home)?;
+            }
+            Self::delete_from_passwd(args.username, files)?;
+            Self::delete_from_shadow(user, files)?;
+            trace!("The users groups: {:#?}", user.get_groups());
+            // The groups are changed on a copy that replaces them once the files have been written.
+            let mut groups = db.groups.clone();
+            let mut deleted_gid = None;
+            for &(kind, gid) in user.get_groups() {
+                trace!("Woring on group: {:?} - {}", kind, gid);
+                match kind {
+                    crate::group::MembershipKind::Primary => {
+                        let position = groups
+                            .iter()
+                            .position(|g| g.get_gid() == Some(gid))
+                            .expect("The group does not exist");
+                        let group = &mut groups[position];
+                        let delete = match args.delete_primary_group {
+                            DeletePrimaryGroup::Delete => true,
+                            DeletePrimaryGroup::Keep => false,
+                            DeletePrimaryGroup::DeleteIfEmpty => {
+                                group
+                                    .get_member_names()
+                                    .expect("this group allways has a member")
+                                    .len()
+                                    == 1
+                            }
+                        };
+                        if delete {
+                            let groupname = group
+                                .get_groupname()
+                                .expect("a group has to have a name")
+                                .to_owned();
+                            trace!("Deleting the primary group {}", groupname);
+                            Self::delete_from_group(&groupname, files)?;
+                            groups.remove(position);
+                            deleted_gid = Some(gid);
+                        } else {
+                            // only remove the membership if the group is kept or if there are other members.
+                            // Primary memberships are not part of the group file so the file stays as it is.
+                            group.remove_member(MembershipKind::Primary, args.username);
+                            if args.delete_primary_group == DeletePrimaryGroup::Keep {
+                                info!("The primary group (GID: {}) is kept", gid);
                             } else {
-                                // remove the from the group instead of deleting the group if he was not the only user in its primary group.
-                                if let Some(group) = self.get_group_by_id(group) {
-                                    group
-                                        .borrow_mut()
-                                        .remove_member(MembershipKind::Primary, args.username)
-                                };
-                                self.write_groups(&mut locked_g)?;
                                 warn!(
                                     "The primary group (GID: {}) was not empty and is thus not removed. Only the membership has been removed",
-                                    group
+                                    gid
                                 );
                             }
                         }
-                        crate::group::MembershipKind::Member => {
-                            trace!("delete the membership in the group");
-                            if let Some(group) = self.get_group_by_id(group) {
-                                group
-                                    .borrow_mut()
-                                    .remove_member(MembershipKind::Member, args.username)
-                            };
-                            self.write_groups(&mut locked_g)?;
+                    }
+                    crate::group::MembershipKind::Member => {
+                        trace!("delete the membership in the group");
+                        if let Some(group) = groups.iter_mut().find(|g| g.get_gid() == Some(gid)) {
+                            group.remove_member(MembershipKind::Member, args.username);
+                            Self::write_group(group, None, files)?;
                         }
                     }
                 }
-                // Remove the user from the memory database(HashMap)
-                let res = self.users.remove(args.username);
-                match res {
-                    Some(u) => Ok(u),
-                    None => Err("Failed to remove the user from the internal HashMap".into()),
+            }
+            // like userdel remove the user from the gshadow entries of all the groups, not only the ones it is a member of.
+            for group in &mut groups {
+                if group.remove_from_gshadow(args.username) {
+                    Self::write_group(group, None, files)?;
                 }
             }
+            Ok((groups, db.remove_subids(args.username, files)?, deleted_gid))
+        })?;
+        self.groups = groups;
+        // other users may have been members of the deleted primary group.
+        if let Some(gid) = deleted_gid {
+            for user in self.users.values_mut() {
+                user.remove_group(gid);
+            }
+        }
+        (self.subuids, self.subgids) = subids;
+        // Remove the user from the memory database(HashMap) before the home so that it matches the files in any case.
+        let user = self
+            .users
+            .remove(args.username)
+            .ok_or("Failed to remove the user from the internal HashMap")?;
+        if let Some(home) = home {
+            home::remove_home(&home)
+                .and_then(|()| self.remove_mail_spool(args.username))
+                .map_err(|e| UserLibError::HomeFailed(Box::new(e)))?;
         }
+        Ok(user)
     }
 
-    fn new_user(&mut self, args: CreateUserArgs) -> Result<&crate::User, crate::UserLibError> {
-        if self.users.contains_key(args.username) {
-            Err(format!("The username {} already exists! Aborting!", args.username).into())
+    fn new_user(&mut self, args: CreateUserArgs) -> Result<NewUser<'_>, crate::UserLibError> {
+        self.check_writable()?;
+        self.reload_foreign_ids()?;
+        let (mut new_user, mut primary_group) = self.user_from_args(&args)?;
+        // The changed copies replace the groups once the files have been written.
+        let mut supplementary: Vec<crate::Group> = args
+            .groups
+            .iter()
+            .filter_map(|name| self.get_group_by_name(name).cloned())
+            .collect();
+
+        let (uid, gid) = (new_user.get_uid(), new_user.get_gid());
+        let home_dir = std::path::PathBuf::from(
+            new_user
+                .get_home_dir()
+                .expect("new users allways have a home directory"),
+        );
+        // The home is created first so that a failure does not leave a user without one.
+        let new_home = if self.source_files.is_virtual() {
+            None
         } else {
-            let mut new_user = crate::User::default();
-            new_user.username(args.username.to_owned());
-            if self.users.contains_key(args.username) {
-                Err("Failed to create the user. A user with the same Name already exists".into())
+            self.create_new_home(&args, &home_dir, uid, gid)?
+        };
+
+        let subids = self.write_files("Adding the user", |db, files| {
+            info!("Adding passwd entry {}", new_user);
+            files.append_to("passwd", new_user.to_string())?;
+            if let Some(shadow) = new_user.get_shadow() {
+                info!("Adding shadow entry {}", shadow);
+                files.append_to("shadow", shadow.to_string())?;
             } else {
-                let opened = self.source_files.lock_all_get();
-                let (mut locked_p, mut locked_s, mut _locked_g) =
-                    opened.expect("failed to lock files!");
-                //dbg!(&locked_p);
-                locked_p.append(format!("{}", new_user))?;
-                if let Some(shadow) = new_user.get_shadow() {
-                    info!("Adding shadow entry {}", shadow);
-                    locked_s.append(format!("{}", shadow))?;
-                } else {
-                    warn!("Omitting shadow entry!")
+                warn!("Omitting shadow entry!");
+            }
+            for group in &mut supplementary {
+                group.add_member(args.username);
+                Self::write_group(group, None, files)?;
+            }
+            if let Some(ref mut group) = primary_group {
+                info!("Adding group entry {}", group);
+                Self::write_group(group, None, files)?;
+            }
+            if args.system {
+                Ok(None)
+            } else {
+                db.add_subids(args.username, files).map(Some)
+            }
+        });
+        let subids = match subids {
+            Ok(subids) => subids,
+            Err(e) => {
+                if let Some(home) = new_home {
+                    home::remove_new_home(&home);
                 }
-                assert!(self
-                    .users
-                    .insert(args.username.to_owned(), new_user)
-                    .is_none());
-                self.users
-                    .get(args.username)
-                    .map_or_else(|| Err("User was not successfully added!".into()), Ok)
+                return Err(e);
             }
+        };
+        if let Some(subids) = subids {
+            (self.subuids, self.subgids) = subids;
+        }
+
+        // Link the new user to its groups
+        for group in supplementary {
+            let name = group.get_groupname().expect("a group has to have a name");
+            if let Some(existing) = self
+                .groups
+                .iter_mut()
+                .find(|g| g.get_groupname() == Some(name))
+            {
+                new_user.add_group(
+                    MembershipKind::Member,
+                    group.get_gid().expect("groups have to have a gid"),
+                );
+                *existing = group;
+            }
+        }
+        if let Some(group) = primary_group {
+            self.groups.push(group);
+        }
+        if let Some(group) = self.get_group_by_id_mut(new_user.get_gid()) {
+            group.append_user(args.username);
+            new_user.add_group(MembershipKind::Primary, new_user.get_gid());
+        } else {
+            warn!(
+                "The primary group (GID: {}) of the new user does not exist",
+                new_user.get_gid()
+            );
         }
+        assert!(self
+            .users
+            .insert(args.username.to_owned(), new_user)
+            .is_none());
+
+        let foreign_files = if self.source_files.is_virtual() {
+            Vec::new()
+        } else {
+            // An adopted home can not be given back, so if this fails the user has been added without it.
+            self.adopt_new_home(&args, &home_dir, uid, gid)
+                .map_err(|e| UserLibError::HomeFailed(Box::new(e)))?
+        };
+        self.users.get(args.username).map_or_else(
+            || Err("User was not successfully added!".into()),
+            |user| {
+                Ok(NewUser {
+                    user,
+                    foreign_files,
+                })
+            },
+        )
     }
 
-    fn delete_group(&mut self, _group: &crate::Group) -> Result<(), crate::UserLibError> {
-        todo!()
+    fn delete_group(&mut self, args: DeleteGroupArgs) -> Result<crate::Group, UserLibError> {
+        self.check_writable()?;
+        let gid = match self.get_group_by_name(args.groupname) {
+            Some(group) => group.get_gid().expect("groups have to have a gid"),
+            None => return Err(UserLibError::NotFound),
+        };
+
+        // Like groupdel refuse to remove the primary group of an existing user.
+        let primary_of: Vec<&str> = self
+            .users
+            .values()
+            .filter(|u| u.get_gid() == gid)
+            .filter_map(UserRead::get_username)
+            .collect();
+        if !primary_of.is_empty() {
+            if args.force {
+                warn!(
+                    "Removing the group {} although it is the primary group of: {}",
+                    args.groupname,
+                    primary_of.join(", ")
+                );
+            } else {
+                let error_msg = format!(
+                    "The group {} is the primary group of: {}. Aborting!",
+                    args.groupname,
+                    primary_of.join(", ")
+                );
+                error!("{}", error_msg);
+                return Err(error_msg.into());
+            }
+        }
+
+        self.write_files("Deleting the group", |_, files| {
+            Self::delete_from_group(args.groupname, files)
+        })?;
+
+        // Remove the group from the memory database
+        for user in self.users.values_mut() {
+            user.remove_group(gid);
+        }
+        let position = self
+            .groups
+            .iter()
+            .position(|g| g.get_groupname() == Some(args.groupname))
+            .ok_or(UserLibError::NotFound)?;
+        Ok(self.groups.remove(position))
     }
 
-    fn new_group(&mut self) -> Result<&crate::Group, crate::UserLibError> {
-        todo!()
+    fn new_group(&mut self, args: CreateGroupArgs) -> Result<&crate::Group, UserLibError> {
+        self.check_writable()?;
+        self.reload_foreign_ids()?;
+        if !self.is_groupname_valid_and_free(args.groupname) {
+            return Err(format!(
+                "The groupname {} is invalid or already exists! Aborting!",
+                args.groupname
+            )
+            .into());
+        }
+        let gid = match args.gid {
+            Some(gid) if self.is_gid_valid_and_free(gid) => gid,
+            Some(gid) => {
+                return Err(format!("The GID {gid} is already in use! Aborting!").into());
+            }
+            None => self
+                .next_free_gid(args.system)
+                .ok_or("There is no free GID left. Aborting!")?,
+        };
+        for member in &args.members {
+            if self.get_user_by_name(member).is_none() {
+                return Err(format!("The member {member} does not exist! Aborting!").into());
+            }
+        }
+        let mut group = crate::Group::new(args.groupname, gid, &args.members)?;
+
+        self.write_files("Adding the group", |_, files| {
+            info!("Adding group entry {}", group);
+            Self::write_group(&mut group, None, files)
+        })?;
+
+        // Link the members and the users that already had this GID as primary group.
+        for user in self.users.values_mut() {
+            let username = user
+                .get_username()
+                .expect("Users without username are not supported")
+                .to_owned();
+            if args.members.contains(&username.as_str()) {
+                user.add_group(MembershipKind::Member, gid);
+            }
+            if user.get_gid() == gid {
+                group.append_user(&username);
+                user.add_group(MembershipKind::Primary, gid);
+            }
+        }
+        self.groups.push(group);
+        self.groups
+            .last()
+            .map_or_else(|| Err("Group was not successfully added!".into()), Ok)
+    }
+
+    fn rename_group(
+        &mut self,
+        groupname: &str,
+        new_name: &str,
+    ) -> Result<&crate::Group, UserLibError> {
+        self.check_writable()?;
+        let mut group = match self.get_group_by_name(groupname) {
+            Some(group) => group.clone(),
+            None => return Err(UserLibError::NotFound),
+        };
+        if !self.is_groupname_valid_and_free(new_name) {
+            return Err(format!(
+                "The groupname {new_name} is invalid or already exists! Aborting!"
+            )
+            .into());
+        }
+
+        self.write_files("Renaming the group", |_, files| {
+            info!("Renaming the group entry {}", group);
+            group.rename(new_name)?;
+            Self::write_group(&mut group, Some(groupname), files)
+        })?;
+        let existing = self
+            .groups
+            .iter_mut()
+            .find(|g| g.get_groupname() == Some(groupname))
+            .ok_or("Group was not successfully renamed!")?;
+        *existing = group;
+        Ok(existing)
     }
 }
 
@@ -340,13 +1390,13 @@ impl UserDBRead for UserDBLocal {
         None
     }
 
-    fn get_all_groups(&self) -> Vec<crate::Group> {
-        self.groups.iter().map(std::clone::Clone::clone).collect()
+    fn get_all_groups(&self) -> Vec<&crate::Group> {
+        self.groups.iter().collect()
     }
 
     fn get_group_by_name(&self, name: &str) -> Option<&crate::Group> {
         for group in &self.groups {
-            if group.borrow().get_groupname()? == name {
+            if group.get_groupname()? == name {
                 return Some(group);
             }
         }
@@ -355,7 +1405,7 @@ impl UserDBRead for UserDBLocal {
 
     fn get_group_by_id(&self, id: u32) -> Option<&crate::Group> {
         for group in &self.groups {
-            if group.borrow().get_gid()? == id {
+            if group.get_gid()? == id {
                 return Some(group);
             }
         }
@@ -366,22 +1416,28 @@ impl UserDBRead for UserDBLocal {
 use crate::api::UserDBValidation;
 impl UserDBValidation for UserDBLocal {
     fn is_uid_valid_and_free(&self, uid: u32) -> bool {
-        warn!("No valid check, only free check");
-        let free = self.users.iter().all(|(_, u)| u.get_uid() != uid);
-        free
+        // (uid_t)-1 is reserved as error value by the libc functions.
+        let valid = uid != u32::MAX;
+        let free = self.users.iter().all(|(_, u)| u.get_uid() != uid)
+            && !self.invalid_records.uids.contains(&uid)
+            && !self.foreign_ids.uids.contains(&uid);
+        valid && free
     }
 
     fn is_username_valid_and_free(&self, name: &str) -> bool {
         let valid = crate::user::passwd_fields::is_username_valid(name);
-        let free = self.get_user_by_name(name).is_none();
+        let free =
+            self.get_user_by_name(name).is_none() && !self.invalid_records.usernames.contains(name);
         valid && free
     }
 
     fn is_gid_valid_and_free(&self, gid: u32) -> bool {
-        warn!("No valid check, only free check");
-        self.groups
-            .iter()
-            .all(|x| x.borrow().get_gid().unwrap() != gid)
+        // (gid_t)-1 is reserved as error value by the libc functions.
+        let valid = gid != u32::MAX;
+        let free = self.groups.iter().all(|x| x.get_gid().unwrap() != gid)
+            && !self.invalid_records.gids.contains(&gid)
+            && !self.foreign_ids.gids.contains(&gid);
+        valid && free
     }
 
     fn is_groupname_valid_and_free(&self, name: &str) -> bool {
@@ -389,22 +1445,34 @@ impl UserDBValidation for UserDBLocal {
         let free = self
             .groups
             .iter()
-            .all(|x| x.borrow().get_groupname().unwrap() != name);
+            .all(|x| x.get_groupname().unwrap() != name)
+            && !self.invalid_records.groupnames.contains(name);
         valid && free
     }
 }
 
 /// Parse a file to a string
 fn file_to_string(file: &File) -> Result<String, crate::UserLibError> {
-    let mut reader = BufReader::new(file);
+    reader_to_string(BufReader::new(file))
+}
+
+/// Read everything a reader provides to a string
+fn reader_to_string<R: BufRead>(mut reader: R) -> Result<String, crate::UserLibError> {
     let mut lines = String::new();
     let res = reader.read_to_string(&mut lines);
     match res {
         Ok(_) => Ok(lines),
-        Err(e) => Err(format!("failed to read the file: {:?}", e).into()),
+        Err(e) => Err(format!("failed to read the file: {e:?}").into()),
     }
 }
 
+/// Read an optional file to a string. A missing file is read as empty.
+fn optional_file_to_string(
+    locked: Option<&mut files::LockedFileGuard>,
+) -> Result<String, crate::UserLibError> {
+    locked.map_or_else(|| Ok(String::new()), files::LockedFileGuard::read_contents)
+}
+
 fn groups_to_users<'a>(
     users: &'a mut UserList,
     groups: &'a mut [crate::Group],
@@ -412,11 +1480,12 @@ fn groups_to_users<'a>(
     // Populate the regular groups
 
     for group in groups.iter() {
-        match group.borrow().get_member_names() {
+        let gid = group.get_gid().expect("groups have to have a gid");
+        match group.get_member_names() {
             Some(usernames) => {
                 for username in usernames {
                     if let Some(user) = users.get_mut(username) {
-                        user.add_group(crate::group::MembershipKind::Member, group.clone());
+                        user.add_group(crate::group::MembershipKind::Member, gid);
                     }
                 }
             }
@@ -427,17 +1496,16 @@ fn groups_to_users<'a>(
     // Populate the primary membership
     for user in users.values_mut() {
         let gid = user.get_gid();
-        let grouplist: Vec<&crate::Group> = groups
-            .iter()
-            .filter(|g| g.borrow().get_gid().unwrap() == gid)
+        let mut grouplist: Vec<&mut crate::Group> = groups
+            .iter_mut()
+            .filter(|g| g.get_gid().unwrap() == gid)
             .collect();
-        if grouplist.len() == 1 {
-            let group = *grouplist.first().unwrap();
-            group.borrow_mut().append_user(
+        if let [group] = grouplist.as_mut_slice() {
+            group.append_user(
                 user.get_username()
                     .expect("Users without username are not supported"),
             );
-            user.add_group(crate::group::MembershipKind::Primary, group.clone());
+            user.add_group(crate::group::MembershipKind::Primary, gid);
         } else {
             error!(
                 "Somehow the group with gid {} was found {} times",
@@ -450,14 +1518,70 @@ fn groups_to_users<'a>(
 }
 
 /// Merge the Shadow passwords into the users
-fn shadow_to_users(users: &mut UserList, shadow: Vec<crate::Shadow>) -> &mut UserList {
+/// Link the shadow entries to their users. Entries without a user are added to `failures`.
+fn shadow_to_users(
+    users: &mut UserList,
+    shadow: Vec<crate::Shadow>,
+    failures: &mut Vec<ParseFailure>,
+) {
     for pass in shadow {
-        let user = users
-            .get_mut(pass.get_username())
-            .unwrap_or_else(|| panic!("the user {} does not exist", pass.get_username()));
-        user.password = crate::Password::Shadow(pass);
+        if let Some(user) = users.get_mut(pass.get_username()) {
+            user.password = crate::Password::Shadow(pass);
+        } else {
+            failures.push(ParseFailure {
+                file: "shadow".to_owned(),
+                ..ParseFailure::new(ParseError::Username, pass.get_username(), pass.get_pos())
+            });
+        }
+    }
+}
+
+/// Merge the gshadow entries into the groups. Entries without a group are added to `warnings`.
+fn gshadow_to_groups(
+    groups: &mut [crate::Group],
+    gshadow: Vec<crate::GShadow>,
+    warnings: &mut Vec<ParseFailure>,
+) {
+    for entry in gshadow {
+        if let Some(group) = groups
+            .iter_mut()
+            .find(|g| g.get_groupname() == Some(entry.get_groupname()))
+        {
+            group.set_gshadow(Some(entry));
+        } else {
+            warn!(
+                "The gshadow entry of {} has no matching group and is ignored",
+                entry.get_groupname()
+            );
+            warnings.push(ParseFailure {
+                file: "gshadow".to_owned(),
+                ..ParseFailure::new(
+                    ParseError::Groupname,
+                    entry.get_groupname(),
+                    entry.get_pos(),
+                )
+            });
+        }
+    }
+}
+
+/// Add the members of the groups that do not exist to `warnings`.
+fn check_members(users: &UserList, groups: &[crate::Group], warnings: &mut Vec<ParseFailure>) {
+    for group in groups {
+        for name in group.get_member_names().unwrap_or_default() {
+            if !users.contains_key(name) {
+                warn!(
+                    "The member {} of the group {} does not exist",
+                    name,
+                    group.get_groupname().unwrap_or_default()
+                );
+                warnings.push(ParseFailure {
+                    file: "group".to_owned(),
+                    ..ParseFailure::new(ParseError::Members, name, group.get_pos())
+                });
+            }
+        }
     }
-    users
 }
 
 /// Convert a `Vec<crate::User>` to a `UserList` (`HashMap<String, crate::User>`) where the username is used as key
@@ -478,42 +1602,91 @@ fn user_vec_to_hashmap(users: Vec<crate::User>) -> UserList {
 /// Try to parse a String into some Object.
 ///
 /// # Errors
-/// if the parsing failed a [`UserLibError::Message`](crate::userlib_error::UserLibError::Message) is returned containing a more detailed error message.
+/// if the parsing failed a [`UserLibError::Parse`] is returned containing the field and the offending value.
 pub trait NewFromString {
     fn new_from_string(line: String, position: u32) -> Result<Self, crate::UserLibError>
     where
         Self: Sized;
 }
 
+/// Parse a field of the record at `position`.
+///
+/// # Errors
+/// A [`UserLibError::Parse`] naming the field and the offending value.
+pub(crate) fn parse_field<T>(
+    field: ParseError,
+    value: &str,
+    position: u32,
+) -> Result<T, crate::UserLibError>
+where
+    T: std::convert::TryFrom<String, Error = crate::UserLibError>,
+{
+    T::try_from(value.to_owned()).map_err(|e| {
+        debug!("{}", e);
+        ParseFailure::new(field, value, position).into()
+    })
+}
+
 /// A generic function that parses a string line by line and creates the appropriate `Vec<T>` requested by the type system.
-fn string_to<T>(source: &str) -> Vec<T>
+///
+/// Records that cannot be parsed are skipped and the problems are added to `failures`.
+fn string_to<T>(source: &str, file: &str, failures: &mut Vec<ParseFailure>) -> Vec<T>
 where
     T: NewFromString,
 {
-    use std::convert::TryInto;
-    source
-        .lines()
-        .enumerate()
+    use std::convert::TryFrom;
+    // comments and empty lines are skipped. Their position is kept anyway as they are not touched when writing.
+    document::Document::parse(source)
+        .records()
         .filter_map(|(n, line)| {
-            if line.len() > 5 {
-                Some(
-                    T::new_from_string(
-                        line.to_owned(),
-                        n.try_into()
-                            .unwrap_or_else(|e| panic!("Failed to convert usize to u32 {}", e)),
-                    )
-                    .expect("failed to read lines"),
-                )
-            } else {
-                None
+            let position = u32::try_from(n).unwrap_or(u32::MAX);
+            match T::new_from_string(line.to_owned(), position) {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    match e.in_file(file) {
+                        UserLibError::Parse(mut found) => failures.append(&mut found),
+                        other => {
+                            debug!("{}", other);
+                            failures.push(ParseFailure {
+                                file: file.to_owned(),
+                                ..ParseFailure::new(ParseError::Record, line, position)
+                            });
+                        }
+                    }
+                    None
+                }
             }
         })
         .collect()
 }
 
+/// Parse the contents of the passwd, shadow, group and gshadow files and link the records.
+///
+/// Records that cannot be parsed are skipped and the problems are added to `failures`.
+/// Records that refer to missing users or groups are added to `warnings`.
+fn parse_contents(
+    contents: [&str; 4],
+    failures: &mut Vec<ParseFailure>,
+    warnings: &mut Vec<ParseFailure>,
+) -> (UserList, Vec<crate::Group>) {
+    let [passwd, shadow, group, gshadow] = contents;
+    let mut users = user_vec_to_hashmap(string_to(passwd, "passwd", failures));
+    let shadow_entries = string_to(shadow, "shadow", failures);
+    let mut groups = string_to(group, "group", failures);
+    shadow_to_users(&mut users, shadow_entries, failures);
+    gshadow_to_groups(
+        &mut groups,
+        string_to(gshadow, "gshadow", failures),
+        warnings,
+    );
+    check_members(&users, &groups, warnings);
+    groups_to_users(&mut users, &mut groups);
+    (users, groups)
+}
+
 #[test]
 fn test_creator_user_db_local() {
-    let data = UserDBLocal::import_from_strings("test:x:1002:1002:full Name,004,000342,001-2312,myemail@test.com:/home/test:/bin/test", "test:$6$u0Hh.9WKRF1Aeu4g$XqoDyL6Re/4ZLNQCGAXlNacxCxbdigexEqzFzkOVPV5Z1H23hlenjW8ZLgq6GQtFURYwenIFpo1c.r4aW9l5S/:18260:0:99999:7:::", "teste:x:1002:\nanother:x:1003:test");
+    let data = UserDBLocal::import_from_strings("test:x:1002:1002:full Name,004,000342,001-2312,myemail@test.com:/home/test:/bin/test", "test:$6$u0Hh.9WKRF1Aeu4g$XqoDyL6Re/4ZLNQCGAXlNacxCxbdigexEqzFzkOVPV5Z1H23hlenjW8ZLgq6GQtFURYwenIFpo1c.r4aW9l5S/:18260:0:99999:7:::", "teste:x:1002:\nanother:x:1003:test").unwrap();
     assert_eq!(
         data.users.get("test").unwrap().get_username().unwrap(),
         "test"
@@ -524,12 +1697,222 @@ fn test_creator_user_db_local() {
         let (member_group2, group2) = user.get_groups().get(1).unwrap();
 
         assert_eq!(*member_group1, crate::group::MembershipKind::Member);
-        assert_eq!(group1.borrow().get_groupname(), Some("another"));
+        assert_eq!(
+            data.get_group_by_id(*group1).unwrap().get_groupname(),
+            Some("another")
+        );
         assert_eq!(*member_group2, crate::group::MembershipKind::Primary);
-        assert_eq!(group2.borrow().get_groupname(), Some("teste"));
+        assert_eq!(
+            data.get_group_by_id(*group2).unwrap().get_groupname(),
+            Some("teste")
+        );
     }
 }
 
+#[test]
+fn test_parse_failures_are_collected() {
+    let result = UserDBLocal::import_from_strings(
+        "# users\ntest:x:1001:1001::/home/test:/bin/test\nbroken:x:1o02:1002::/home/b:/bin/sh",
+        "test:!:18260:0:99999:7:::\nghost:!:18260:0:99999:7:::",
+        "teste:x:1002:test\nshort:x",
+    );
+    let failure = |file: &str, line, field, value: &str| ParseFailure {
+        file: file.to_owned(),
+        line,
+        field,
+        value: value.to_owned(),
+    };
+    assert_eq!(
+        result.err().unwrap(),
+        UserLibError::Parse(vec![
+            failure("passwd", 3, ParseError::Uid, "1o02"),
+            failure("group", 2, ParseError::Record, "short:x"),
+            failure("shadow", 2, ParseError::Username, "ghost"),
+        ])
+    );
+    // dates that are out of range are reported instead of panicking.
+    let result = UserDBLocal::import_from_strings(
+        "test:x:1001:1001::/home/test:/bin/test",
+        "test:!:999999999999999:0:99999:7:::",
+        "",
+    );
+    assert_eq!(
+        result.err().unwrap(),
+        UserLibError::Parse(vec![failure(
+            "shadow",
+            1,
+            ParseError::LastChange,
+            "999999999999999"
+        )])
+    );
+}
+
+#[test]
+fn test_lenient_loading() {
+    let contents = (
+        "test:x:1001:1001::/home/test:/bin/test\nBroken:x:1002:1002::/home/b:/bin/sh",
+        "test:!:18260:0:99999:7:::\nghost:!:18260:0:99999:7:::",
+        "teste:x:1001:test,missing\nAdmins:x:1003:",
+    );
+    assert!(UserDBLocal::import_from_strings(contents.0, contents.1, contents.2).is_err());
+    let (db, warnings) = UserDBLocal::import_from_strings_with_options(
+        contents.0,
+        contents.1,
+        contents.2,
+        LoadOptions::Lenient,
+    )
+    .unwrap();
+    let warning = |file: &str, line, field, value: &str| ParseFailure {
+        file: file.to_owned(),
+        line,
+        field,
+        value: value.to_owned(),
+    };
+    assert_eq!(
+        warnings,
+        vec![
+            warning("passwd", 2, ParseError::Username, "Broken"),
+            warning("group", 2, ParseError::Groupname, "Admins"),
+            warning("shadow", 2, ParseError::Username, "ghost"),
+            warning("group", 1, ParseError::Members, "missing"),
+        ]
+    );
+    assert_eq!(db.users.len(), 1);
+    assert_eq!(db.groups.len(), 1);
+    // the records that are kept as they are cannot be replaced.
+    assert!(!db.is_username_valid_and_free("ghost"));
+    assert!(!db.is_uid_valid_and_free(1002));
+    assert!(!db.is_gid_valid_and_free(1003));
+    assert!(db.is_uid_valid_and_free(1003));
+}
+
+#[test]
+fn test_in_memory_database() {
+    let mut db = UserDBLocal::import_from_strings(
+        "# users\nroot:x:0:0::/root:/bin/bash\ntest:x:1001:1001::/home/test:/bin/sh\n",
+        "root:!:18260:0:99999:7:::\ntest:!:18260:0:99999:7:::\n",
+        "root:x:0:\ntest:x:1001:\nusers:x:100:\n",
+    )
+    .unwrap();
+    db.new_group(
+        CreateGroupArgs::builder()
+            .groupname("audio")
+            .gid(63)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    db.new_user(
+        CreateUserArgs::builder()
+            .username("fred")
+            .uid(1002)
+            .groups(vec!["audio"])
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    db.rename_group("audio", "sound").unwrap();
+    db.delete_user(DeleteUserArgs::builder().username("test").build().unwrap())
+        .unwrap();
+    let (passwd, shadow, group) = db.export_to_strings();
+    assert_eq!(
+        passwd,
+        "# users\nroot:x:0:0::/root:/bin/bash\nfred:x:1002:1002::/home/fred:/bin/sh\n"
+    );
+    assert!(shadow.starts_with("root:!:18260:0:99999:7:::\nfred:"));
+    assert_eq!(
+        group,
+        "root:x:0:\nusers:x:100:\nsound:x:63:fred\nfred:x:1002:\n"
+    );
+
+    db.delete_group(
+        DeleteGroupArgs::builder()
+            .groupname("sound")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        db.export_to_strings().2,
+        "root:x:0:\nusers:x:100:\nfred:x:1002:\n"
+    );
+    assert_eq!(db.get_user_by_name("fred").unwrap().get_groups().len(), 1);
+}
+
+#[test]
+fn test_readers_and_writers() {
+    let passwd = "root:x:0:0::/root:/bin/bash\n";
+    let shadow = "root:!:18260:0:99999:7:::\n";
+    let group = "root:x:0:\n";
+    let gshadow = "root:!:root:\n";
+    let subuid = "root:100000:65536\n";
+    let (mut db, warnings) = UserDBLocal::load_from_readers(
+        passwd.as_bytes(),
+        std::io::Cursor::new(shadow),
+        BufReader::new(group.as_bytes()),
+        OptionalFiles {
+            gshadow: Some(&mut gshadow.as_bytes()),
+            subuid: Some(&mut subuid.as_bytes()),
+            subgid: None,
+        },
+        LoadOptions::Strict,
+    )
+    .unwrap();
+    assert!(warnings.is_empty());
+    assert_eq!(
+        db.get_group_by_name("root")
+            .unwrap()
+            .get_gshadow()
+            .unwrap()
+            .get_administrator_names(),
+        vec!["root"]
+    );
+    db.new_group(
+        CreateGroupArgs::builder()
+            .groupname("audio")
+            .gid(63)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+
+    let mut written = (Vec::new(), Vec::new(), Vec::new());
+    let (mut written_gshadow, mut written_subuid) = (Vec::new(), Vec::new());
+    // the optional files that have been loaded are required.
+    assert!(db
+        .save_to_writers(
+            &mut written.0,
+            &mut written.1,
+            &mut written.2,
+            OptionalFiles::default()
+        )
+        .is_err());
+    db.save_to_writers(
+        &mut written.0,
+        &mut written.1,
+        &mut written.2,
+        OptionalFiles {
+            gshadow: Some(&mut written_gshadow),
+            subuid: Some(&mut written_subuid),
+            subgid: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(written.0, passwd.as_bytes());
+    assert_eq!(written.1, shadow.as_bytes());
+    assert_eq!(written.2, b"root:x:0:\naudio:x:63:\n");
+    assert_eq!(written_gshadow, b"root:!:root:\naudio:!::\n");
+    assert_eq!(written_subuid, subuid.as_bytes());
+    assert!(UserDBLocal::load_from_readers(
+        &[0xff_u8][..],
+        shadow.as_bytes(),
+        group.as_bytes(),
+        OptionalFiles::default(),
+        LoadOptions::Strict
+    )
+    .is_err());
+}
+
 #[test]
 fn test_parsing_local_database() {
     use std::path::PathBuf;
@@ -538,16 +1921,8 @@ fn test_parsing_local_database() {
     let grpfile = File::open(PathBuf::from("/etc/group")).unwrap();
     let my_passwd_lines = file_to_string(&pwdfile).unwrap();
     let my_group_lines = file_to_string(&grpfile).unwrap();
-    let data = UserDBLocal::import_from_strings(&my_passwd_lines, "", &my_group_lines);
-    assert_eq!(
-        data.groups
-            .get(0)
-            .unwrap()
-            .borrow()
-            .get_groupname()
-            .unwrap(),
-        "root"
-    );
+    let data = UserDBLocal::import_from_strings(&my_passwd_lines, "", &my_group_lines).unwrap();
+    assert_eq!(data.groups.get(0).unwrap().get_groupname().unwrap(), "root");
 }
 
 #[test]
@@ -557,7 +1932,7 @@ fn test_user_db_read_implementation() {
     let grpfile = File::open(PathBuf::from("/etc/group")).unwrap();
     let pass = file_to_string(&pwdfile).unwrap();
     let group = file_to_string(&grpfile).unwrap();
-    let data = UserDBLocal::import_from_strings(&pass, "", &group);
+    let data = UserDBLocal::import_from_strings(&pass, "", &group).unwrap();
     // Usually there are more than 10 users
     assert!(data.get_all_users().len() > 10);
     assert!(data.get_user_by_name("root").is_some());
@@ -569,19 +1944,11 @@ fn test_user_db_read_implementation() {
     assert!(data.get_all_groups().len() > 10);
     assert!(data.get_group_by_name("root").is_some());
     assert_eq!(
-        data.get_group_by_name("root")
-            .unwrap()
-            .borrow()
-            .get_gid()
-            .unwrap(),
+        data.get_group_by_name("root").unwrap().get_gid().unwrap(),
         0
     );
     assert_eq!(
-        data.get_group_by_id(0)
-            .unwrap()
-            .borrow()
-            .get_groupname()
-            .unwrap(),
+        data.get_group_by_id(0).unwrap().get_groupname().unwrap(),
         "root"
     );
     assert!(data.get_user_by_name("norealnameforsure").is_none());
@@ -591,7 +1958,7 @@ fn test_user_db_read_implementation() {
 #[test]
 fn test_user_db_write_implementation() {
     use crate::api::DeleteUserArgs;
-    let mut data = UserDBLocal::import_from_strings("test:x:1001:1001:full Name,004,000342,001-2312,myemail@test.com:/home/test:/bin/test", "test:$6$u0Hh.9WKRF1Aeu4g$XqoDyL6Re/4ZLNQCGAXlNacxCxbdigexEqzFzkOVPV5Z1H23hlenjW8ZLgq6GQtFURYwenIFpo1c.r4aW9l5S/:18260:0:99999:7:::", "teste:x:1002:test,test");
+    let mut data = UserDBLocal::import_from_strings("test:x:1001:1001:full Name,004,000342,001-2312,myemail@test.com:/home/test:/bin/test", "test:$6$u0Hh.9WKRF1Aeu4g$XqoDyL6Re/4ZLNQCGAXlNacxCxbdigexEqzFzkOVPV5Z1H23hlenjW8ZLgq6GQtFURYwenIFpo1c.r4aW9l5S/:18260:0:99999:7:::", "teste:x:1002:test,test").unwrap();
     let user = "test";
 
     assert_eq!(data.get_all_users().len(), 1);
@@ -603,3 +1970,402 @@ fn test_user_db_write_implementation() {
         .is_err());
     assert_eq!(data.get_all_users().len(), 0);
 }
+
+#[test]
+fn test_delete_user_primary_group() {
+    use crate::api::DeleteUserArgs;
+    let mut data = UserDBLocal::import_from_strings(
+        "anna:x:1001:1001::/home/anna:/bin/sh\n\
+         bert:x:1002:1002::/home/bert:/bin/sh\n\
+         carl:x:1003:1003::/home/carl:/bin/sh\n\
+         dora:x:1004:1003::/home/dora:/bin/sh\n\
+         emil:x:1005:1005::/home/emil:/bin/sh\n",
+        "",
+        "anna:x:1001:\nbert:x:1002:carl\ncarl:x:1003:\nemil:x:1005:carl\n",
+    )
+    .unwrap();
+    let delete = |data: &mut UserDBLocal, username, delete_primary_group| {
+        data.delete_user(
+            DeleteUserArgs::builder()
+                .username(username)
+                .delete_primary_group(delete_primary_group)
+                .build()
+                .unwrap(),
+        )
+    };
+
+    delete(&mut data, "anna", DeletePrimaryGroup::Keep).unwrap();
+    assert!(data.get_group_by_name("anna").is_some());
+    // carl is still a member of bert.
+    delete(&mut data, "bert", DeletePrimaryGroup::DeleteIfEmpty).unwrap();
+    assert!(data.get_group_by_name("bert").is_some());
+    delete(&mut data, "emil", DeletePrimaryGroup::Delete).unwrap();
+    assert!(data.get_group_by_name("emil").is_none());
+    assert!(!data
+        .get_user_by_name("carl")
+        .unwrap()
+        .get_groups()
+        .iter()
+        .any(|&(_, gid)| gid == 1005));
+    // the group of carl is the primary group of dora as well.
+    assert!(delete(&mut data, "carl", DeletePrimaryGroup::Delete).is_err());
+    assert!(data.get_user_by_name("carl").is_some());
+    assert_eq!(
+        data.export_to_strings().2,
+        "anna:x:1001:\nbert:x:1002:carl\ncarl:x:1003:\n"
+    );
+}
+
+#[test]
+fn test_group_write_implementation() {
+    use crate::api::{CreateGroupArgs, DeleteGroupArgs};
+    let mut data = UserDBLocal::import_from_strings(
+        "test:x:1001:1001::/home/test:/bin/test",
+        "",
+        "test:x:1001:\nusers:x:100:",
+    )
+    .unwrap();
+    let group = data
+        .new_group(
+            CreateGroupArgs::builder()
+                .groupname("developers")
+                .members(vec!["test"])
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    // the next GID after the highest one in use
+    assert_eq!(group.get_gid(), Some(1002));
+    assert_eq!(group.to_string(), "developers:x:1002:test");
+    let system_group = data
+        .new_group(
+            CreateGroupArgs::builder()
+                .groupname("daemons")
+                .system(true)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    assert_eq!(system_group.get_gid(), Some(999));
+    // name and GID have to be free and the members have to exist.
+    assert!(data
+        .new_group(
+            CreateGroupArgs::builder()
+                .groupname("daemons")
+                .build()
+                .unwrap()
+        )
+        .is_err());
+    assert!(data
+        .new_group(
+            CreateGroupArgs::builder()
+                .groupname("other")
+                .gid(100)
+                .build()
+                .unwrap()
+        )
+        .is_err());
+    assert!(data
+        .new_group(
+            CreateGroupArgs::builder()
+                .groupname("other")
+                .members(vec!["nobody"])
+                .build()
+                .unwrap()
+        )
+        .is_err());
+    assert_eq!(data.get_user_by_name("test").unwrap().get_groups().len(), 2);
+
+    assert!(data
+        .delete_group(
+            DeleteGroupArgs::builder()
+                .groupname("developers")
+                .build()
+                .unwrap()
+        )
+        .is_ok());
+    assert_eq!(data.get_user_by_name("test").unwrap().get_groups().len(), 1);
+    // The primary group of a user is only removed when forced.
+    assert!(data
+        .delete_group(
+            DeleteGroupArgs::builder()
+                .groupname("test")
+                .build()
+                .unwrap()
+        )
+        .is_err());
+    assert!(data
+        .delete_group(
+            DeleteGroupArgs::builder()
+                .groupname("test")
+                .force(true)
+                .build()
+                .unwrap()
+        )
+        .is_ok());
+    assert!(data.get_group_by_name("test").is_none());
+    assert!(data
+        .get_user_by_name("test")
+        .unwrap()
+        .get_groups()
+        .is_empty());
+}
+
+#[test]
+fn test_new_user_with_arguments() {
+    use crate::api::{CreateUserArgs, PrimaryGroup};
+    let mut data = UserDBLocal::import_from_strings(
+        "test:x:1000:100::/home/test:/bin/test",
+        "test:!!:18260:0:99999:7:::",
+        "users:x:100:\nwheel:x:10:\naudio:x:63:test",
+    )
+    .unwrap();
+    let user = data
+        .new_user(
+            CreateUserArgs::builder()
+                .username("fred")
+                .group(PrimaryGroup::Name("wheel"))
+                .groups(vec!["audio"])
+                .gecos("Fred Flintstone,,,")
+                .shell("/bin/bash")
+                .password("$6$xyz")
+                .expire_date(chrono::NaiveDate::from_ymd(2030, 1, 1))
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    assert_eq!(
+        user.to_string(),
+        "fred:x:1001:10:Fred Flintstone,,,:/home/fred:/bin/bash"
+    );
+    let shadow = user.get_shadow().unwrap().to_string();
+    assert!(shadow.starts_with("fred:$6$xyz:"));
+    assert!(shadow.ends_with(":0:99999:7::21915:"));
+    assert_eq!(user.get_groups().len(), 2);
+    assert_eq!(
+        data.get_group_by_name("audio").unwrap().to_string(),
+        "audio:x:63:test,fred"
+    );
+
+    let system_user = data
+        .new_user(
+            CreateUserArgs::builder()
+                .username("daemon")
+                .system(true)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    // a matching primary group is created
+    assert_eq!(system_user.get_uid(), 999);
+    assert_eq!(system_user.get_gid(), 999);
+    assert_eq!(
+        data.get_group_by_name("daemon").unwrap().get_member_names(),
+        Some(vec!["daemon"])
+    );
+
+    // invalid arguments
+    for args in [
+        CreateUserArgs::builder().username("fred").build(),
+        CreateUserArgs::builder().username("bob").uid(1000).build(),
+        CreateUserArgs::builder()
+            .username("bob")
+            .group(PrimaryGroup::Gid(4711))
+            .build(),
+        CreateUserArgs::builder()
+            .username("bob")
+            .groups(vec!["nonexistent"])
+            .build(),
+        CreateUserArgs::builder()
+            .username("bob")
+            .home_dir("relative/home")
+            .build(),
+        CreateUserArgs::builder()
+            .username("bob")
+            .gecos("a:b")
+            .build(),
+    ] {
+        assert!(data.new_user(args.unwrap()).is_err());
+    }
+    assert_eq!(data.get_all_users().len(), 3);
+}
+
+#[test]
+fn test_new_user_primary_group() {
+    use crate::api::{CreatePrimaryGroup, CreateUserArgs};
+    let mut data = UserDBLocal::import_from_strings(
+        "test:x:1000:1000::/home/test:/bin/test",
+        "",
+        "test:x:1000:\nusers:x:100:\nshared:x:1001:\nblocked:x:1002:",
+    )
+    .unwrap();
+    // uid and gid 1001 are not both free so 1003 is the first matching pair.
+    let user = data
+        .new_user(
+            CreateUserArgs::builder()
+                .username("fred")
+                .create_primary_group(CreatePrimaryGroup::Create)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    assert_eq!((user.get_uid(), user.get_gid()), (1003, 1003));
+    let (kind, gid) = *user.get_groups().first().unwrap();
+    assert_eq!(kind, MembershipKind::Primary);
+    assert_eq!(
+        data.get_group_by_id(gid).unwrap().to_string(),
+        "fred:x:1003:"
+    );
+    assert!(data.get_group_by_name("fred").is_some());
+
+    // join the existing group with the same name
+    let user = data
+        .new_user(
+            CreateUserArgs::builder()
+                .username("shared")
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    assert_eq!(user.get_gid(), 1001);
+    assert_eq!(
+        data.get_group_by_name("shared").unwrap().get_member_names(),
+        Some(vec!["shared"])
+    );
+    // Create fails if the group exists already
+    assert!(data
+        .new_user(
+            CreateUserArgs::builder()
+                .username("blocked")
+                .create_primary_group(CreatePrimaryGroup::Create)
+                .build()
+                .unwrap(),
+        )
+        .is_err());
+    // Skip uses the default group
+    let user = data
+        .new_user(
+            CreateUserArgs::builder()
+                .username("bob")
+                .create_primary_group(CreatePrimaryGroup::Skip)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    assert_eq!(user.get_gid(), 100);
+    assert!(data.get_group_by_name("bob").is_none());
+    assert_eq!(data.get_all_groups().len(), 5);
+}
+
+#[test]
+fn test_database_is_thread_safe() {
+    use std::sync::{Arc, RwLock};
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<UserDBLocal>();
+
+    let data = Arc::new(RwLock::new(
+        UserDBLocal::import_from_strings(
+            "test:x:1002:1002::/home/test:/bin/test",
+            "",
+            "teste:x:1002:\nanother:x:1003:test",
+        )
+        .unwrap(),
+    ));
+    let reader = {
+        let data = Arc::clone(&data);
+        std::thread::spawn(move || {
+            let data = data.read().unwrap();
+            let names = data
+                .get_user_by_name("test")
+                .unwrap()
+                .get_groups()
+                .iter()
+                .filter_map(|(_, gid)| data.get_group_by_id(*gid)?.get_groupname())
+                .map(ToOwned::to_owned)
+                .collect::<Vec<String>>();
+            drop(data);
+            names
+        })
+    };
+    assert_eq!(reader.join().unwrap(), vec!["another", "teste"]);
+    data.write()
+        .unwrap()
+        .delete_group(
+            DeleteGroupArgs::builder()
+                .groupname("another")
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    assert_eq!(
+        data.read()
+            .unwrap()
+            .get_user_by_name("test")
+            .unwrap()
+            .get_groups()
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn test_read_only_without_shadow_access() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name);
+    std::fs::write(path("passwd"), "test:x:1002:1002::/home/test:/bin/test\n").unwrap();
+    std::fs::write(path("shadow"), "test:!:18260:0:99999:7:::\n").unwrap();
+    std::fs::write(path("group"), "teste:x:1002:\n").unwrap();
+    // reading the shadow file is not permitted like it is for users other than root.
+    let deny_shadow = |file: Option<&std::path::Path>| match file {
+        Some(file) if file == path("shadow") => {
+            Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        }
+        other => files::read_unlocked(other),
+    };
+    let (data, warnings) = UserDBLocal::load_read_only_with(
+        files::Files {
+            lock_policy: files::LockPolicy::non_blocking(),
+            ..files::Files::new(path("passwd"), path("shadow"), path("group"))
+        },
+        LoadOptions::Strict,
+        deny_shadow,
+    )
+    .unwrap();
+    assert!(warnings.is_empty());
+    let user = data.get_user_by_name("test").unwrap();
+    assert_eq!(*user.get_password_state(), crate::Password::Unknown);
+    assert!(user.get_shadow().is_none());
+    // no lock file has been created.
+    let mut names: Vec<String> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["group", "passwd", "shadow"]);
+}
+
+#[test]
+fn test_new_user_follows_login_defs() {
+    let mut data = UserDBLocal::import_from_strings("", "", "users:x:100:").unwrap();
+    let args = CreateUserArgs::builder().username("fred").build().unwrap();
+    // without a setting the home and the group are created.
+    assert_eq!(data.create_home_for(&args), CreateHome::Create);
+
+    data.set_login_defs(login_defs::LoginDefs::parse(
+        "CREATE_HOME no\nUSERGROUPS_ENAB no\n",
+    ));
+    assert_eq!(data.create_home_for(&args), CreateHome::Skip);
+    assert_eq!(data.new_user(args).unwrap().get_gid(), DEFAULT_GID);
+    assert!(data.get_group_by_name("fred").is_none());
+
+    // explicit choices win over login.defs.
+    let args = CreateUserArgs::builder()
+        .username("anna")
+        .create_home(CreateHome::Create)
+        .create_primary_group(CreatePrimaryGroup::Create)
+        .build()
+        .unwrap();
+    assert_eq!(data.create_home_for(&args), CreateHome::Create);
+    data.new_user(args).unwrap();
+    assert!(data.get_group_by_name("anna").is_some());
+}
@@ -0,0 +1,377 @@
+//! Subordinate UIDs and GIDs as stored in `/etc/subuid` and `/etc/subgid`.
+//!
+//! Every line grants a user (identified by name or UID) a range of IDs: `owner:start:count`.
+//! New ranges are allocated in the first gap that does not overlap any existing range like `useradd` does it.
+use super::allocation::IdRange;
+use super::document::Document;
+use super::{files, UserDBLocal};
+use crate::api::UserRead;
+use crate::error::{ParseError, ParseFailure};
+use crate::UserLibError;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+
+/// A range of subordinate IDs granted to `owner`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubIdRange {
+    owner: String,
+    start: u32,
+    count: u32,
+}
+
+impl SubIdRange {
+    #[must_use]
+    pub fn new(owner: &str, start: u32, count: u32) -> Self {
+        Self {
+            owner: owner.to_owned(),
+            start,
+            count,
+        }
+    }
+
+    /// The name or the UID of the owner.
+    #[must_use]
+    pub fn get_owner(&self) -> &str {
+        &self.owner
+    }
+
+    #[must_use]
+    pub const fn get_start(&self) -> u32 {
+        self.start
+    }
+
+    #[must_use]
+    pub const fn get_count(&self) -> u32 {
+        self.count
+    }
+
+    /// The first ID after the range.
+    const fn end(&self) -> u64 {
+        self.start as u64 + self.count as u64
+    }
+
+    #[must_use]
+    pub const fn overlaps(&self, other: &Self) -> bool {
+        (self.start as u64) < other.end() && (other.start as u64) < self.end()
+    }
+
+    /// Check if the range belongs to the user with the given name or UID.
+    #[must_use]
+    pub fn is_owned_by(&self, username: &str, uid: u32) -> bool {
+        self.owner == username || self.owner == uid.to_string()
+    }
+}
+
+impl SubIdRange {
+    /// Parse a line like `owner:start:count`.
+    pub(super) fn from_line(line: &str) -> Option<Self> {
+        let elements: Vec<&str> = line.trim().split(':').collect();
+        match elements.as_slice() {
+            [owner, start, count] if !owner.is_empty() => Some(Self::new(
+                owner,
+                start.parse::<u32>().ok()?,
+                count.parse::<u32>().ok()?,
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl Display for SubIdRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.owner, self.start, self.count)
+    }
+}
+
+/// The range new subordinate IDs are taken from and the number of IDs every user gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubIdSettings {
+    pub range: IdRange,
+    pub count: u32,
+}
+
+impl Default for SubIdSettings {
+    /// The defaults of shadow-utils.
+    fn default() -> Self {
+        Self {
+            range: IdRange::new(100_000, 600_100_000),
+            count: 65536,
+        }
+    }
+}
+
+/// The content of a `/etc/subuid` or `/etc/subgid` file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubIds {
+    ranges: Vec<SubIdRange>,
+}
+
+impl SubIds {
+    /// Parse the content of a subordinate ID file. Empty lines and comments are skipped.
+    ///
+    /// # Errors
+    /// A [`UserLibError::Parse`] containing all the lines that do not consist of an owner, a start and a count.
+    pub fn parse(content: &str) -> Result<Self, UserLibError> {
+        let (subids, failures) = Self::parse_valid(content);
+        if failures.is_empty() {
+            Ok(subids)
+        } else {
+            Err(UserLibError::Parse(failures))
+        }
+    }
+
+    /// Parse the valid lines of a subordinate ID file and return the problems of all the other lines.
+    #[must_use]
+    pub fn parse_valid(content: &str) -> (Self, Vec<ParseFailure>) {
+        let mut ranges = Vec::new();
+        let mut failures = Vec::new();
+        for (n, line) in Document::parse(content).records() {
+            match SubIdRange::from_line(line) {
+                Some(range) => ranges.push(range),
+                None => failures.push(ParseFailure::new(
+                    ParseError::Record,
+                    line,
+                    u32::try_from(n).unwrap_or(u32::MAX),
+                )),
+            }
+        }
+        (Self { ranges }, failures)
+    }
+
+    #[must_use]
+    pub fn get_ranges(&self) -> &[SubIdRange] {
+        &self.ranges
+    }
+
+    /// All the ranges of the user with the given name or UID.
+    #[must_use]
+    pub fn ranges_of(&self, username: &str, uid: u32) -> Vec<&SubIdRange> {
+        self.ranges
+            .iter()
+            .filter(|r| r.is_owned_by(username, uid))
+            .collect()
+    }
+
+    /// All the pairs of ranges that overlap.
+    #[must_use]
+    pub fn overlaps(&self) -> Vec<(&SubIdRange, &SubIdRange)> {
+        let mut result = Vec::new();
+        for (n, first) in self.ranges.iter().enumerate() {
+            for second in &self.ranges[n + 1..] {
+                if first.overlaps(second) {
+                    result.push((first, second));
+                }
+            }
+        }
+        result
+    }
+
+    /// Find the lowest start of `settings.count` IDs within `settings.range` that do not overlap an existing range.
+    #[must_use]
+    pub fn find_free(&self, settings: SubIdSettings) -> Option<u32> {
+        let count = u64::from(settings.count);
+        let max = u64::from(settings.range.max);
+        let mut used: Vec<&SubIdRange> = self.ranges.iter().collect();
+        used.sort_by_key(|r| r.start);
+        let mut candidate = u64::from(settings.range.min);
+        for range in used {
+            if candidate + count <= u64::from(range.start) {
+                break;
+            }
+            candidate = candidate.max(range.end());
+        }
+        if count > 0 && candidate + count - 1 <= max {
+            u32::try_from(candidate).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Allocate a new range for `owner`.
+    ///
+    /// # Errors
+    /// Fails if there is no free range left.
+    pub fn allocate(
+        &mut self,
+        owner: &str,
+        settings: SubIdSettings,
+    ) -> Result<SubIdRange, UserLibError> {
+        let start = self
+            .find_free(settings)
+            .ok_or("There are no free subordinate IDs left")?;
+        let range = SubIdRange::new(owner, start, settings.count);
+        self.ranges.push(range.clone());
+        Ok(range)
+    }
+
+    /// Allocate a new range for `owner` that does not overlap the `reserved` ranges either.
+    ///
+    /// # Errors
+    /// Fails if there is no free range left.
+    pub fn allocate_besides(
+        &mut self,
+        owner: &str,
+        settings: SubIdSettings,
+        reserved: &Self,
+    ) -> Result<SubIdRange, UserLibError> {
+        let mut taken = self.clone();
+        taken.ranges.extend(reserved.ranges.iter().cloned());
+        let range = taken.allocate(owner, settings)?;
+        self.ranges.push(range.clone());
+        Ok(range)
+    }
+
+    /// Remove all the ranges of the user with the given name or UID. The removed ranges are returned.
+    pub fn remove_owner(&mut self, username: &str, uid: u32) -> Vec<SubIdRange> {
+        let (removed, kept) = self
+            .ranges
+            .drain(..)
+            .partition(|r| r.is_owned_by(username, uid));
+        self.ranges = kept;
+        removed
+    }
+}
+
+impl Display for SubIds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for range in &self.ranges {
+            writeln!(f, "{range}")?;
+        }
+        Ok(())
+    }
+}
+
+impl UserDBLocal {
+    /// The subordinate UID ranges of the user `username`.
+    #[must_use]
+    pub fn get_subuid_ranges(&self, username: &str) -> Vec<&SubIdRange> {
+        let uid = self.users.get(username).map_or(u32::MAX, UserRead::get_uid);
+        self.subuids.ranges_of(username, uid)
+    }
+
+    /// The subordinate GID ranges of the user `username`.
+    #[must_use]
+    pub fn get_subgid_ranges(&self, username: &str) -> Vec<&SubIdRange> {
+        let uid = self.users.get(username).map_or(u32::MAX, UserRead::get_uid);
+        self.subgids.ranges_of(username, uid)
+    }
+
+    #[must_use]
+    pub const fn get_subuids(&self) -> &SubIds {
+        &self.subuids
+    }
+
+    #[must_use]
+    pub const fn get_subgids(&self) -> &SubIds {
+        &self.subgids
+    }
+
+    /// Allocate subordinate UIDs and GIDs for a new regular user like `useradd` does.
+    ///
+    /// Nothing is allocated if the files do not exist or the `SUB_UID_COUNT`/`SUB_GID_COUNT` is 0.
+    /// The updated subordinate UIDs and GIDs are returned to replace the current ones once all the files have been written.
+    pub(super) fn add_subids(
+        &self,
+        username: &str,
+        files: &mut dyn files::EditFiles,
+    ) -> Result<(SubIds, SubIds), UserLibError> {
+        let (mut uid_ranges, mut gid_ranges) = (self.subuids.clone(), self.subgids.clone());
+        if files.contains("subuid") {
+            let settings = self.login_defs.sub_uid_settings();
+            if settings.count > 0 {
+                let range =
+                    uid_ranges.allocate_besides(username, settings, &self.foreign_ids.subuids)?;
+                info!("Adding subordinate UIDs {}", range);
+                files.append_to("subuid", range.to_string())?;
+            }
+        }
+        if files.contains("subgid") {
+            let settings = self.login_defs.sub_gid_settings();
+            if settings.count > 0 {
+                let range =
+                    gid_ranges.allocate_besides(username, settings, &self.foreign_ids.subgids)?;
+                info!("Adding subordinate GIDs {}", range);
+                files.append_to("subgid", range.to_string())?;
+            }
+        }
+        Ok((uid_ranges, gid_ranges))
+    }
+
+    /// Remove all the subordinate UIDs and GIDs of a user that is about to be deleted.
+    ///
+    /// Like [`UserDBLocal::add_subids`] the updated subordinate UIDs and GIDs are returned.
+    pub(super) fn remove_subids(
+        &self,
+        username: &str,
+        files: &mut dyn files::EditFiles,
+    ) -> Result<(SubIds, SubIds), UserLibError> {
+        let (mut uid_ranges, mut gid_ranges) = (self.subuids.clone(), self.subgids.clone());
+        let uid = self.users.get(username).map_or(u32::MAX, UserRead::get_uid);
+        // Only the lines of the user are removed from the files.
+        let owned =
+            |line: &str| SubIdRange::from_line(line).is_some_and(|r| r.is_owned_by(username, uid));
+        if !uid_ranges.remove_owner(username, uid).is_empty() {
+            files.edit_file("subuid", &mut |document| {
+                document.retain(|line| !owned(line));
+            })?;
+        }
+        if !gid_ranges.remove_owner(username, uid).is_empty() {
+            files.edit_file("subgid", &mut |document| {
+                document.retain(|line| !owned(line));
+            })?;
+        }
+        Ok((uid_ranges, gid_ranges))
+    }
+}
+
+#[test]
+fn test_subid_allocation() {
+    let mut subids = SubIds::parse(
+        "# comment\n\
+        dietrich:100000:65536\n\
+        1002:231072:65536\n\
+        teste:300000:1000\n",
+    )
+    .unwrap();
+    assert_eq!(subids.get_ranges().len(), 3);
+    assert_eq!(subids.ranges_of("teste", 1002).len(), 2);
+    assert!(subids.overlaps().is_empty());
+
+    let settings = SubIdSettings::default();
+    // the gap between 165536 and 231072 is exactly big enough.
+    assert_eq!(
+        subids.allocate("bergfried", settings).unwrap().get_start(),
+        165_536
+    );
+    // the gap after 296608 is too small.
+    assert_eq!(
+        subids.allocate("fred", settings).unwrap().get_start(),
+        301_000
+    );
+    assert_eq!(
+        subids.to_string().lines().last().unwrap(),
+        "fred:301000:65536"
+    );
+    let small = SubIdSettings {
+        range: IdRange::new(100_000, 200_000),
+        count: 65536,
+    };
+    assert_eq!(subids.find_free(small), None);
+
+    assert_eq!(subids.remove_owner("teste", 1002).len(), 2);
+    assert_eq!(subids.get_ranges().len(), 3);
+
+    let overlapping = SubIds::parse("a:100000:65536\nb:165535:10\nc:165545:10\n").unwrap();
+    let overlaps = overlapping.overlaps();
+    assert_eq!(overlaps.len(), 1);
+    assert_eq!(overlaps[0].1.get_owner(), "b");
+    assert_eq!(
+        SubIds::parse("a:100000:10\n\na:100000\nb:1:c").unwrap_err(),
+        UserLibError::Parse(vec![
+            ParseFailure::new(ParseError::Record, "a:100000", 2),
+            ParseFailure::new(ParseError::Record, "b:1:c", 3),
+        ])
+    );
+}
@@ -16,17 +16,14 @@ fn test_create_user_function() {
 
     let pf = fs::read_to_string(&p.path).unwrap();
 
-    let mf = umanux::Files {
-        passwd: Some(p.path.clone()),
-        shadow: Some(s.path),
-        group: Some(g.path),
-    };
+    let mf = umanux::Files::new(p.path.clone(), s.path, g.path.clone());
 
     let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
 
-    let user_res: Result<&umanux::User, umanux::UserLibError> = db.new_user(
+    let user_res: Result<umanux::api::NewUser, umanux::UserLibError> = db.new_user(
         umanux::api::CreateUserArgs::builder()
             .username("test2")
+            .create_home(umanux::api::CreateHome::Skip)
             // .delete_home(umanux::api::DeleteHome::Delete)
             .build()
             .unwrap(),
@@ -50,7 +47,123 @@ fn test_create_user_function() {
         .last()
         .unwrap()
         .starts_with("test2"));
+    // a user private group with the same ID has been created.
+    let group_file_string = fs::read_to_string(&g.path).unwrap();
+    assert_eq!(group_file_string.lines().last().unwrap(), "test2:x:1010:");
+    assert!(password_file_string
+        .lines()
+        .last()
+        .unwrap()
+        .starts_with("test2:x:1010:1010:"));
+    // the default shell is used.
+    assert!(password_file_string
+        .lines()
+        .last()
+        .unwrap()
+        .ends_with(":/bin/sh"));
+}
+#[test]
+fn test_create_user_with_arguments() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::UserDBWrite;
+    use umanux::api::UserRead;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+
+    let mf = umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone());
+
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+
+    let user_res: Result<umanux::api::NewUser, umanux::UserLibError> = db.new_user(
+        umanux::api::CreateUserArgs::builder()
+            .username("test3")
+            .create_home(umanux::api::CreateHome::Skip)
+            .uid(1500)
+            .group(umanux::api::PrimaryGroup::Name("bergfried"))
+            .groups(vec!["wheel", "audio"])
+            .gecos("Test User,,,")
+            .home_dir("/srv/test3")
+            .shell("/bin/zsh")
+            .password("$6$salt$hash")
+            .expire_date(chrono::NaiveDate::from_ymd(2030, 1, 1))
+            .build()
+            .unwrap(),
+    );
+    assert_eq!(user_res.unwrap().get_uid(), 1500);
+
+    let passwd_file_string = fs::read_to_string(&p.path).unwrap();
+    let shadow_file_string = fs::read_to_string(&s.path).unwrap();
+    let group_file_string = fs::read_to_string(&g.path).unwrap();
+    assert_eq!(
+        passwd_file_string.lines().last().unwrap(),
+        "test3:x:1500:1009:Test User,,,:/srv/test3:/bin/zsh"
+    );
+    let shadow_line = shadow_file_string.lines().last().unwrap();
+    assert!(shadow_line.starts_with("test3:$6$salt$hash:"));
+    assert!(shadow_line.ends_with(":21915:"));
+    assert!(group_file_string
+        .lines()
+        .any(|l| l == "wheel:x:10:dietrich,test3"));
+    assert!(group_file_string.lines().any(|l| l == "audio:x:63:test3"));
+}
+
+#[test]
+fn test_create_user_login_defs() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::UserDBWrite;
+    use umanux::api::UserRead;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let l = Fixture::copy("login.defs");
+
+    let mf = umanux::Files {
+        login_defs: Some(l.path.clone()),
+        ..umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone())
+    };
+
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+    assert_eq!(db.get_login_defs().pass_max_days(), Some(90));
+
+    let uid = db
+        .new_user(
+            umanux::api::CreateUserArgs::builder()
+                .username("test4")
+                .create_home(umanux::api::CreateHome::Skip)
+                .build()
+                .unwrap(),
+        )
+        .unwrap()
+        .get_uid();
+    assert_eq!(uid, 5000);
+    let system_uid = db
+        .new_user(
+            umanux::api::CreateUserArgs::builder()
+                .username("test5")
+                .create_home(umanux::api::CreateHome::Skip)
+                .system(true)
+                .build()
+                .unwrap(),
+        )
+        .unwrap()
+        .get_uid();
+    assert_eq!(system_uid, 499);
+
+    let shadow_file_string = fs::read_to_string(&s.path).unwrap();
+    assert!(shadow_file_string
+        .lines()
+        .last()
+        .unwrap()
+        .ends_with(":1:90:14:::"));
 }
+
 #[test]
 fn test_create_user_binary() {
     use testfiles::Fixture;
@@ -77,6 +190,7 @@ fn test_create_user_binary() {
             s.path.to_str().unwrap(),
             "-g",
             g.path.to_str().unwrap(),
+            "-M",
         ])
         .output()
         .expect("Failed to run the command");
@@ -121,3 +235,420 @@ fn test_create_user_binary() {
         true
     );
 }
+
+#[test]
+// changing the owner of the new files requires root privileges.
+#[ignore = "requires root"]
+fn test_create_user_home_from_skel() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use umanux::api::UserDBWrite;
+    use umanux::api::UserRead;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let l = Fixture::copy("login.defs");
+    let root = p.path.parent().unwrap().to_owned();
+
+    // prepare a skeleton directory with a file, a subdirectory and a symlink.
+    let skel = root.join("etc/skel");
+    fs::create_dir_all(skel.join(".config")).unwrap();
+    fs::write(skel.join(".bashrc"), "# bashrc\n").unwrap();
+    fs::set_permissions(skel.join(".bashrc"), fs::Permissions::from_mode(0o640)).unwrap();
+    fs::write(skel.join(".config/settings"), "setting=1\n").unwrap();
+    std::os::unix::fs::symlink(".bashrc", skel.join(".profile")).unwrap();
+    fs::write(skel.join("setuid"), "#!/bin/sh\n").unwrap();
+    fs::set_permissions(skel.join("setuid"), fs::Permissions::from_mode(0o6755)).unwrap();
+
+    // the files of the database are inside of the root directory as well.
+    for fixture in &[&s, &g, &l] {
+        fs::copy(&fixture.path, root.join(fixture.path.file_name().unwrap())).unwrap();
+    }
+    let mf = umanux::Files {
+        login_defs: Some("/login.defs".into()),
+        root: Some(root.clone()),
+        ..umanux::Files::new("/passwd", "/shadow", "/group")
+    };
+
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+    let user = db
+        .new_user(
+            umanux::api::CreateUserArgs::builder()
+                .username("test6")
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    let (uid, gid) = (user.get_uid(), user.get_gid());
+
+    let home = root.join("home/test6");
+    // the mode is taken from HOME_MODE in login.defs
+    assert_eq!(fs::metadata(&home).unwrap().mode() & 0o7777, 0o700);
+    assert_eq!(
+        fs::read_to_string(home.join(".bashrc")).unwrap(),
+        "# bashrc\n"
+    );
+    assert_eq!(
+        fs::metadata(home.join(".bashrc")).unwrap().mode() & 0o7777,
+        0o640
+    );
+    assert_eq!(
+        fs::read_to_string(home.join(".config/settings")).unwrap(),
+        "setting=1\n"
+    );
+    assert_eq!(
+        fs::read_link(home.join(".profile")).unwrap(),
+        std::path::PathBuf::from(".bashrc")
+    );
+    // the setuid and setgid bits are not given to the new user.
+    assert_eq!(
+        fs::metadata(home.join("setuid")).unwrap().mode() & 0o7777,
+        0o755
+    );
+    for entry in &["", ".bashrc", ".config", ".config/settings", "setuid"] {
+        let metadata = fs::symlink_metadata(home.join(entry)).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+    }
+
+    // an existing home directory is not touched
+    let user = db
+        .new_user(
+            umanux::api::CreateUserArgs::builder()
+                .username("test7")
+                .home_dir("/home/test6")
+                .create_primary_group(umanux::api::CreatePrimaryGroup::Skip)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    assert_ne!(user.get_uid(), uid);
+    assert_eq!(fs::symlink_metadata(&home).unwrap().uid(), uid);
+
+    // skipping the creation does not create any directory
+    db.new_user(
+        umanux::api::CreateUserArgs::builder()
+            .username("test8")
+            .create_home(umanux::api::CreateHome::Skip)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    assert!(!root.join("home/test8").exists());
+}
+
+#[test]
+fn test_create_user_home_failure() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::UserDBRead;
+    use umanux::api::UserDBWrite;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let root = p.path.parent().unwrap().to_owned();
+    for fixture in &[&s, &g] {
+        fs::copy(&fixture.path, root.join(fixture.path.file_name().unwrap())).unwrap();
+    }
+    // the home directory can not be created as its parent is a file.
+    fs::write(root.join("home"), "").unwrap();
+    let pf = fs::read_to_string(&p.path).unwrap();
+    let gf = fs::read_to_string(root.join("group")).unwrap();
+
+    let mf = umanux::Files {
+        root: Some(root.clone()),
+        ..umanux::Files::new("/passwd", "/shadow", "/group")
+    };
+
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+    assert!(db
+        .new_user(
+            umanux::api::CreateUserArgs::builder()
+                .username("test9")
+                .create_home(umanux::api::CreateHome::Create)
+                .build()
+                .unwrap(),
+        )
+        .is_err());
+    // neither the files nor the database contain the user.
+    assert_eq!(fs::read_to_string(&p.path).unwrap(), pf);
+    assert_eq!(fs::read_to_string(root.join("group")).unwrap(), gf);
+    assert!(db.get_user_by_name("test9").is_none());
+    assert!(db.get_group_by_name("test9").is_none());
+}
+
+#[test]
+// changing the owner of the new home requires root privileges.
+#[ignore = "requires root"]
+fn test_create_user_conflict_removes_home() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use std::io::Write;
+    use umanux::api::UserDBRead;
+    use umanux::api::UserDBWrite;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let root = p.path.parent().unwrap().to_owned();
+    for fixture in &[&s, &g] {
+        fs::copy(&fixture.path, root.join(fixture.path.file_name().unwrap())).unwrap();
+    }
+    fs::create_dir(root.join("home")).unwrap();
+
+    let mf = umanux::Files {
+        root: Some(root.clone()),
+        ..umanux::Files::new("/passwd", "/shadow", "/group")
+    };
+
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+    // another tool adds a user with the same name after the files have been read.
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&p.path)
+        .unwrap()
+        .write_all(b"\ntest10:x:1500:100::/home/test10:/bin/sh")
+        .unwrap();
+    assert!(matches!(
+        db.new_user(
+            umanux::api::CreateUserArgs::builder()
+                .username("test10")
+                .create_home(umanux::api::CreateHome::Create)
+                .build()
+                .unwrap(),
+        ),
+        Err(umanux::UserLibError::FilesChanged(_))
+    ));
+    assert!(db.get_user_by_name("test10").is_none());
+    // the home that was created for the user is removed again.
+    assert!(root.join("home").exists());
+    assert!(!root.join("home/test10").exists());
+}
+
+#[test]
+fn test_create_user_avoids_ids_of_concurrent_changes() {
+    use testfiles::Fixture;
+
+    use std::collections::HashSet;
+    use std::fs;
+    use std::io::Write;
+    use umanux::api::{UserDBWrite, UserRead};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let su = Fixture::copy("subuid");
+
+    let mf = umanux::Files {
+        subuid: Some(su.path.clone()),
+        ..umanux::Files::new(p.path.clone(), s.path, g.path)
+    };
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+    // another tool takes the next free UID and subordinate UIDs after the files have been read.
+    for (path, record) in &[
+        (&p.path, "\nother:x:1010:100::/home/other:/bin/sh"),
+        (&su.path, "other:231072:65536\n"),
+    ] {
+        fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(record.as_bytes())
+            .unwrap();
+    }
+    for name in &["fred", "anna"] {
+        let user = db
+            .new_user(
+                umanux::api::CreateUserArgs::builder()
+                    .username(name)
+                    .create_home(umanux::api::CreateHome::Skip)
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_ne!(user.get_uid(), 1010);
+    }
+    let passwd = fs::read_to_string(&p.path).unwrap();
+    let uids: Vec<&str> = passwd
+        .lines()
+        .filter_map(|line| line.split(':').nth(2))
+        .collect();
+    assert_eq!(uids.iter().collect::<HashSet<_>>().len(), uids.len());
+    assert!(passwd.contains("other:x:1010:"));
+    let subuids =
+        umanux::userlib::subid::SubIds::parse(&fs::read_to_string(&su.path).unwrap()).unwrap();
+    assert!(subuids.overlaps().is_empty());
+    assert_eq!(subuids.get_ranges().len(), 5);
+}
+
+#[test]
+// changing the owner of the files requires root privileges.
+#[ignore = "requires root"]
+fn test_create_user_home_from_dir() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+    use umanux::api::{CreateHome, ForeignOwners, HomeTransfer, UserDBRead, UserDBWrite, UserRead};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let root = p.path.parent().unwrap().to_owned();
+
+    // a restored home of a previous user (4321) containing a setuid file of another user (777).
+    let prepare = |dir: &std::path::Path| {
+        fs::create_dir_all(dir.join("documents")).unwrap();
+        fs::write(dir.join("documents/letter"), "hello").unwrap();
+        fs::write(dir.join("shared"), "shared").unwrap();
+        for entry in &["", "documents", "documents/letter"] {
+            chown(dir.join(entry), Some(4321), Some(4321)).unwrap();
+        }
+        chown(dir.join("shared"), Some(777), Some(777)).unwrap();
+        fs::set_permissions(dir.join("shared"), fs::Permissions::from_mode(0o4755)).unwrap();
+    };
+    prepare(&root.join("backup/moved"));
+    prepare(&root.join("backup/copied"));
+
+    // the files of the database are inside of the root directory as well.
+    for fixture in &[&s, &g] {
+        fs::copy(&fixture.path, root.join(fixture.path.file_name().unwrap())).unwrap();
+    }
+    let mf = umanux::Files {
+        root: Some(root.clone()),
+        ..umanux::Files::new("/passwd", "/shadow", "/group")
+    };
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+
+    let user = db
+        .new_user(
+            umanux::api::CreateUserArgs::builder()
+                .username("moved")
+                .create_home(CreateHome::HomeFromDir {
+                    path: "/backup/moved".into(),
+                    transfer: HomeTransfer::Move,
+                    foreign_owners: ForeignOwners::Report,
+                })
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    let (uid, gid) = (user.get_uid(), user.get_gid());
+    let home = root.join("home/moved");
+    // the file of the other user is reported.
+    assert_eq!(user.foreign_files, vec![home.join("shared")]);
+    assert!(!root.join("backup/moved").exists());
+    for entry in &["", "documents", "documents/letter"] {
+        let metadata = fs::metadata(home.join(entry)).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+    }
+    let metadata = fs::metadata(home.join("shared")).unwrap();
+    assert_eq!((metadata.uid(), metadata.mode() & 0o7777), (777, 0o4755));
+
+    let user = db
+        .new_user(
+            umanux::api::CreateUserArgs::builder()
+                .username("copied")
+                .create_home(CreateHome::HomeFromDir {
+                    path: "/backup/copied".into(),
+                    transfer: HomeTransfer::Copy,
+                    foreign_owners: ForeignOwners::Fix,
+                })
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    let uid = user.get_uid();
+    let home = root.join("home/copied");
+    assert_eq!(user.foreign_files, vec![home.join("shared")]);
+    assert_eq!(
+        fs::read_to_string(home.join("documents/letter")).unwrap(),
+        "hello"
+    );
+    // the setuid bit is not given to the new owner.
+    let metadata = fs::metadata(home.join("shared")).unwrap();
+    assert_eq!((metadata.uid(), metadata.mode() & 0o7777), (uid, 0o755));
+    // the source is untouched
+    assert_eq!(
+        fs::metadata(root.join("backup/copied/documents/letter"))
+            .unwrap()
+            .uid(),
+        4321
+    );
+
+    // a missing source directory is rejected before the user is created.
+    assert!(db
+        .new_user(
+            umanux::api::CreateUserArgs::builder()
+                .username("missing")
+                .create_home(CreateHome::HomeFromDir {
+                    path: "/backup/missing".into(),
+                    transfer: HomeTransfer::Move,
+                    foreign_owners: ForeignOwners::Report,
+                })
+                .build()
+                .unwrap(),
+        )
+        .is_err());
+    assert!(db.get_user_by_name("missing").is_none());
+}
+
+#[test]
+fn test_create_user_subordinate_ids() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::UserDBWrite;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let su = Fixture::copy("subuid");
+    let sg = Fixture::copy("subgid");
+
+    let mf = umanux::Files {
+        subuid: Some(su.path.clone()),
+        subgid: Some(sg.path.clone()),
+        ..umanux::Files::new(p.path.clone(), s.path, g.path)
+    };
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+    // teste owns its subordinate UIDs by UID and its subordinate GIDs by name.
+    assert_eq!(db.get_subuid_ranges("teste").len(), 1);
+    assert_eq!(db.get_subgid_ranges("teste").len(), 1);
+
+    db.new_user(
+        umanux::api::CreateUserArgs::builder()
+            .username("test2")
+            .create_home(umanux::api::CreateHome::Skip)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    let ranges = db.get_subuid_ranges("test2");
+    assert_eq!(ranges.len(), 1);
+    assert_eq!(ranges[0].get_start(), 231_072);
+    assert_eq!(ranges[0].get_count(), 65536);
+    assert!(db.get_subuids().overlaps().is_empty());
+    for path in &[&su.path, &sg.path] {
+        let content = fs::read_to_string(path).unwrap();
+        assert_eq!(content.lines().last().unwrap(), "test2:231072:65536");
+    }
+
+    // system users do not get subordinate IDs.
+    db.new_user(
+        umanux::api::CreateUserArgs::builder()
+            .username("daemon2")
+            .system(true)
+            .create_home(umanux::api::CreateHome::Skip)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    assert!(db.get_subuid_ranges("daemon2").is_empty());
+    assert_eq!(fs::read_to_string(&su.path).unwrap().lines().count(), 3);
+}
@@ -15,11 +15,7 @@ fn test_delete_user_function() {
 
     let pf = fs::read_to_string(&p.path).unwrap();
 
-    let mf = umanux::Files {
-        passwd: Some(p.path.clone()),
-        shadow: Some(s.path),
-        group: Some(g.path.clone()),
-    };
+    let mf = umanux::Files::new(p.path.clone(), s.path, g.path.clone());
 
     let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
 
@@ -51,3 +47,356 @@ fn test_delete_user_function() {
         assert!(!line.ends_with("teste"))
     }
 }
+
+#[test]
+// changing the owner of the files requires root privileges.
+#[ignore = "requires root"]
+fn test_delete_user_archive_home() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use umanux::api::{ArchiveCompression, DeleteHome, UserDBWrite};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let root = p.path.parent().unwrap().to_owned();
+
+    let home = root.join("home/teste");
+    fs::create_dir_all(home.join("documents")).unwrap();
+    fs::write(home.join("documents/letter"), "hello").unwrap();
+    std::os::unix::fs::symlink("documents/letter", home.join("link")).unwrap();
+    std::os::unix::fs::chown(home.join("documents/letter"), Some(1002), Some(1002)).unwrap();
+    // not every filesystem supports extended attributes.
+    let has_xattr = xattr::set(home.join("documents/letter"), "user.test", b"value").is_ok();
+    fs::create_dir(root.join("archive")).unwrap();
+
+    // the files of the database are inside of the root directory as well.
+    for fixture in &[&s, &g] {
+        fs::copy(&fixture.path, root.join(fixture.path.file_name().unwrap())).unwrap();
+    }
+    let mf = umanux::Files {
+        root: Some(root.clone()),
+        ..umanux::Files::new("/passwd", "/shadow", "/group")
+    };
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+
+    // a missing archive directory aborts the deletion.
+    assert!(db
+        .delete_user(
+            umanux::api::DeleteUserArgs::builder()
+                .username("teste")
+                .delete_home(DeleteHome::Archive {
+                    path: "/missing".into(),
+                    compression: ArchiveCompression::None,
+                })
+                .build()
+                .unwrap(),
+        )
+        .is_err());
+    assert!(home.exists());
+    assert!(fs::read_to_string(&p.path).unwrap().contains("teste:"));
+
+    db.delete_user(
+        umanux::api::DeleteUserArgs::builder()
+            .username("teste")
+            .delete_home(DeleteHome::Archive {
+                path: "/archive".into(),
+                compression: ArchiveCompression::Gzip,
+            })
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    assert!(!home.exists());
+
+    let archives: Vec<_> = fs::read_dir(root.join("archive"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    assert_eq!(archives.len(), 1);
+    let archive_name = archives[0].file_name().unwrap().to_str().unwrap();
+    assert!(archive_name.starts_with("teste-1002-"));
+    assert!(archive_name.ends_with(".tar.gz"));
+    // the archive is as private as the home directory.
+    assert_eq!(
+        fs::metadata(&archives[0]).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(
+        fs::File::open(&archives[0]).unwrap(),
+    ));
+    let mut names = Vec::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().into_owned();
+        if path == std::path::Path::new("teste/documents/letter") {
+            assert_eq!(entry.header().uid().unwrap(), 1002);
+            if has_xattr {
+                let extensions = entry.pax_extensions().unwrap().unwrap();
+                assert!(extensions.map(Result::unwrap).any(|e| e.key()
+                    == Ok("SCHILY.xattr.user.test")
+                    && e.value_bytes() == b"value"));
+            }
+        }
+        if path == std::path::Path::new("teste/link") {
+            assert_eq!(entry.header().entry_type(), tar::EntryType::Symlink);
+            assert_eq!(
+                entry.link_name().unwrap().unwrap(),
+                std::path::Path::new("documents/letter")
+            );
+        }
+        names.push(path);
+    }
+    assert_eq!(
+        names,
+        [
+            "teste",
+            "teste/documents",
+            "teste/documents/letter",
+            "teste/link"
+        ]
+        .iter()
+        .map(std::path::PathBuf::from)
+        .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_delete_user_subordinate_ids() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::UserDBWrite;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let su = Fixture::copy("subuid");
+    let sg = Fixture::copy("subgid");
+
+    let mf = umanux::Files {
+        subuid: Some(su.path.clone()),
+        subgid: Some(sg.path.clone()),
+        ..umanux::Files::new(p.path, s.path, g.path)
+    };
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+    db.delete_user(
+        umanux::api::DeleteUserArgs::builder()
+            .username("teste")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    assert!(db.get_subuid_ranges("teste").is_empty());
+    for path in &[&su.path, &sg.path] {
+        let content = fs::read_to_string(path).unwrap();
+        assert_eq!(content.trim_end(), "dietrich:100000:65536");
+    }
+}
+
+#[test]
+fn test_delete_user_merges_concurrent_changes() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::{UserDBRead, UserDBWrite};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let su = Fixture::copy("subuid");
+    let files = || umanux::Files {
+        subuid: Some(su.path.clone()),
+        ..umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone())
+    };
+    let mut db = umanux::UserDBLocal::load_files(files()).unwrap();
+
+    // another tool adds a group and changes the shell of another user after the files have been read.
+    let mut group = fs::read_to_string(&g.path).unwrap();
+    group.push_str("\nconcurrent:x:4242:");
+    fs::write(&g.path, &group).unwrap();
+    let passwd = fs::read_to_string(&p.path).unwrap().replace(
+        "test:x:1001:1015:Long,007,1499990,1999201,AKK:/home/test:/bin/bash",
+        "test:x:1001:1015:Long,007,1499990,1999201,AKK:/home/test:/bin/zsh",
+    );
+    fs::write(&p.path, &passwd).unwrap();
+    db.delete_user(
+        umanux::api::DeleteUserArgs::builder()
+            .username("teste")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    let passwd = fs::read_to_string(&p.path).unwrap();
+    assert!(passwd.contains("/home/test:/bin/zsh"));
+    assert!(!passwd.contains("teste:"));
+    let group = fs::read_to_string(&g.path).unwrap();
+    assert!(group.contains("concurrent:x:4242:"));
+    assert!(group.contains("\nteste:x:1002:test\n"));
+    assert_eq!(
+        fs::read_to_string(&su.path).unwrap().trim_end(),
+        "dietrich:100000:65536"
+    );
+
+    // the following writes keep the concurrent changes as well.
+    db.new_group(
+        umanux::api::CreateGroupArgs::builder()
+            .groupname("later")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    let group = fs::read_to_string(&g.path).unwrap();
+    assert!(group.contains("concurrent:x:4242:"));
+    assert!(group.contains("later:x:"));
+
+    // changing a record that is deleted is a conflict and nothing is written.
+    let mut subuid = fs::read_to_string(&su.path).unwrap();
+    subuid.push_str("bergfried:200000:65536\n");
+    fs::write(&su.path, &subuid).unwrap();
+    let mut db = umanux::UserDBLocal::load_files(files()).unwrap();
+    let passwd = fs::read_to_string(&p.path).unwrap().replace(
+        "bergfried:x:1009:1009:Bergfried Freiberg,,,,:/home/bergfried:/bin/bash",
+        "bergfried:x:1009:1009:Bergfried Freiberg,,,,:/home/bergfried:/bin/zsh",
+    );
+    fs::write(&p.path, &passwd).unwrap();
+    let shadow = fs::read_to_string(&s.path).unwrap();
+    let result = db.delete_user(
+        umanux::api::DeleteUserArgs::builder()
+            .username("bergfried")
+            .build()
+            .unwrap(),
+    );
+    assert_eq!(
+        result.unwrap_err(),
+        umanux::UserLibError::FilesChanged(vec![umanux::MergeConflict {
+            file: "passwd".to_owned(),
+            original: Some(
+                "bergfried:x:1009:1009:Bergfried Freiberg,,,,:/home/bergfried:/bin/bash".to_owned()
+            ),
+            ours: None,
+            theirs: Some(
+                "bergfried:x:1009:1009:Bergfried Freiberg,,,,:/home/bergfried:/bin/zsh".to_owned()
+            ),
+        }])
+    );
+    assert_eq!(fs::read_to_string(&p.path).unwrap(), passwd);
+    assert_eq!(fs::read_to_string(&s.path).unwrap(), shadow);
+    assert_eq!(fs::read_to_string(&su.path).unwrap(), subuid);
+    // the database still matches the files.
+    assert!(db.get_user_by_name("bergfried").is_some());
+    assert!(db.get_group_by_id(1009).is_some());
+    assert_eq!(db.get_subuid_ranges("bergfried").len(), 1);
+
+    // the same holds for adding a user that another tool has added as well.
+    let passwd = format!("{passwd}\nfred:x:1500:100::/home/fred:/bin/sh");
+    fs::write(&p.path, &passwd).unwrap();
+    let wheel = db.get_group_by_name("wheel").unwrap().clone();
+    assert!(matches!(
+        db.new_user(
+            umanux::api::CreateUserArgs::builder()
+                .username("fred")
+                .groups(vec!["wheel"])
+                .build()
+                .unwrap(),
+        ),
+        Err(umanux::UserLibError::FilesChanged(_))
+    ));
+    assert_eq!(fs::read_to_string(&p.path).unwrap(), passwd);
+    assert_eq!(fs::read_to_string(&su.path).unwrap(), subuid);
+    assert!(db.get_user_by_name("fred").is_none());
+    assert!(db.get_group_by_name("fred").is_none());
+    assert_eq!(db.get_group_by_name("wheel"), Some(&wheel));
+    assert!(db.get_subuid_ranges("fred").is_empty());
+}
+
+#[test]
+fn test_delete_user_keeps_the_root_directory() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::{DeleteHome, UserDBWrite};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let root = p.path.parent().unwrap().to_owned();
+    for fixture in &[&s, &g] {
+        fs::copy(&fixture.path, root.join(fixture.path.file_name().unwrap())).unwrap();
+    }
+    // one home is the root directory, the other one leads there through a symlink.
+    let mut passwd = fs::read_to_string(&p.path).unwrap();
+    passwd.push_str("\nslash:x:1500:100::/:/bin/sh\nescape:x:1501:100::/home/escape:/bin/sh");
+    fs::write(&p.path, &passwd).unwrap();
+    fs::create_dir(root.join("home")).unwrap();
+    std::os::unix::fs::symlink("/", root.join("home/escape")).unwrap();
+
+    let mut db = umanux::UserDBLocal::load_files(umanux::Files {
+        root: Some(root.clone()),
+        ..umanux::Files::new("/passwd", "/shadow", "/group")
+    })
+    .unwrap();
+    for username in &["slash", "escape"] {
+        assert!(db
+            .delete_user(
+                umanux::api::DeleteUserArgs::builder()
+                    .username(username)
+                    .delete_home(DeleteHome::Delete)
+                    .build()
+                    .unwrap(),
+            )
+            .is_err());
+    }
+    // nothing has been changed.
+    assert_eq!(fs::read_to_string(&p.path).unwrap(), passwd);
+    assert!(root.join("shadow").exists());
+    assert!(root.join("home/escape").exists());
+}
+
+#[test]
+fn test_delete_user_without_home() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::{DeleteHome, UserDBRead, UserDBWrite};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let root = p.path.parent().unwrap().to_owned();
+    for fixture in &[&s, &g] {
+        fs::copy(&fixture.path, root.join(fixture.path.file_name().unwrap())).unwrap();
+    }
+    // the home of teste does not exist and the home of test is a file that cannot be removed as a directory.
+    fs::create_dir(root.join("home")).unwrap();
+    fs::write(root.join("home/test"), "").unwrap();
+
+    let mut db = umanux::UserDBLocal::load_files(umanux::Files {
+        root: Some(root.clone()),
+        ..umanux::Files::new("/passwd", "/shadow", "/group")
+    })
+    .unwrap();
+    let delete = |username| {
+        umanux::api::DeleteUserArgs::builder()
+            .username(username)
+            .delete_home(DeleteHome::Delete)
+            .build()
+            .unwrap()
+    };
+    // like userdel a missing home is no error.
+    db.delete_user(delete("teste")).unwrap();
+    assert!(db.get_user_by_name("teste").is_none());
+
+    // the user is deleted from the files and the database even if the home cannot be removed.
+    assert!(matches!(
+        db.delete_user(delete("test")),
+        Err(umanux::UserLibError::HomeFailed(_))
+    ));
+    assert!(db.get_user_by_name("test").is_none());
+    let passwd = fs::read_to_string(&p.path).unwrap();
+    assert!(!passwd.contains("\nteste:"));
+    assert!(!passwd.contains("\ntest:"));
+}
@@ -0,0 +1,448 @@
+extern crate umanux;
+mod testfiles;
+
+#[test]
+fn test_atomic_replace() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use umanux::userlib::files::LockedFileGuard;
+
+    let p = Fixture::copy("passwd");
+    fs::set_permissions(&p.path, fs::Permissions::from_mode(0o640)).unwrap();
+    xattr::set(&p.path, "user.umanux", b"label").unwrap();
+    let inode = fs::metadata(&p.path).unwrap().ino();
+
+    let mut guard = LockedFileGuard::new(&p.path).unwrap();
+    guard
+        .replace_contents("root:x:0:0::/root:/bin/bash".to_owned())
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(&p.path).unwrap(),
+        "root:x:0:0::/root:/bin/bash\n"
+    );
+    // the file has been replaced instead of being truncated.
+    assert_ne!(fs::metadata(&p.path).unwrap().ino(), inode);
+    guard
+        .append("test:x:1000:1000::/home/test:/bin/bash".to_owned())
+        .unwrap();
+    assert_eq!(
+        guard.read_contents().unwrap(),
+        "root:x:0:0::/root:/bin/bash\ntest:x:1000:1000::/home/test:/bin/bash\n"
+    );
+
+    // the mode and the extended attributes are kept.
+    let metadata = fs::metadata(&p.path).unwrap();
+    assert_eq!(metadata.mode() & 0o7777, 0o640);
+    assert_eq!(
+        xattr::get(&p.path, "user.umanux").unwrap(),
+        Some(b"label".to_vec())
+    );
+    assert!(!p.path.with_file_name("passwd+").exists());
+}
+
+#[test]
+fn test_failed_write_keeps_the_original() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::userlib::files::LockedFileGuard;
+
+    let p = Fixture::copy("passwd");
+    let original = fs::read_to_string(&p.path).unwrap();
+    // the temporary file cannot be created if there is a directory in the way.
+    fs::create_dir(p.path.with_file_name("passwd+")).unwrap();
+
+    let mut guard = LockedFileGuard::new(&p.path).unwrap();
+    assert!(guard.replace_contents(String::new()).is_err());
+    assert!(guard
+        .append("test:x:1000:1000::/home/test:/bin/bash".to_owned())
+        .is_err());
+    assert_eq!(fs::read_to_string(&p.path).unwrap(), original);
+    assert_eq!(guard.read_contents().unwrap(), original);
+}
+
+#[test]
+fn test_backup_files() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use umanux::api::UserDBWrite;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    fs::set_permissions(&s.path, fs::Permissions::from_mode(0o600)).unwrap();
+    let original_passwd = fs::read_to_string(&p.path).unwrap();
+    let original_shadow = fs::read_to_string(&s.path).unwrap();
+    let original_group = fs::read_to_string(&g.path).unwrap();
+
+    let mf = umanux::Files {
+        backup: true,
+        ..umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone())
+    };
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+    db.new_user(
+        umanux::api::CreateUserArgs::builder()
+            .username("test2")
+            // the group file is written once for every group.
+            .groups(vec!["audio", "video"])
+            .create_home(umanux::api::CreateHome::Skip)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+
+    let shadow_backup = s.path.with_file_name("shadow-");
+    assert_eq!(
+        fs::read_to_string(p.path.with_file_name("passwd-")).unwrap(),
+        original_passwd
+    );
+    assert_eq!(fs::read_to_string(&shadow_backup).unwrap(), original_shadow);
+    assert_eq!(fs::metadata(&shadow_backup).unwrap().mode() & 0o7777, 0o600);
+    assert_eq!(
+        fs::read_to_string(g.path.with_file_name("group-")).unwrap(),
+        original_group
+    );
+
+    // the backup always contains the content before the last operation.
+    let created_passwd = fs::read_to_string(&p.path).unwrap();
+    db.delete_user(
+        umanux::api::DeleteUserArgs::builder()
+            .username("test2")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        fs::read_to_string(p.path.with_file_name("passwd-")).unwrap(),
+        created_passwd
+    );
+}
+
+#[test]
+fn test_no_backup_files() {
+    use testfiles::Fixture;
+
+    use umanux::userlib::files::LockedFileGuard;
+
+    let p = Fixture::copy("passwd");
+    let mut guard = LockedFileGuard::new(&p.path).unwrap().with_backup(false);
+    guard.replace_contents(String::new()).unwrap();
+    assert!(!p.path.with_file_name("passwd-").exists());
+}
+
+#[test]
+fn test_untouched_lines_are_kept() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::{UserDBRead, UserDBWrite};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let gs = Fixture::copy("gshadow");
+    // comments, empty lines and short records like `a:!::` have to survive every write.
+    for (fixture, extra) in &[(&p, ""), (&s, ""), (&g, "a:x:4000:\n"), (&gs, "a:!::\n")] {
+        let content = fs::read_to_string(&fixture.path).unwrap();
+        let content = format!(
+            "# edited by hand\n\n{}\n{}\n# the end\n",
+            content.trim_end(),
+            extra
+        );
+        fs::write(&fixture.path, content).unwrap();
+    }
+    let read_all = || {
+        [&p, &s, &g, &gs]
+            .iter()
+            .map(|f| fs::read_to_string(&f.path).unwrap())
+            .collect::<Vec<String>>()
+    };
+    let originals = read_all();
+
+    let mut db = umanux::UserDBLocal::load_files(umanux::Files {
+        gshadow: Some(gs.path.clone()),
+        ..umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone())
+    })
+    .unwrap();
+    assert!(db.get_group_by_name("a").unwrap().get_gshadow().is_some());
+
+    // adding and removing a group reproduces the files byte by byte.
+    db.new_group(
+        umanux::api::CreateGroupArgs::builder()
+            .groupname("later")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    assert!(fs::read_to_string(&gs.path)
+        .unwrap()
+        .ends_with("# the end\nlater:!::\n"));
+    db.delete_group(
+        umanux::api::DeleteGroupArgs::builder()
+            .groupname("later")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(read_all(), originals);
+
+    // deleting a user only removes its own lines.
+    db.delete_user(
+        umanux::api::DeleteUserArgs::builder()
+            .username("bergfried")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    for (current, original) in read_all().iter().zip(&originals) {
+        let line = original
+            .lines()
+            .find(|l| l.starts_with("bergfried:"))
+            .unwrap();
+        assert_eq!(*current, original.replace(&format!("{line}\n"), ""));
+    }
+}
+
+#[test]
+fn test_load_reports_all_parse_failures() {
+    use testfiles::Fixture;
+
+    use std::convert::TryFrom;
+    use std::fs;
+    use umanux::error::{ParseError, ParseFailure};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let gs = Fixture::copy("gshadow");
+    let su = Fixture::copy("subuid");
+    let passwd = fs::read_to_string(&p.path)
+        .unwrap()
+        .replace("teste:x:1002:1002:", "teste:x:1002:-1:");
+    fs::write(&p.path, passwd).unwrap();
+    let mut gshadow = fs::read_to_string(&gs.path).unwrap();
+    gshadow.push_str("wheel:!:dietrich\n");
+    fs::write(&gs.path, &gshadow).unwrap();
+    fs::write(&su.path, "dietrich:100000:65536\nteste:lots:65536\n").unwrap();
+
+    let result = umanux::UserDBLocal::load_files(umanux::Files {
+        gshadow: Some(gs.path.clone()),
+        subuid: Some(su.path.clone()),
+        ..umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone())
+    });
+    let failures = match result {
+        Err(umanux::UserLibError::Parse(failures)) => failures,
+        _ => panic!("the files should not be parsed"),
+    };
+    let gshadow_line = u32::try_from(gshadow.lines().count()).unwrap();
+    assert_eq!(
+        failures,
+        vec![
+            ParseFailure {
+                file: "passwd".to_owned(),
+                line: 57,
+                field: ParseError::Gid,
+                value: "-1".to_owned(),
+            },
+            // without a passwd entry the shadow entry is an error as well.
+            ParseFailure {
+                file: "shadow".to_owned(),
+                line: 57,
+                field: ParseError::Username,
+                value: "teste".to_owned(),
+            },
+            ParseFailure {
+                file: "gshadow".to_owned(),
+                line: gshadow_line,
+                field: ParseError::Record,
+                value: "wheel:!:dietrich".to_owned(),
+            },
+            ParseFailure {
+                file: "subuid".to_owned(),
+                line: 2,
+                field: ParseError::Record,
+                value: "teste:lots:65536".to_owned(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_lenient_loading_keeps_invalid_records() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::{UserDBRead, UserDBWrite};
+    use umanux::error::ParseError;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let gs = Fixture::copy("gshadow");
+    let passwd = fs::read_to_string(&p.path)
+        .unwrap()
+        .replace("teste:x:1002:1002:", "teste:x:1002:-1:");
+    fs::write(&p.path, &passwd).unwrap();
+    let mut group = fs::read_to_string(&g.path).unwrap();
+    group.push_str("Admins:x:4000:teste\n");
+    fs::write(&g.path, &group).unwrap();
+    let files = || umanux::Files {
+        gshadow: Some(gs.path.clone()),
+        ..umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone())
+    };
+    assert!(umanux::UserDBLocal::load_files(files()).is_err());
+
+    let (mut db, warnings) =
+        umanux::UserDBLocal::load_files_with_options(files(), umanux::LoadOptions::Lenient)
+            .unwrap();
+    let warned = |file: &str, field, value: &str| {
+        warnings
+            .iter()
+            .any(|w| w.file == file && w.field == field && w.value == value)
+    };
+    assert!(warned("passwd", ParseError::Gid, "-1"));
+    assert!(warned("shadow", ParseError::Username, "teste"));
+    assert!(warned("group", ParseError::Groupname, "Admins"));
+    assert!(db.get_user_by_name("teste").is_none());
+    assert!(db.get_user_by_name("bergfried").is_some());
+
+    // the names and IDs of the invalid records cannot be used.
+    let new_user = |name, uid| {
+        umanux::api::CreateUserArgs::builder()
+            .username(name)
+            .uid(uid)
+            .create_home(umanux::api::CreateHome::Skip)
+            .build()
+            .unwrap()
+    };
+    assert!(db.new_user(new_user("teste", 3000)).is_err());
+    assert!(db.new_user(new_user("other", 1002)).is_err());
+    assert!(db
+        .new_group(
+            umanux::api::CreateGroupArgs::builder()
+                .groupname("later")
+                .gid(4000)
+                .build()
+                .unwrap()
+        )
+        .is_err());
+
+    // writing keeps the invalid records as they are.
+    db.new_user(new_user("other", 3000)).unwrap();
+    let written = fs::read_to_string(&p.path).unwrap();
+    assert!(written.starts_with(&passwd));
+    assert!(written.contains("\nother:x:3000:"));
+    assert!(fs::read_to_string(&g.path)
+        .unwrap()
+        .contains("Admins:x:4000:teste\n"));
+}
+
+#[test]
+fn test_resolve_in_root() {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+    use umanux::userlib::files::resolve_in_root;
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    fs::create_dir_all(root.join("usr/etc")).unwrap();
+    fs::create_dir_all(root.join("etc")).unwrap();
+    // absolute links and `..` stay inside of the root directory.
+    symlink("/usr/etc/passwd", root.join("etc/passwd")).unwrap();
+    symlink("../../../../../shadow", root.join("etc/shadow")).unwrap();
+    symlink("/var/mail", root.join("etc/mail")).unwrap();
+    symlink("loop", root.join("etc/loop")).unwrap();
+
+    let resolve = |path: &str| resolve_in_root(root, Path::new(path)).unwrap();
+    assert_eq!(resolve("/etc/passwd"), root.join("usr/etc/passwd"));
+    assert_eq!(resolve("/etc/shadow"), root.join("shadow"));
+    assert_eq!(resolve("/etc/mail/teste"), root.join("var/mail/teste"));
+    assert_eq!(resolve("/../etc/./group"), root.join("etc/group"));
+    // a loop or a long chain of links is an error like in the kernel instead of being taken literally.
+    assert_eq!(
+        resolve_in_root(root, Path::new("/etc/loop"))
+            .unwrap_err()
+            .raw_os_error(),
+        Some(nix::libc::ELOOP)
+    );
+    symlink("/etc/hostname", root.join("link40")).unwrap();
+    for n in 0..40 {
+        symlink(format!("link{}", n + 1), root.join(format!("link{n}"))).unwrap();
+    }
+    assert_eq!(resolve("/link1"), root.join("etc/hostname"));
+    assert_eq!(
+        resolve_in_root(root, Path::new("/link0"))
+            .unwrap_err()
+            .raw_os_error(),
+        Some(nix::libc::ELOOP)
+    );
+}
+
+#[test]
+fn test_alternate_root() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use umanux::api::{UserDBRead, UserDBWrite};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    fs::create_dir_all(root.join("etc")).unwrap();
+    fs::create_dir_all(root.join("usr/etc")).unwrap();
+    fs::create_dir_all(root.join("home/teste")).unwrap();
+    fs::create_dir_all(root.join("var/mail")).unwrap();
+    fs::copy(&p.path, root.join("etc/passwd")).unwrap();
+    fs::copy(&s.path, root.join("etc/shadow")).unwrap();
+    // the group file is a link that would point to the group file of the host without the root.
+    fs::copy(&g.path, root.join("usr/etc/group")).unwrap();
+    symlink("/usr/etc/group", root.join("etc/group")).unwrap();
+    fs::write(root.join("etc/login.defs"), "MAIL_DIR /var/mail\n").unwrap();
+    fs::write(root.join("var/mail/teste"), "").unwrap();
+
+    let mut db = umanux::UserDBLocal::load_files(umanux::Files {
+        backup: false,
+        ..umanux::Files::with_root(root)
+    })
+    .unwrap();
+    assert!(db.get_user_by_name("teste").is_some());
+    assert_eq!(
+        db.get_mail_spool("teste").unwrap(),
+        Some(root.join("var/mail/teste"))
+    );
+    db.new_group(
+        umanux::api::CreateGroupArgs::builder()
+            .groupname("later")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    assert!(fs::read_to_string(root.join("usr/etc/group"))
+        .unwrap()
+        .contains("\nlater:x:"));
+    assert!(fs::symlink_metadata(root.join("etc/group"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    db.delete_user(
+        umanux::api::DeleteUserArgs::builder()
+            .username("teste")
+            .delete_home(umanux::api::DeleteHome::Delete)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    assert!(!root.join("home/teste").exists());
+    assert!(!root.join("var/mail/teste").exists());
+    assert!(!fs::read_to_string(root.join("etc/passwd"))
+        .unwrap()
+        .contains("teste:"));
+}
@@ -0,0 +1,223 @@
+extern crate umanux;
+mod testfiles;
+
+#[test]
+fn test_create_and_delete_group_function() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::{GroupRead, UserDBRead, UserDBWrite};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+
+    let gf = fs::read_to_string(&g.path).unwrap();
+
+    let mf = umanux::Files::new(p.path.clone(), s.path, g.path.clone());
+
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+
+    let group_res = db.new_group(
+        umanux::api::CreateGroupArgs::builder()
+            .groupname("testgroup")
+            .members(vec!["test", "bergfried"])
+            .build()
+            .unwrap(),
+    );
+    // 1020 is the highest GID in use in the fixture.
+    assert_eq!(group_res.unwrap().get_gid(), Some(1021));
+    let gf2 = fs::read_to_string(&g.path).unwrap();
+    for (l1, l2) in gf.lines().zip(gf2.lines()) {
+        assert_eq!(l1, l2);
+    }
+    assert_eq!(
+        gf2.lines().last().unwrap(),
+        "testgroup:x:1021:test,bergfried"
+    );
+
+    // teste is the primary group of the user teste
+    let delete_res = db.delete_group(
+        umanux::api::DeleteGroupArgs::builder()
+            .groupname("teste")
+            .build()
+            .unwrap(),
+    );
+    assert!(delete_res.is_err());
+    assert!(db.get_group_by_name("teste").is_some());
+
+    let delete_res = db.delete_group(
+        umanux::api::DeleteGroupArgs::builder()
+            .groupname("testgroup")
+            .build()
+            .unwrap(),
+    );
+    assert!(delete_res.is_ok());
+    let gf3 = fs::read_to_string(&g.path).unwrap();
+    assert_eq!(gf3.trim_end(), gf.trim_end());
+    assert!(db.get_group_by_name("testgroup").is_none());
+}
+
+#[test]
+fn test_gshadow_consistency() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::{GroupRead, UserDBRead, UserDBWrite};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let gs = Fixture::copy("gshadow");
+
+    let gsf = fs::read_to_string(&gs.path).unwrap();
+    // test2 is an administrator and a member in gshadow without being a member in the group file.
+    fs::write(
+        &gs.path,
+        gsf.replace("\nbergfried:!::\n", "\nbergfried:!:test2:\n")
+            .replace("\nt1:!::\n", "\nt1:!::test2\n"),
+    )
+    .unwrap();
+
+    let mf = umanux::Files {
+        gshadow: Some(gs.path.clone()),
+        ..umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone())
+    };
+
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+    {
+        let wheel = db.get_group_by_name("wheel").unwrap();
+        assert_eq!(wheel.get_encrypted_password(), Some("!"));
+        let gshadow = wheel.get_gshadow().unwrap();
+        assert_eq!(gshadow.get_administrator_names(), vec!["dietrich"]);
+    }
+
+    db.new_group(
+        umanux::api::CreateGroupArgs::builder()
+            .groupname("testgroup")
+            .members(vec!["bergfried"])
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    let gsf2 = fs::read_to_string(&gs.path).unwrap();
+    assert_eq!(gsf2.lines().last().unwrap(), "testgroup:!::bergfried");
+
+    // a new user gets a gshadow entry for its group and is added to the supplementary groups
+    db.new_user(
+        umanux::api::CreateUserArgs::builder()
+            .username("test2")
+            .groups(vec!["testgroup"])
+            .create_home(umanux::api::CreateHome::Skip)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    let gsf3 = fs::read_to_string(&gs.path).unwrap();
+    assert!(gsf3.lines().any(|l| l == "testgroup:!::bergfried,test2"));
+    assert_eq!(gsf3.lines().last().unwrap(), "test2:!::");
+
+    db.rename_group("testgroup", "renamed").unwrap();
+    let gf = fs::read_to_string(&g.path).unwrap();
+    let gsf4 = fs::read_to_string(&gs.path).unwrap();
+    assert!(gf.lines().any(|l| l.starts_with("renamed:x:1021:")));
+    assert!(gsf4.lines().any(|l| l == "renamed:!::bergfried,test2"));
+    assert!(!gsf4.contains("testgroup"));
+
+    // deleting the user removes it from all the groups
+    db.delete_user(
+        umanux::api::DeleteUserArgs::builder()
+            .username("test2")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    let gsf5 = fs::read_to_string(&gs.path).unwrap();
+    assert!(gsf5.lines().any(|l| l == "renamed:!::bergfried"));
+    assert!(gsf5.lines().any(|l| l == "bergfried:!::"));
+    assert!(gsf5.lines().any(|l| l == "t1:!::"));
+    assert!(!gsf5.contains("test2"));
+
+    db.delete_group(
+        umanux::api::DeleteGroupArgs::builder()
+            .groupname("renamed")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    let gsf6 = fs::read_to_string(&gs.path).unwrap();
+    assert_eq!(gsf6.trim_end(), gsf.trim_end());
+}
+
+#[test]
+fn test_missing_gshadow_entries_are_created() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::{GroupRead, UserDBRead, UserDBWrite};
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let gs = Fixture::copy("gshadow");
+
+    // audio has no gshadow entry and video has its password in the group file.
+    let gsf = fs::read_to_string(&gs.path).unwrap();
+    fs::write(
+        &gs.path,
+        gsf.replace("\naudio:!::\n", "\n")
+            .replace("\nvideo:!::\n", "\n"),
+    )
+    .unwrap();
+    let gf = fs::read_to_string(&g.path).unwrap();
+    fs::write(
+        &g.path,
+        gf.replace("\nvideo:x:39:\n", "\nvideo:$6$hash:39:\n"),
+    )
+    .unwrap();
+
+    let mf = umanux::Files {
+        gshadow: Some(gs.path.clone()),
+        ..umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone())
+    };
+    let mut db = umanux::UserDBLocal::load_files(mf).unwrap();
+    assert!(db
+        .get_group_by_name("audio")
+        .unwrap()
+        .get_gshadow()
+        .is_none());
+
+    db.new_user(
+        umanux::api::CreateUserArgs::builder()
+            .username("test2")
+            .groups(vec!["audio", "video"])
+            .create_home(umanux::api::CreateHome::Skip)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    let gf2 = fs::read_to_string(&g.path).unwrap();
+    let gsf2 = fs::read_to_string(&gs.path).unwrap();
+    assert!(gf2.lines().any(|l| l == "audio:x:63:test2"));
+    assert!(gsf2.lines().any(|l| l == "audio:!::test2"));
+    // like grpconv the password is moved to the gshadow file.
+    assert!(gf2.lines().any(|l| l == "video:x:39:test2"));
+    assert!(gsf2.lines().any(|l| l == "video:$6$hash::test2"));
+    let video = db.get_group_by_name("video").unwrap();
+    assert_eq!(video.get_encrypted_password(), Some("$6$hash"));
+    assert_eq!(
+        video.get_gshadow().unwrap().get_member_names(),
+        vec!["test2"]
+    );
+
+    db.delete_user(
+        umanux::api::DeleteUserArgs::builder()
+            .username("test2")
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    let gsf3 = fs::read_to_string(&gs.path).unwrap();
+    assert!(gsf3.lines().any(|l| l == "audio:!::"));
+    assert!(!gsf3.contains("test2"));
+}
@@ -0,0 +1,207 @@
+extern crate umanux;
+mod testfiles;
+
+#[test]
+fn test_stale_lock_is_removed() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::userlib::files::LockedFileGuard;
+
+    let p = Fixture::copy("passwd");
+    let lockfile = p.path.with_file_name("passwd.lock");
+
+    // the pid of a process that has already terminated.
+    let mut child = std::process::Command::new("true").spawn().unwrap();
+    let dead_pid = child.id();
+    child.wait().unwrap();
+
+    for content in &[dead_pid.to_string(), "no pid".to_owned(), String::new()] {
+        fs::write(&lockfile, content).unwrap();
+        let guard = LockedFileGuard::new(&p.path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&lockfile).unwrap(),
+            std::process::id().to_string()
+        );
+        drop(guard);
+        assert!(!lockfile.exists());
+    }
+}
+
+#[test]
+fn test_valid_lock_is_kept() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::userlib::files::LockedFileGuard;
+
+    let p = Fixture::copy("passwd");
+    let lockfile = p.path.with_file_name("passwd.lock");
+    let guard = LockedFileGuard::new(&p.path).unwrap();
+
+    // this process is still running so the lock is not stale.
+    assert_eq!(
+        LockedFileGuard::with_policy(&p.path, umanux::LockPolicy::non_blocking()).unwrap_err(),
+        umanux::UserLibError::LockHeldBy(std::process::id())
+    );
+    assert_eq!(
+        fs::read_to_string(&lockfile).unwrap(),
+        std::process::id().to_string()
+    );
+    drop(guard);
+    assert!(LockedFileGuard::new(&p.path).is_ok());
+}
+
+#[test]
+fn test_lock_timeout() {
+    use testfiles::Fixture;
+
+    use std::time::{Duration, Instant};
+    use umanux::userlib::files::LockedFileGuard;
+
+    let p = Fixture::copy("passwd");
+    let guard = LockedFileGuard::new(&p.path).unwrap();
+    let policy = umanux::LockPolicy {
+        timeout: Duration::from_millis(200),
+        retry_interval: Duration::from_millis(10),
+        max_retry_interval: Duration::from_millis(50),
+    };
+
+    let started = Instant::now();
+    assert_eq!(
+        LockedFileGuard::with_policy(&p.path, policy).unwrap_err(),
+        umanux::UserLibError::LockTimeout
+    );
+    assert!(started.elapsed() >= Duration::from_millis(150));
+
+    // the lock is acquired as soon as it is released.
+    let releaser = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        drop(guard);
+    });
+    assert!(LockedFileGuard::with_policy(&p.path, policy).is_ok());
+    releaser.join().unwrap();
+}
+
+#[test]
+fn test_load_locked_files() {
+    use testfiles::Fixture;
+
+    use umanux::userlib::files::LockedFileGuard;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let _guard = LockedFileGuard::new(&s.path).unwrap();
+
+    let mf = umanux::Files {
+        lock_policy: umanux::LockPolicy::non_blocking(),
+        ..umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone())
+    };
+    // the error is returned instead of panicking.
+    assert_eq!(
+        umanux::UserDBLocal::load_files(mf).err(),
+        Some(umanux::UserLibError::LockHeldBy(std::process::id()))
+    );
+    // the passwd file that has been locked before the failure is unlocked again.
+    assert!(!p.path.with_file_name("passwd.lock").exists());
+}
+
+/// The environment variable that makes [`test_pwd_lock_helper`] take the lock in a separate process.
+const PWD_LOCK_HELPER: &str = "UMANUX_PWD_LOCK_HELPER";
+
+/// Runs in the second process of [`test_pwd_lock_is_exclusive`] and reports the result of locking.
+#[test]
+fn test_pwd_lock_helper() {
+    let path = match std::env::var_os(PWD_LOCK_HELPER) {
+        Some(path) => std::path::PathBuf::from(path),
+        None => return,
+    };
+    match umanux::userlib::files::PwdLock::new(&path, umanux::LockPolicy::non_blocking()) {
+        Ok(_) => println!("locking result: locked"),
+        Err(e) => println!("locking result: {e}"),
+    }
+}
+
+#[test]
+fn test_pwd_lock_is_exclusive() {
+    use testfiles::Fixture;
+
+    use std::process::Command;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let pwd_lock = p.path.with_file_name(".pwd.lock");
+    // fcntl locks are only exclusive between processes so try to lock in a second one.
+    let lock_in_other_process = || {
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "test_pwd_lock_helper", "--nocapture"])
+            .env(PWD_LOCK_HELPER, &pwd_lock)
+            .output()
+            .unwrap();
+        // the test harness prints the name of the test on the same line.
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let result = stdout.split("locking result: ").nth(1).unwrap();
+        result.lines().next().unwrap().to_owned()
+    };
+
+    let mf = umanux::Files {
+        lock_policy: umanux::LockPolicy::non_blocking(),
+        ..umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone())
+    };
+    let locked = mf.lock_all_get().unwrap();
+    assert_eq!(
+        lock_in_other_process(),
+        umanux::UserLibError::LockHeldBy(std::process::id()).to_string()
+    );
+    drop(locked);
+    assert_eq!(lock_in_other_process(), "locked");
+}
+
+#[test]
+fn test_read_only_needs_no_lock() {
+    use testfiles::Fixture;
+
+    use std::fs;
+    use umanux::api::{UserDBRead, UserDBWrite};
+    use umanux::userlib::files::LockedFileGuard;
+
+    let p = Fixture::copy("passwd");
+    let s = Fixture::copy("shadow");
+    let g = Fixture::copy("group");
+    let original = fs::read_to_string(&p.path).unwrap();
+    // another process is editing the passwd file.
+    let guard = LockedFileGuard::new(&p.path).unwrap();
+
+    let (mut db, _) = umanux::UserDBLocal::load_files_read_only(
+        umanux::Files {
+            lock_policy: umanux::LockPolicy::non_blocking(),
+            ..umanux::Files::new(p.path.clone(), s.path.clone(), g.path.clone())
+        },
+        umanux::LoadOptions::Strict,
+    )
+    .unwrap();
+    assert!(db.is_read_only());
+    let teste = db.get_user_by_name("teste").unwrap();
+    assert!(teste.get_shadow().is_some());
+    assert_ne!(*teste.get_password_state(), umanux::Password::Unknown);
+
+    assert_eq!(
+        db.new_user(
+            umanux::api::CreateUserArgs::builder()
+                .username("other")
+                .create_home(umanux::api::CreateHome::Skip)
+                .build()
+                .unwrap()
+        )
+        .unwrap_err(),
+        umanux::UserLibError::ReadOnly
+    );
+    assert_eq!(
+        db.rename_group("teste", "renamed").unwrap_err(),
+        umanux::UserLibError::ReadOnly
+    );
+    assert_eq!(fs::read_to_string(&p.path).unwrap(), original);
+    drop(guard);
+}